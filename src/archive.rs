@@ -0,0 +1,571 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::read::DeflateDecoder;
+use log::info;
+use memmap::Mmap;
+
+use crate::failure::fail;
+use crate::is_raw;
+
+const ZIP_LOCAL_HEADER: u32 = 0x04034b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP_END: u32 = 0x06054b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_END_LOCATOR: u32 = 0x07064b50;
+/// the end of central directory record is followed by a comment of up to 64k
+const ZIP_END_SEARCH: usize = 22 + u16::MAX as usize;
+
+const ZIP_CENTRAL_HEADER_LEN: usize = 46;
+/// deflate can't shrink data to less than a 1032th of its size, a larger
+/// uncompressed size is a damaged header
+const DEFLATE_MAX_RATIO: u64 = 1032;
+
+const TAR_BLOCK: usize = 512;
+
+enum Data {
+    Mapped(Mmap),
+    Memory(Vec<u8>),
+}
+
+impl Data {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Data::Mapped(mmap) => mmap,
+            Data::Memory(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Stored,
+    Deflate,
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: PathBuf,
+    /// of the (compressed) data in the archive
+    start: usize,
+    size: usize,
+    method: Method,
+    uncompressed_size: usize,
+}
+
+/// A ZIP or TAR archive, e.g. a camera backup, whose raws are read straight
+/// from it without extracting them to disk
+pub struct Archive {
+    pub path: PathBuf,
+    data: Data,
+    entries: Vec<Entry>,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(unix)]
+fn entry_name(name: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(name))
+}
+
+#[cfg(not(unix))]
+fn entry_name(name: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(name).into_owned())
+}
+
+/// Where the central directory is and how many entries it has, from the
+/// ZIP64 records when the plain ones overflowed
+fn zip_directory(bytes: &[u8]) -> Option<(usize, usize)> {
+    let search = bytes.len().saturating_sub(ZIP_END_SEARCH);
+    let end = (search..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(bytes, i) == Some(ZIP_END))?;
+
+    let count = u16_at(bytes, end + 10)? as usize;
+    let offset = u32_at(bytes, end + 16)? as usize;
+
+    if count != u16::MAX as usize && offset != u32::MAX as usize {
+        return Some((offset, count));
+    }
+
+    let locator = end.checked_sub(20)?;
+    if u32_at(bytes, locator)? != ZIP64_END_LOCATOR {
+        return None;
+    }
+    let end64 = u64_at(bytes, locator + 8)? as usize;
+    if u32_at(bytes, end64)? != ZIP64_END {
+        return None;
+    }
+
+    Some((
+        u64_at(bytes, end64 + 48)? as usize,
+        u64_at(bytes, end64 + 32)? as usize,
+    ))
+}
+
+fn zip_entries(bytes: &[u8]) -> Option<Vec<Entry>> {
+    let (mut at, count) = zip_directory(bytes)?;
+    // every entry has a header in the archive, so a larger count is damaged
+    if count > bytes.len() / ZIP_CENTRAL_HEADER_LEN {
+        return None;
+    }
+    let mut entries = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        if u32_at(bytes, at)? != ZIP_CENTRAL_HEADER {
+            return None;
+        }
+
+        let method = u16_at(bytes, at + 10)?;
+        let mut size = u32_at(bytes, at + 20)? as u64;
+        let mut uncompressed_size = u32_at(bytes, at + 24)? as u64;
+        let name_len = u16_at(bytes, at + 28)? as usize;
+        let extra_len = u16_at(bytes, at + 30)? as usize;
+        let comment_len = u16_at(bytes, at + 32)? as usize;
+        let mut local = u32_at(bytes, at + 42)? as u64;
+
+        let name = bytes.get(at + 46..at + 46 + name_len)?;
+
+        // the ZIP64 extra field has the values that didn't fit, in this order
+        let mut extra = at + 46 + name_len;
+        let extra_end = extra + extra_len;
+        while extra + 4 <= extra_end {
+            let id = u16_at(bytes, extra)?;
+            let len = u16_at(bytes, extra + 2)? as usize;
+            if id == 1 {
+                let mut field = extra + 4;
+                for value in [&mut uncompressed_size, &mut size, &mut local] {
+                    if *value == u32::MAX as u64 {
+                        *value = u64_at(bytes, field)?;
+                        field += 8;
+                    }
+                }
+            }
+            extra += 4 + len;
+        }
+
+        at = extra_end + comment_len;
+
+        if name.ends_with(b"/") {
+            continue;
+        }
+
+        let local = local as usize;
+        if u32_at(bytes, local)? != ZIP_LOCAL_HEADER {
+            return None;
+        }
+        let start =
+            local + 30 + u16_at(bytes, local + 26)? as usize + u16_at(bytes, local + 28)? as usize;
+        // a truncated backup has entries past its end
+        let size = usize::try_from(size).ok()?;
+        bytes.get(start..start.checked_add(size)?)?;
+        if method == 8 && uncompressed_size > (size as u64).saturating_mul(DEFLATE_MAX_RATIO) {
+            return None;
+        }
+
+        let method = match method {
+            0 => Method::Stored,
+            8 => Method::Deflate,
+            other => fail!(
+                Io,
+                "{} is compressed with ZIP method {}, only stored and deflated entries can be read",
+                String::from_utf8_lossy(name),
+                other
+            ),
+        };
+
+        entries.push(Entry {
+            name: entry_name(name),
+            start,
+            size,
+            method,
+            uncompressed_size: usize::try_from(uncompressed_size).ok()?,
+        });
+    }
+
+    Some(entries)
+}
+
+/// Octal, or base 256 with the high bit set for sizes past 8 GB
+fn tar_number(field: &[u8]) -> Option<usize> {
+    if field.first()? & 0x80 != 0 {
+        let n = field[1..]
+            .iter()
+            .try_fold(0usize, |n, &b| n.checked_mul(256)?.checked_add(b as usize));
+        return Some(n.unwrap_or_else(|| {
+            fail!(
+                Metadata,
+                "a TAR entry is larger than {} bytes, the archive is damaged",
+                usize::MAX
+            )
+        }));
+    }
+
+    let text = std::str::from_utf8(field).ok()?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(text, 8).ok()
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+/// `path` record of a pax extended header
+fn pax_path(records: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = records;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ')?;
+        let len = std::str::from_utf8(&rest[..space])
+            .ok()?
+            .parse::<usize>()
+            .ok()?;
+        let record = rest.get(space + 1..len)?;
+        if let Some(path) = record.strip_prefix(b"path=") {
+            return Some(path.strip_suffix(b"\n").unwrap_or(path).to_vec());
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+fn tar_entries(bytes: &[u8]) -> Option<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    let mut long_name = None;
+
+    while at + TAR_BLOCK <= bytes.len() {
+        let header = &bytes[at..at + TAR_BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let size = tar_number(&header[124..136])?;
+        let start = at + TAR_BLOCK;
+        let data = bytes.get(start..start.checked_add(size)?)?;
+        at = start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+
+        match header[156] {
+            // GNU long name, and pax header: the name of the next entry
+            b'L' => long_name = Some(until_nul(data).to_vec()),
+            b'x' => long_name = pax_path(data).or(long_name),
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let name = until_nul(&header[0..100]);
+                    let prefix = until_nul(&header[345..500]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        [prefix, b"/", name].concat()
+                    } else {
+                        name.to_vec()
+                    }
+                });
+                entries.push(Entry {
+                    name: entry_name(&name),
+                    start,
+                    size,
+                    method: Method::Stored,
+                    uncompressed_size: size,
+                });
+            }
+            _ => long_name = None,
+        }
+    }
+
+    Some(entries)
+}
+
+fn entries(bytes: &[u8]) -> Option<Vec<Entry>> {
+    if u32_at(bytes, 0) == Some(ZIP_LOCAL_HEADER) {
+        zip_entries(bytes)
+    } else {
+        tar_entries(bytes)
+    }
+}
+
+impl Archive {
+    /// Opens a ZIP or TAR archive, `-` reads one from stdin
+    pub fn open(path: &Path) -> Self {
+        let data = if path.as_os_str() == "-" {
+            let mut bytes = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut bytes)
+                .unwrap_or_else(|e| fail!(Io, "can't read the archive from stdin: {}", e));
+            Data::Memory(bytes)
+        } else {
+            let file = File::open(path)
+                .unwrap_or_else(|e| fail!(Io, "can't open {}: {}", path.display(), e));
+            Data::Mapped(
+                unsafe { Mmap::map(&file) }
+                    .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e)),
+            )
+        };
+
+        let entries = entries(data.bytes()).unwrap_or_else(|| {
+            fail!(
+                Io,
+                "{} is not a ZIP or TAR archive, or is damaged",
+                path.display()
+            )
+        });
+
+        info!("{} has {} files", path.display(), entries.len());
+
+        Self {
+            path: path.to_path_buf(),
+            data,
+            entries,
+        }
+    }
+
+    /// Names of the raws in the archive, sorted
+    pub fn raws(&self) -> Vec<PathBuf> {
+        let mut raws = self
+            .entries
+            .iter()
+            .map(|entry| entry.name.clone())
+            .filter(|name| is_raw(name))
+            .collect::<Vec<_>>();
+        raws.sort();
+        raws
+    }
+
+    /// The entry named `name`, or the only one whose file name it is
+    fn entry(&self, name: &Path) -> &Entry {
+        if let Some(entry) = self.entries.iter().find(|entry| entry.name == name) {
+            return entry;
+        }
+
+        let matching = self
+            .entries
+            .iter()
+            .filter(|entry| entry.name.file_name() == Some(name.as_os_str()))
+            .collect::<Vec<_>>();
+
+        match matching[..] {
+            [entry] => entry,
+            [] => fail!(
+                MissingFrames,
+                "{} is not in {}",
+                name.display(),
+                self.path.display()
+            ),
+            _ => fail!(
+                Usage,
+                "{} has {} files named {}, give the full path",
+                self.path.display(),
+                matching.len(),
+                name.display()
+            ),
+        }
+    }
+
+    /// Full name in the archive of `name`, see `entry`
+    pub fn resolve(&self, name: &Path) -> PathBuf {
+        self.entry(name).name.clone()
+    }
+
+    /// Content of the entry named `name`, decompressed in memory if it has to
+    /// be. Nothing is kept: every call decompresses the entry again, which is
+    /// why a sequence can't name the same frame twice.
+    pub fn read(&self, name: &Path) -> Cow<'_, [u8]> {
+        let entry = self.entry(name);
+        let data = &self.data.bytes()[entry.start..entry.start + entry.size];
+
+        match entry.method {
+            Method::Stored => Cow::Borrowed(data),
+            Method::Deflate => {
+                let mut bytes = Vec::with_capacity(entry.uncompressed_size);
+                DeflateDecoder::new(data)
+                    .read_to_end(&mut bytes)
+                    .unwrap_or_else(|e| {
+                        fail!(
+                            Io,
+                            "can't decompress {} from {}: {}",
+                            name.display(),
+                            self.path.display(),
+                            e
+                        )
+                    });
+                Cow::Owned(bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const RAW: &[u8] = b"not much of a raw, but raw enough to be compressed, raw raw raw raw";
+
+    fn archive(bytes: Vec<u8>) -> Archive {
+        Archive {
+            path: PathBuf::from("test"),
+            entries: entries(&bytes).expect("a readable archive"),
+            data: Data::Memory(bytes),
+        }
+    }
+
+    /// A ZIP of `files`, deflated or stored, without checksums since they
+    /// aren't checked
+    fn zip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut central = Vec::new();
+        for (name, content) in files {
+            let data = if deflate {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(content).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                content.to_vec()
+            };
+            let method: u16 = if deflate { 8 } else { 0 };
+            let offset = bytes.len() as u32;
+
+            bytes.extend(ZIP_LOCAL_HEADER.to_le_bytes());
+            bytes.extend([20, 0, 0, 0]);
+            bytes.extend(method.to_le_bytes());
+            bytes.extend([0; 8]);
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend((content.len() as u32).to_le_bytes());
+            bytes.extend((name.len() as u16).to_le_bytes());
+            bytes.extend([0, 0]);
+            bytes.extend(name.as_bytes());
+            bytes.extend(&data);
+
+            central.extend(ZIP_CENTRAL_HEADER.to_le_bytes());
+            central.extend([20, 0, 20, 0, 0, 0]);
+            central.extend(method.to_le_bytes());
+            central.extend([0; 8]);
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend((content.len() as u32).to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend([0; 12]);
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+
+        let directory = bytes.len() as u32;
+        bytes.extend(&central);
+        bytes.extend(ZIP_END.to_le_bytes());
+        bytes.extend([0; 4]);
+        bytes.extend((files.len() as u16).to_le_bytes());
+        bytes.extend((files.len() as u16).to_le_bytes());
+        bytes.extend((central.len() as u32).to_le_bytes());
+        bytes.extend(directory.to_le_bytes());
+        bytes.extend([0, 0]);
+        bytes
+    }
+
+    /// A ustar header for a file named `prefix`/`name` of `size` bytes
+    fn tar_header(prefix: &str, name: &str, size: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..124 + size.len()].copy_from_slice(size);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header
+    }
+
+    fn tar(prefix: &str, name: &str, content: &[u8]) -> Vec<u8> {
+        let mut bytes = tar_header(prefix, name, format!("{:011o}", content.len()).as_bytes());
+        bytes.extend(content);
+        bytes.resize(bytes.len().next_multiple_of(TAR_BLOCK) + 2 * TAR_BLOCK, 0);
+        bytes
+    }
+
+    #[test]
+    fn stored_zip() {
+        let archive = archive(zip(&[("DCIM/", b""), ("DCIM/DSC0001.ARW", RAW)], false));
+        assert_eq!(archive.raws(), [PathBuf::from("DCIM/DSC0001.ARW")]);
+        assert!(matches!(
+            archive.read(Path::new("DSC0001.ARW")),
+            Cow::Borrowed(RAW)
+        ));
+    }
+
+    #[test]
+    fn deflated_zip() {
+        let archive = archive(zip(&[("DSC0001.ARW", RAW), ("DSC0002.ARW", b"")], true));
+        assert_eq!(archive.entries[0].method, Method::Deflate);
+        assert!(archive.entries[0].size < RAW.len());
+        assert_eq!(&*archive.read(Path::new("DSC0001.ARW")), RAW);
+        assert_eq!(&*archive.read(Path::new("DSC0002.ARW")), b"");
+    }
+
+    #[test]
+    fn tar_with_ustar_prefix() {
+        let archive = archive(tar("backup/DCIM", "DSC0001.ARW", RAW));
+        assert_eq!(archive.raws(), [PathBuf::from("backup/DCIM/DSC0001.ARW")]);
+        assert_eq!(&*archive.read(Path::new("DSC0001.ARW")), RAW);
+    }
+
+    #[test]
+    fn truncated_archives_are_damaged() {
+        let bytes = zip(&[("DSC0001.ARW", RAW)], false);
+        assert!(entries(&bytes[..bytes.len() - 1]).is_none());
+        // a central directory whose entry points past the end
+        let mut bytes = bytes;
+        let local = bytes.len() - 22 - 46 - 11 + 42;
+        bytes[local..local + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(entries(&bytes).is_none());
+
+        let bytes = tar("", "DSC0001.ARW", RAW);
+        assert!(entries(&bytes[..TAR_BLOCK + 10]).is_none());
+    }
+
+    #[test]
+    fn oversized_headers_are_damaged() {
+        // more entries than the archive has room for headers
+        let mut bytes = zip(&[("DSC0001.ARW", RAW)], false);
+        let end = bytes.len() - 22;
+        bytes[end + 8..end + 12].copy_from_slice(&[0xfe, 0xff, 0xfe, 0xff]);
+        assert!(entries(&bytes).is_none());
+
+        // more than deflate could have compressed into the entry
+        let mut bytes = zip(&[("DSC0001.ARW", RAW)], true);
+        let central = bytes.len() - 22 - 46 - 11;
+        bytes[central + 24..central + 28].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(entries(&bytes).is_none());
+
+        // a TAR size field larger than the archive
+        let mut bytes = tar("", "DSC0001.ARW", RAW);
+        bytes[124..136].copy_from_slice(b"77777777777\0");
+        assert!(entries(&bytes).is_none());
+    }
+
+    #[test]
+    fn base_256_sizes() {
+        assert_eq!(
+            tar_number(&[0x80, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1]),
+            Some(1 << 33 | 1)
+        );
+
+        let overflow = std::panic::catch_unwind(|| tar_number(&[0xff; 12]));
+        let failure = overflow
+            .unwrap_err()
+            .downcast::<crate::failure::Failure>()
+            .unwrap();
+        assert_eq!(failure.kind, crate::failure::Kind::Metadata);
+    }
+}
@@ -0,0 +1,317 @@
+use log::info;
+use rayon::prelude::*;
+
+use crate::failure::fail;
+use crate::{Color, RawImage};
+
+/// every this many rows and columns are measured, odd to go through both
+/// pairs of green frames and every CFA color
+const STEP: u32 = 3;
+
+/// green differences this many standard deviations of the noise off are motion
+const MOTION_SIGMAS: f32 = 5.0;
+
+/// differences up to this many DN are told apart, larger ones are motion anyway
+const MAX_DIFFERENCE: usize = 4096;
+
+/// levels are binned by half stops for the noise of the green differences
+const LEVEL_BINS: usize = 2 * 17;
+
+/// bins with fewer differences than this take the noise of their neighbour
+const MIN_BIN_COUNT: u64 = 64;
+
+/// largest misalignment, in sensor pixels, the differences of green samples
+/// are taken for rather than motion
+const MAX_SHIFT: f32 = 1.0;
+
+/// standard errors a shift is taken down by, what noise alone gives
+const SHIFT_SIGMAS: f64 = 3.0;
+
+/// points of the score per sensor pixel of misalignment: a tenth of a pixel
+/// weighs as much as a percent of the image moving
+const RESIDUAL_POINTS: f64 = 10.0;
+
+/// How far a merge is from what a still scene shot from a still camera gives
+#[derive(Debug, Clone, Copy)]
+pub struct ArtifactScore {
+    /// percent of the pixels whose green samples disagree past the noise
+    pub motion: f64,
+    /// largest shift between two frames of a group, in sensor pixels
+    pub residual: f64,
+    /// largest difference in mean level between frames, in percent
+    pub flicker: f64,
+}
+
+impl ArtifactScore {
+    /// One number for all three, about the percent of the image that shows
+    /// artifacts: 0 for a clean sequence
+    pub fn total(&self) -> f64 {
+        self.motion + self.residual * RESIDUAL_POINTS + self.flicker
+    }
+}
+
+/// The two green samples of a 4 shots merge pixel, from the frames of
+/// `group` that have one there, in the order of the group, and which two
+/// frames they are; None for clipped or missing ones
+fn greens(group: &[RawImage], x: u32, y: u32) -> Option<([f32; 2], [usize; 2])> {
+    let mut values = [0.0; 2];
+    let mut frames = [0; 2];
+    let mut found = 0;
+
+    for (i, file) in group.iter().enumerate() {
+        let offset = file.inter_group_offsets();
+        let (fx, fy) = (x.checked_sub(offset.1)?, y.checked_sub(offset.0)?);
+        if fx >= file.width || fy >= file.height || file.color(fx, fy) != Color::Green {
+            continue;
+        }
+        if found == 2 || file.get_pixel(fx, fy) as u32 >= file.white_level {
+            return None;
+        }
+        values[found] = file.sample(fx, fy, None);
+        frames[found] = i;
+        found += 1;
+    }
+
+    (found == 2).then_some((values, frames))
+}
+
+fn level_bin(level: f32) -> usize {
+    (((level.max(0.0) + 1.0).log2() * 2.0) as usize).min(LEVEL_BINS - 1)
+}
+
+/// Rows of a group that are measured, with the gradient of the greens
+/// around every pixel
+fn rows(group: &[RawImage]) -> impl IndexedParallelIterator<Item = u32> {
+    (1..group[0].height - 1)
+        .into_par_iter()
+        .step_by(STEP as usize)
+}
+
+/// Columns measured on every row
+fn columns(group: &[RawImage]) -> impl Iterator<Item = u32> {
+    (1..group[0].width - 1).step_by(STEP as usize)
+}
+
+/// Histogram of the green differences of every level bin
+fn difference_histograms(group: &[RawImage]) -> Vec<Vec<u64>> {
+    let black = group[0].black_level as f32;
+    rows(group)
+        .fold(
+            || vec![vec![0u64; MAX_DIFFERENCE + 1]; LEVEL_BINS],
+            |mut bins, y| {
+                for x in columns(group) {
+                    if let Some(([a, b], _)) = greens(group, x, y) {
+                        let bin = level_bin((a + b) / 2.0 - black);
+                        let difference = ((a - b).abs() as usize).min(MAX_DIFFERENCE);
+                        bins[bin][difference] += 1;
+                    }
+                }
+                bins
+            },
+        )
+        .reduce(
+            || vec![vec![0u64; MAX_DIFFERENCE + 1]; LEVEL_BINS],
+            |mut a, b| {
+                for (a, b) in a.iter_mut().zip(b) {
+                    a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                }
+                a
+            },
+        )
+}
+
+/// Standard deviation of the noise of the green differences at every level
+/// bin, from the median absolute difference so that motion doesn't count
+fn noise(histograms: &[Vec<u64>]) -> Vec<Option<f32>> {
+    let mut noise = histograms
+        .iter()
+        .map(|histogram| {
+            let total = histogram.iter().sum::<u64>();
+            if total < MIN_BIN_COUNT {
+                return None;
+            }
+            let mut seen = 0;
+            let median = histogram.iter().position(|&count| {
+                seen += count;
+                seen * 2 >= total
+            })?;
+            Some(1.4826 * (median as f32).max(1.0))
+        })
+        .collect::<Vec<_>>();
+
+    // bins without enough of them take the noise of the closest one that has
+    let known = noise
+        .iter()
+        .enumerate()
+        .filter_map(|(i, sigma)| Some((i, (*sigma)?)))
+        .collect::<Vec<_>>();
+    for (i, sigma) in noise.iter_mut().enumerate() {
+        if sigma.is_none() {
+            *sigma = known
+                .iter()
+                .min_by_key(|(k, _)| k.abs_diff(i))
+                .map(|&(_, sigma)| sigma);
+        }
+    }
+    noise
+}
+
+/// Least squares shift between every two frames of `group` that share green
+/// samples, from how their difference follows the gradient of the greens,
+/// less `SHIFT_SIGMAS` standard errors: noise alone doesn't shift frames.
+/// Only the differences no shift up to `MAX_SHIFT` explains are left out
+/// as motion, the others are what misaligned frames look like.
+fn shifts(group: &[RawImage], thresholds: &[Option<f32>]) -> Vec<f64> {
+    let black = group[0].black_level as f32;
+    let n = group.len();
+    let green = |x: u32, y: u32| greens(group, x, y).map(|([a, b], _)| (a + b) / 2.0);
+
+    // per pair of frames, the normal equations of their shift, the sum of
+    // the squared differences and their count
+    let term = |x: u32, y: u32| {
+        let ([a, b], [i, j]) = greens(group, x, y)?;
+        let threshold = thresholds[level_bin((a + b) / 2.0 - black)]?;
+        let gx = (green(x + 1, y)? - green(x - 1, y)?) / 2.0;
+        let gy = (green(x, y + 1)? - green(x, y - 1)?) / 2.0;
+        if (a - b).abs() > threshold + MAX_SHIFT * (gx.abs() + gy.abs()) {
+            return None;
+        }
+        let (gx, gy, d) = (gx as f64, gy as f64, (b - a) as f64);
+        Some((
+            i * n + j,
+            [gx * gx, gx * gy, gy * gy, gx * d, gy * d, d * d, 1.0],
+        ))
+    };
+    let rows = rows(group)
+        .map(|y| {
+            let mut sums = vec![[0.0f64; 7]; n * n];
+            for (pair, terms) in columns(group).filter_map(|x| term(x, y)) {
+                sums[pair].iter_mut().zip(terms).for_each(|(s, t)| *s += t);
+            }
+            sums
+        })
+        .collect::<Vec<_>>();
+
+    // summed in order, the score of a sequence doesn't change between runs
+    let mut sums = vec![[0.0f64; 7]; n * n];
+    for row in rows {
+        for (sum, terms) in sums.iter_mut().zip(row) {
+            sum.iter_mut().zip(terms).for_each(|(s, t)| *s += t);
+        }
+    }
+
+    sums.iter()
+        .filter_map(|&[xx, xy, yy, xd, yd, dd, count]| {
+            let det = xx * yy - xy * xy;
+            // a scene without edges in both directions tells nothing
+            if count < 3.0 || det <= 1e-9 * (xx + yy).powi(2) {
+                return None;
+            }
+            let (dx, dy) = ((yy * xd - xy * yd) / det, (xx * yd - xy * xd) / det);
+            let shift = dx.hypot(dy);
+            if shift == 0.0 {
+                return Some(0.0);
+            }
+
+            // variance of the shift along itself, from what is left of the
+            // differences once it is taken off
+            let variance = (dd - dx * xd - dy * yd).max(0.0) / (count - 2.0);
+            let (ux, uy) = (dx / shift, dy / shift);
+            let along = (yy * ux * ux - 2.0 * xy * ux * uy + xx * uy * uy) / det;
+            Some((shift - SHIFT_SIGMAS * (variance * along).sqrt()).max(0.0))
+        })
+        .collect()
+}
+
+/// Largest spread of the mean level of the frames, in percent of their
+/// median. All the CFA colors together: a frame shifted by a pixel sees the
+/// colors of the scene at other places, not the scene at another level.
+fn flicker(files: &[RawImage]) -> f64 {
+    let mut means = files
+        .par_iter()
+        .map(|file| {
+            let black = file.black_level as f64;
+            let (mut sum, mut count) = (0.0f64, 0u64);
+            for y in (0..file.height).step_by(STEP as usize) {
+                for x in (0..file.width).step_by(STEP as usize) {
+                    sum += file.sample(x, y, None) as f64 - black;
+                    count += 1;
+                }
+            }
+            sum / count.max(1) as f64
+        })
+        .collect::<Vec<_>>();
+
+    means.sort_unstable_by(f64::total_cmp);
+    let median = means[means.len() / 2];
+    if median <= 1.0 {
+        return 0.0;
+    }
+    (means[means.len() - 1] - means[0]) / median * 100.0
+}
+
+/// Scores the artifacts the frames of a sequence leave in their merge: the
+/// share of the image that moved between the two frames seeing its green,
+/// how far frames are off their place in the shift pattern, and how much
+/// the exposure changed between frames
+pub fn score(files: &[RawImage]) -> ArtifactScore {
+    info!("scoring artifacts");
+    let mut moving = 0;
+    let mut measured = 0;
+    let mut residual = 0.0f64;
+
+    for group in files.chunk_by(|a, b| a.group == b.group) {
+        if group[0].width < 3 || group[0].height < 3 {
+            continue;
+        }
+        let histograms = difference_histograms(group);
+        let thresholds = noise(&histograms)
+            .into_iter()
+            .map(|sigma| sigma.map(|sigma| sigma * MOTION_SIGMAS))
+            .collect::<Vec<_>>();
+
+        for (histogram, threshold) in histograms.iter().zip(&thresholds) {
+            measured += histogram.iter().sum::<u64>();
+            if let Some(threshold) = threshold {
+                moving += histogram
+                    .iter()
+                    .enumerate()
+                    .filter(|&(d, _)| d as f32 > *threshold)
+                    .map(|(_, count)| count)
+                    .sum::<u64>();
+            }
+        }
+
+        for shift in shifts(group, &thresholds) {
+            residual = residual.max(shift);
+        }
+    }
+
+    ArtifactScore {
+        motion: moving as f64 / measured.max(1) as f64 * 100.0,
+        residual,
+        flicker: flicker(files),
+    }
+}
+
+/// Logs the score of `files`, failing when it is above `max`
+pub fn check(files: &[RawImage], max: Option<f64>) -> ArtifactScore {
+    let score = score(files);
+    info!(
+        "artifact score {:.1}: {:.2}% moving, frames off by up to {:.2} px, {:.2}% flicker",
+        score.total(),
+        score.motion,
+        score.residual,
+        score.flicker
+    );
+
+    if let Some(max) = max.filter(|&max| score.total() > max) {
+        fail!(
+            Artifacts,
+            "artifact score {:.1} is above --max-artifact-score {}, the sequence needs a reshoot",
+            score.total(),
+            max
+        );
+    }
+    score
+}
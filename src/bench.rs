@@ -0,0 +1,30 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::cli::BenchArgs;
+use crate::context::MergeContext;
+use crate::process;
+
+pub fn run(args: &BenchArgs) {
+    let mut times = Vec::<Duration>::new();
+    // the buffers of the first run are reused by the next ones, as in watch
+    let mut context = MergeContext::default();
+
+    for run in 1..=args.runs {
+        let now = Instant::now();
+        let merge = process(&args.input_files, &args.merge, &mut context);
+        times.push(now.elapsed());
+
+        info!("run {}: {:?}", run, times.last().unwrap());
+        context.recycle(merge);
+    }
+
+    let total = times.iter().sum::<Duration>();
+    info!(
+        "{} runs, best {:?}, average {:?}",
+        times.len(),
+        times.iter().min().unwrap(),
+        total / times.len() as u32
+    );
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use log::info;
+use rayon::prelude::*;
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+
+use crate::failure::fail;
+use crate::RawImage;
+
+/// Per sample radiometric correction of the frames, from a 2 pages float TIFF
+/// the size of the sensor: the gain of every sample, then its offset.
+///
+/// The offset replaces the black level for that sample, e.g. a master bias or
+/// dark, and the gain is what flattens the response, e.g. the mean of the
+/// master flat over the flat itself. Calibrated samples keep the black level
+/// so that the rest of the merge doesn't change.
+pub struct Calibration {
+    width: u32,
+    height: u32,
+    gain: Vec<f32>,
+    offset: Vec<f32>,
+}
+
+fn read_page(decoder: &mut Decoder<BufReader<File>>, path: &Path) -> Vec<f32> {
+    match decoder.read_image() {
+        Ok(DecodingResult::F32(data)) => data,
+        Ok(_) => fail!(
+            Usage,
+            "{} must be a single channel 32 bit float TIFF",
+            path.display()
+        ),
+        Err(e) => fail!(Io, "can't read {}: {}", path.display(), e),
+    }
+}
+
+impl Calibration {
+    pub fn load(path: &Path) -> Self {
+        let file =
+            File::open(path).unwrap_or_else(|e| fail!(Io, "can't open {}: {}", path.display(), e));
+        let mut decoder = Decoder::new(BufReader::new(file))
+            .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e))
+            .with_limits(Limits::unlimited());
+
+        let (width, height) = decoder.dimensions().unwrap();
+        let gain = read_page(&mut decoder, path);
+
+        if !decoder.more_images() {
+            fail!(
+                Usage,
+                "{} has no second page with the offsets",
+                path.display()
+            );
+        }
+        decoder.next_image().unwrap();
+
+        if decoder.dimensions().unwrap() != (width, height) {
+            fail!(
+                Usage,
+                "the gain and offset pages of {} have different sizes",
+                path.display()
+            );
+        }
+        let offset = read_page(&mut decoder, path);
+
+        info!(
+            "loaded {}x{} calibration from {}",
+            width,
+            height,
+            path.display()
+        );
+
+        Self {
+            width,
+            height,
+            gain,
+            offset,
+        }
+    }
+
+    /// `calibration`, or none at all, with the dark frame `dark` taken off
+    /// every sample too, e.g. the long exposure noise reduction frame of the
+    /// camera
+    pub fn with_dark_frame(calibration: Option<Self>, dark: &RawImage) -> Self {
+        let (width, height) = (dark.width, dark.height);
+        let len = width as usize * height as usize;
+        let black = dark.black_level as f32;
+
+        let mut calibration = calibration.unwrap_or_else(|| Self {
+            width,
+            height,
+            gain: vec![1.0; len],
+            offset: vec![black; len],
+        });
+        calibration.check(dark);
+
+        calibration
+            .offset
+            .par_iter_mut()
+            .zip(dark.data_pixels.par_iter())
+            .for_each(|(offset, &dark)| *offset += dark as f32 - black);
+        calibration
+    }
+
+    /// The calibration has to be for the sensor the frames come from
+    pub fn check(&self, file: &RawImage) {
+        if (self.width, self.height) != (file.width, file.height) {
+            fail!(
+                Usage,
+                "the calibration is {}x{} but {} is {}x{}",
+                self.width,
+                self.height,
+                file.path.display(),
+                file.width,
+                file.height
+            );
+        }
+    }
+
+    /// Calibrated value of the sample at `x`, `y` of `file`
+    pub fn apply(&self, file: &RawImage, x: u32, y: u32, raw: u32) -> f32 {
+        if x >= self.width || y >= self.height {
+            return raw as f32;
+        }
+
+        let i = (y * self.width + x) as usize;
+        (raw as f32 - self.offset[i]) * self.gain[i] + file.black_level as f32
+    }
+}
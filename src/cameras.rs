@@ -0,0 +1,136 @@
+use crate::exif::ExifData;
+
+/// Something a camera does differently from the Sony bodies the merge was
+/// written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirk {
+    /// The frames carry no sequence number, they are numbered by file name
+    NoSequenceNumbers,
+    /// The whole sequence is stored in a single raw, which has to be split
+    /// into one file per frame first
+    SingleFileSequence,
+}
+
+/// What is known of a pixel shift camera, used where its files say nothing
+#[derive(Debug)]
+pub struct Camera {
+    pub name: &'static str,
+    /// as exiftool prints Camera Model Name
+    pub models: &'static [&'static str],
+    /// None where it changes with the mode, the files have to tell
+    pub black_level: Option<u32>,
+    pub white_level: Option<u32>,
+    /// (x, y) of the red sample in the top left 2x2 cell of the sensor
+    pub cfa_phase: (u32, u32),
+    /// the shift patterns, by name, the camera shoots; picked over the
+    /// generic ones taking as many frames
+    pub shift_patterns: &'static [&'static str],
+    /// XYZ to camera RGB under D65, times 10000, as in the Adobe DNG
+    /// converter; None where it isn't known
+    pub color_matrix: Option<[i32; 9]>,
+    pub quirks: &'static [Quirk],
+}
+
+impl Camera {
+    pub fn has(&self, quirk: Quirk) -> bool {
+        self.quirks.contains(&quirk)
+    }
+}
+
+const SONY_PATTERNS: &[&str] = &["4 shots", "16 shots"];
+
+pub static CAMERAS: &[Camera] = &[
+    Camera {
+        name: "Sony A7R IV",
+        models: &["ILCE-7RM4", "ILCE-7RM4A"],
+        black_level: Some(512),
+        white_level: Some(16383),
+        cfa_phase: (0, 0),
+        shift_patterns: SONY_PATTERNS,
+        color_matrix: Some([7662, -2686, -660, -5240, 12965, 2530, -796, 1508, 6167]),
+        quirks: &[],
+    },
+    Camera {
+        name: "Sony A7R V",
+        models: &["ILCE-7RM5"],
+        black_level: Some(512),
+        white_level: Some(16383),
+        cfa_phase: (0, 0),
+        shift_patterns: SONY_PATTERNS,
+        color_matrix: None,
+        quirks: &[],
+    },
+    Camera {
+        name: "Sony A1",
+        models: &["ILCE-1", "ILCE-1M2"],
+        black_level: Some(512),
+        white_level: Some(16383),
+        cfa_phase: (0, 0),
+        shift_patterns: SONY_PATTERNS,
+        color_matrix: None,
+        quirks: &[],
+    },
+    Camera {
+        name: "Sony A7CR",
+        models: &["ILCE-7CR"],
+        black_level: Some(512),
+        white_level: Some(16383),
+        cfa_phase: (0, 0),
+        shift_patterns: SONY_PATTERNS,
+        color_matrix: None,
+        quirks: &[],
+    },
+    Camera {
+        name: "Pentax K-1",
+        models: &["PENTAX K-1", "PENTAX K-1 Mark II"],
+        black_level: None,
+        white_level: None,
+        cfa_phase: (1, 1),
+        shift_patterns: &["4 shots"],
+        color_matrix: Some([8596, -2981, -639, -4202, 12046, 2431, -685, 1424, 6122]),
+        quirks: &[Quirk::SingleFileSequence],
+    },
+    Camera {
+        name: "Fujifilm GFX100",
+        models: &["GFX100", "GFX100S", "GFX100 II", "GFX100RF"],
+        black_level: None,
+        white_level: None,
+        cfa_phase: (0, 0),
+        shift_patterns: &["16 shots"],
+        color_matrix: None,
+        quirks: &[Quirk::NoSequenceNumbers],
+    },
+];
+
+pub fn lookup(model: Option<&str>) -> Option<&'static Camera> {
+    let model = model?;
+    CAMERAS
+        .iter()
+        .find(|camera| camera.models.iter().any(|m| m.eq_ignore_ascii_case(model)))
+}
+
+/// Position of the red sample in an exiftool CFA Pattern like
+/// "[Red,Green][Green,Blue]"
+fn red_position(cfa_pattern: &str) -> Option<(u32, u32)> {
+    let colors = cfa_pattern
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|color| !color.is_empty())
+        .collect::<Vec<_>>();
+
+    if colors.len() != 4 {
+        return None;
+    }
+
+    let red = colors.iter().position(|&color| color == "Red")?;
+    Some((red as u32 % 2, red as u32 / 2))
+}
+
+/// CFA phase of a file: its own CFA Pattern, then the camera database, then
+/// the RGGB of the Sony bodies
+pub fn cfa_phase(exif: &ExifData) -> (u32, u32) {
+    exif.cfa_pattern
+        .as_deref()
+        .and_then(red_position)
+        .or_else(|| lookup(exif.model.as_deref()).map(|camera| camera.cfa_phase))
+        .unwrap_or((0, 0))
+}
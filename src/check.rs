@@ -0,0 +1,38 @@
+use log::info;
+
+use crate::cli::CheckArgs;
+use crate::failure::fail;
+use crate::load_files;
+use crate::sequence::Numbering;
+
+/// Loads the sequence like a merge would, every problem found along the way
+/// ends the run
+pub fn run(args: &CheckArgs) {
+    let (files, pattern, _) = load_files(
+        &args.input_files,
+        Numbering::new(args.trust_filename_order, &args.sequence_numbers),
+    );
+
+    if let Some(file) = files
+        .iter()
+        .find(|file| (file.width, file.height) != (files[0].width, files[0].height))
+    {
+        fail!(
+            Metadata,
+            "{} is {}x{}, {} is {}x{}",
+            file.path.display(),
+            file.width,
+            file.height,
+            files[0].path.display(),
+            files[0].width,
+            files[0].height
+        );
+    }
+
+    info!(
+        "{} sequence is complete, it merges into a {}x{} image",
+        pattern.name,
+        files[0].width * pattern.scale,
+        files[0].height * pattern.scale
+    );
+}
@@ -0,0 +1,154 @@
+use log::{debug, info};
+use rayon::prelude::*;
+
+use crate::context::MergeContext;
+use crate::planes::Planes;
+
+/// How --chroma-smooth filters the color of the merge
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Filter {
+    /// median of the neighbourhood, removes moiré without bleeding color
+    /// across edges
+    #[default]
+    Median,
+    /// mean of the neighbourhood, faster and softer
+    Box,
+}
+
+/// Strength of --chroma-smooth, from 0 to 1
+pub fn parse_strength(value: &str) -> Result<f32, String> {
+    match value.trim().parse::<f32>() {
+        Ok(strength) if (0.0..=1.0).contains(&strength) => Ok(strength),
+        _ => Err(format!("{:?} is not a strength between 0 and 1", value)),
+    }
+}
+
+/// Median of the (2 radius + 1)² neighbourhood of every value, the edges
+/// repeated past them
+fn median(plane: &[f32], width: usize, height: usize, radius: usize, out: &mut [f32]) {
+    out.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let mut window = Vec::with_capacity((2 * radius + 1) * (2 * radius + 1));
+        for (x, v) in row.iter_mut().enumerate() {
+            window.clear();
+            for wy in y.saturating_sub(radius)..=(y + radius).min(height - 1) {
+                let line = &plane[wy * width..(wy + 1) * width];
+                window.extend_from_slice(
+                    &line[x.saturating_sub(radius)..=(x + radius).min(width - 1)],
+                );
+            }
+            let middle = window.len() / 2;
+            *v = *window.select_nth_unstable_by(middle, f32::total_cmp).1;
+        }
+    });
+}
+
+/// Mean of the (2 radius + 1)² neighbourhood of every value, cut at the
+/// edges, as a horizontal then a vertical running sum
+fn mean(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    radius: usize,
+    out: &mut [f32],
+    context: &mut MergeContext,
+) {
+    let mut rows = context.floats(plane.len());
+    rows.par_chunks_mut(width)
+        .zip(plane.par_chunks(width))
+        .for_each(|(row, line)| {
+            let mut sum = line[..radius.min(width)].iter().sum::<f32>();
+            for x in 0..width {
+                if x + radius < width {
+                    sum += line[x + radius];
+                }
+                if x > radius {
+                    sum -= line[x - radius - 1];
+                }
+                let n = (x + radius).min(width - 1) + 1 - x.saturating_sub(radius);
+                row[x] = sum / n as f32;
+            }
+        });
+
+    out.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let (top, bottom) = (y.saturating_sub(radius), (y + radius).min(height - 1));
+        row.fill(0.0);
+        for wy in top..=bottom {
+            for (v, &r) in row.iter_mut().zip(&rows[wy * width..(wy + 1) * width]) {
+                *v += r;
+            }
+        }
+        let n = (bottom + 1 - top) as f32;
+        row.iter_mut().for_each(|v| *v /= n);
+    });
+    context.recycle_floats(rows);
+}
+
+/// Smooths the color of the merge, leaving its luminance alone, to hide the
+/// color moiré fine repeating patterns like fabrics still leave near the
+/// resolution limit of the merge.
+///
+/// The merge is split into luma, (R + 2G + B) / 4, and the R - G and B - G
+/// differences. Only the differences are filtered, and `strength` of the
+/// filtered ones replaces them before the channels are put back together
+/// with the luma they had.
+pub fn smooth(
+    planes: &mut Planes,
+    black: [f32; 3],
+    channel_samples: [u32; 3],
+    filter: Filter,
+    radius: u32,
+    strength: f32,
+    context: &mut MergeContext,
+) {
+    info!("smoothing chroma, {:?} filter of radius {}", filter, radius);
+
+    let (width, height) = (planes.width as usize, planes.height as usize);
+    let radius = radius as usize;
+    let samples = channel_samples.map(|n| n as f32);
+    let [r, g, b] = &mut planes.channels;
+
+    // the channels without their black level, per sample
+    let level = |c: usize, v: f32| (v - black[c]) / samples[c];
+
+    let mut cr = context.floats(r.len());
+    let mut cb = context.floats(r.len());
+    cr.par_iter_mut()
+        .zip(cb.par_iter_mut())
+        .enumerate()
+        .for_each(|(i, (cr, cb))| {
+            let green = level(1, g[i]);
+            *cr = level(0, r[i]) - green;
+            *cb = level(2, b[i]) - green;
+        });
+
+    let mut filtered = context.floats(r.len());
+    for (chroma, name) in [(&mut cr, "R - G"), (&mut cb, "B - G")] {
+        debug!("filtering {}", name);
+        match filter {
+            Filter::Median => median(chroma, width, height, radius, &mut filtered),
+            Filter::Box => mean(chroma, width, height, radius, &mut filtered, context),
+        }
+        chroma
+            .par_iter_mut()
+            .zip(&filtered)
+            .for_each(|(v, &f)| *v += (f - *v) * strength);
+    }
+
+    r.par_iter_mut()
+        .zip(g.par_iter_mut())
+        .zip(b.par_iter_mut())
+        .enumerate()
+        .for_each(|(i, ((r, g), b))| {
+            let (red, green, blue) = (level(0, *r), level(1, *g), level(2, *b));
+            let luma = (red + 2.0 * green + blue) / 4.0;
+
+            let green = luma - (cr[i] + cb[i]) / 4.0;
+            *r = (cr[i] + green) * samples[0] + black[0];
+            *g = green * samples[1] + black[1];
+            *b = (cb[i] + green) * samples[2] + black[2];
+        });
+
+    for buffer in [cr, cb, filtered] {
+        context.recycle_floats(buffer);
+    }
+}
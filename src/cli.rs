@@ -0,0 +1,484 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+
+use crate::reconstruct::Reconstruct;
+use crate::{chroma, cores, debug, output, temperature, transform};
+use crate::{is_raw, GreenMode};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Only log warnings and errors
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Report errors as a JSON object on stderr, one per line, besides the
+    /// exit code telling what kind of error it is
+    #[arg(long, global = true)]
+    pub json_errors: bool,
+
+    /// TOML file of extra shift patterns, on top of the built-in ones and
+    /// ~/.config/psmsmerge/patterns.toml
+    #[arg(long, global = true)]
+    pub patterns: Option<PathBuf>,
+
+    /// Cores to run on: `performance` leaves the efficiency cores of hybrid
+    /// CPUs out, found by timing every core, `all` takes every one, or a
+    /// list of logical CPUs like 0-7,16
+    #[arg(
+        long,
+        global = true,
+        value_name = "CORES",
+        default_value = "performance",
+        value_parser = cores::parse
+    )]
+    pub cores: cores::Cores,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Merge a pixel shift sequence into a single image
+    Merge(MergeArgs),
+    /// Print the pixel shift metadata of raw files
+    Inspect(InspectArgs),
+    /// Check that a sequence is complete and can be merged, without merging it
+    Check(CheckArgs),
+    /// Time the merge of a sequence, without writing it anywhere
+    Bench(BenchArgs),
+    /// Watch a tethered capture directory and merge every complete burst
+    /// that lands in it
+    Watch(WatchArgs),
+    /// Blend images shot at different focus distances, keeping the sharpest
+    /// parts of each
+    Stack(StackArgs),
+    /// Shoot a simulated pixel shift sequence of a full color image, to try
+    /// the merge and its options on frames whose truth is known
+    Simulate(SimulateArgs),
+}
+
+/// How a sequence is turned into an image
+#[derive(clap::Args, Debug, Clone)]
+pub struct MergeOptions {
+    /// Downscale the merged image by an integer factor, e.g. 2 to get a
+    /// 16 shots merge back to the native sensor resolution
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub downscale: Option<u32>,
+
+    /// Scale the merged image so that it matches the brightness of a
+    /// separately shot raw, e.g. a normal exposure to be blended with it later
+    #[arg(long)]
+    pub match_exposure: Option<PathBuf>,
+
+    /// Rotate the merged image clockwise, for cameras mounted sideways or
+    /// upside down
+    #[arg(long, value_enum)]
+    pub rotate: Option<transform::Rotation>,
+
+    /// Mirror the merged image, applied after --rotate
+    #[arg(long, value_enum)]
+    pub flip: Option<transform::Flip>,
+
+    /// Also write a float TIFF next to the output with the fraction of the
+    /// frames that cleanly contributed to each pixel, for weighting in later
+    /// fusion: clipped samples count out, the pixels of --motion-mask as 1
+    #[arg(long)]
+    pub weight_map: bool,
+
+    /// Compare the merge against a single frame demosaic and report whether
+    /// pixel shift actually improved on it
+    #[arg(long)]
+    pub quality_report: bool,
+
+    /// White balance the merge for a light of this color temperature, in
+    /// kelvin, with the color matrix of the camera
+    #[arg(long, value_name = "K", value_parser = temperature::parse_temperature)]
+    pub temp: Option<f64>,
+
+    /// Green (negative) to magenta (positive) correction on top of --temp
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        value_parser = temperature::parse_tint,
+        requires = "temp"
+    )]
+    pub tint: f64,
+
+    /// Keep the columns on the left and rows at the top that some frames are
+    /// shifted away from, darker for missing their samples
+    #[arg(long)]
+    pub no_border_crop: bool,
+
+    /// How to combine the two green samples of every pixel
+    #[arg(long, value_enum, default_value_t)]
+    pub green: GreenMode,
+
+    /// Fail without writing anything when the artifact score of the merge,
+    /// about the percent of the image that moved, misaligned or flickered,
+    /// is above this
+    #[arg(long, value_name = "SCORE")]
+    pub max_artifact_score: Option<f64>,
+
+    /// Start even if the merge doesn't look like it fits in the available memory
+    #[arg(long)]
+    pub no_memory_check: bool,
+
+    /// Take the frames in the order of their file names rather than by their
+    /// sequence numbers, for files whose metadata got lost
+    #[arg(long)]
+    pub trust_filename_order: bool,
+
+    /// Sequence number of every input file, in the order they are given, for
+    /// frames whose metadata has none, like decoded ones without a sidecar;
+    /// 0 marks a dark frame
+    #[arg(
+        long,
+        value_name = "N,...",
+        value_delimiter = ',',
+        conflicts_with = "trust_filename_order"
+    )]
+    pub sequence_numbers: Vec<u32>,
+
+    /// Subtract the dark frame some cameras shoot after a long exposure
+    /// sequence for noise reduction, rather than only leaving it out
+    #[arg(long)]
+    pub nr_dark_frame: bool,
+
+    /// Find the faint banding of the PDAF rows in the shadows of every frame,
+    /// and take it off before the merge sums it up
+    #[arg(long)]
+    pub pdaf_fix: bool,
+
+    /// 2 pages float TIFF the size of the sensor, with the gain then the
+    /// offset of every sample, applied to the frames before they are merged
+    #[arg(long)]
+    pub calibration: Option<PathBuf>,
+
+    /// Print which frame, source pixel and CFA color every channel of the
+    /// merged pixel at x,y comes from, before --downscale and --rotate
+    #[arg(long, value_name = "X,Y", value_parser = debug::parse_point)]
+    pub debug_pixel: Option<(u32, u32)>,
+
+    /// Write a 4 samples TIFF keeping the two green measurements of every
+    /// pixel apart, as R, G1, G2, B, rather than combined with --green
+    #[arg(long, conflicts_with_all = ["pyramid", "planar"])]
+    pub rggb_out: bool,
+
+    /// Grayscale image, the size of the merge or of the sensor, whose white
+    /// parts are taken from the first frame alone rather than merged, for
+    /// regions that moved between the shots
+    #[arg(long, conflicts_with = "rggb_out")]
+    pub motion_mask: Option<PathBuf>,
+
+    /// How far, in merged pixels, the --motion-mask regions fade into the
+    /// merge on each side of their edges, 0 to switch from one to the other
+    /// at once
+    #[arg(long, default_value_t = 8, requires = "motion_mask")]
+    pub feather: u32,
+
+    /// Quick quarter resolution merge of the first 4 frames only, every other
+    /// CFA quad, for culling sequences rather than keeping the result
+    #[arg(long, conflicts_with_all = ["rggb_out", "motion_mask", "weight_map", "quality_report", "debug_pixel"])]
+    pub fast: bool,
+
+    /// How the groups of a 16 shots merge become output pixels; mlr fits them
+    /// to every sample covering them, slower and experimental
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["fast", "rggb_out", "weight_map", "debug_pixel"]
+    )]
+    pub reconstruct: Reconstruct,
+
+    /// Smooth the color of the merge but not its luminance, against the color
+    /// moiré left on fabrics and other fine patterns, from 0 (not at all) to 1
+    /// (the filtered color alone)
+    #[arg(long, value_name = "STRENGTH", value_parser = chroma::parse_strength, conflicts_with = "rggb_out")]
+    pub chroma_smooth: Option<f32>,
+
+    /// Filter of --chroma-smooth
+    #[arg(long, value_enum, default_value_t, requires = "chroma_smooth")]
+    pub chroma_filter: chroma::Filter,
+
+    /// Radius of the --chroma-smooth filter, in merged pixels
+    #[arg(long, default_value_t = 2, requires = "chroma_smooth", value_parser = clap::value_parser!(u32).range(1..=32))]
+    pub chroma_radius: u32,
+}
+
+/// What happens around the written image
+#[derive(clap::Args, Debug, Clone)]
+pub struct OutputOptions {
+    /// Replace the output file if it already exists
+    #[arg(long, conflicts_with = "no_clobber")]
+    pub overwrite: bool,
+
+    /// Skip the merge, without failing, if the output file already exists
+    #[arg(long)]
+    pub no_clobber: bool,
+
+    /// Name of this tile when shooting a panorama, written to the output
+    /// metadata. Names like r2c3 also give the row and column of the tile
+    #[arg(long)]
+    pub tile_name: Option<String>,
+
+    /// Overlap between neighbouring panorama tiles, in percent
+    #[arg(long, default_value_t = 30.0)]
+    pub overlap_pct: f32,
+
+    /// Append the output to a Hugin/PTGui project stub, created if missing,
+    /// so that all the merges of a panorama can be stitched together
+    #[arg(long)]
+    pub project: Option<PathBuf>,
+
+    /// Write a tiled TIFF with reduced resolution overviews, so that viewers
+    /// can pan and zoom huge merges instantly
+    #[arg(long, conflicts_with = "planar")]
+    pub pyramid: bool,
+
+    /// Write every channel on its own, for per channel calibration in
+    /// scientific and astro tools
+    #[arg(long, value_enum)]
+    pub planar: Option<output::Planar>,
+
+    /// Leave out the record of the tool version, arguments and frame hashes
+    /// TIFF outputs carry in their XMP metadata, and the time spent hashing
+    #[arg(long)]
+    pub no_provenance: bool,
+
+    /// Compress TIFF outputs, most tools read Deflate and LZW fine
+    #[arg(long, value_enum)]
+    pub compress: Option<output::Compress>,
+
+    /// Difference neighbouring 16 bit samples before --compress, which
+    /// typically shrinks photos a lot further
+    #[arg(long, requires = "compress")]
+    pub predictor: bool,
+
+    /// Store the samples of --planar and FITS outputs as 32 bit floats
+    #[arg(long)]
+    pub float: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MergeArgs {
+    #[arg(short, long)]
+    pub output_file: PathBuf,
+
+    /// The raws of the sequence; with --archive, their names in the archive,
+    /// every raw in it if left out
+    #[arg(
+        short,
+        long,
+        value_parser,
+        num_args = 1..,
+        value_delimiter = ' ',
+        required_unless_present = "archive"
+    )]
+    pub input_files: Vec<PathBuf>,
+
+    /// Read the raws from a ZIP or TAR archive, e.g. a camera backup, without
+    /// extracting it; `-` reads the archive from stdin
+    #[arg(long)]
+    pub archive: Option<PathBuf>,
+
+    /// Show a preview of the merge in the desktop image viewer once it is
+    /// written, at up to this many pixels on the longest side, 0 for every
+    /// merged pixel to look at it at 100%. psmsmerge has no preview window
+    /// of its own yet, the viewer pans and zooms.
+    #[arg(long, num_args = 0..=1, default_missing_value = "4096")]
+    pub show: Option<u32>,
+
+    #[command(flatten)]
+    pub merge: MergeOptions,
+
+    #[command(flatten)]
+    pub output: OutputOptions,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InspectArgs {
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Print a JSON array instead of text, for scripts
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CheckArgs {
+    #[arg(required = true)]
+    pub input_files: Vec<PathBuf>,
+
+    /// Take the frames in the order of their file names rather than by their
+    /// sequence numbers
+    #[arg(long)]
+    pub trust_filename_order: bool,
+
+    /// Sequence number of every input file, in the order they are given
+    #[arg(
+        long,
+        value_name = "N,...",
+        value_delimiter = ',',
+        conflicts_with = "trust_filename_order"
+    )]
+    pub sequence_numbers: Vec<u32>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    #[arg(required = true)]
+    pub input_files: Vec<PathBuf>,
+
+    /// How many times to run the merge
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub runs: u32,
+
+    #[command(flatten)]
+    pub merge: MergeOptions,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+    /// Tethered capture directory
+    pub dir: PathBuf,
+
+    /// Where the merges and their previews go
+    #[arg(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Seconds a burst that could be complete waits for more frames, on top
+    /// of the Pixel Shift Interval of its frames, before being merged
+    #[arg(long, value_name = "SECONDS", default_value_t = 5.0)]
+    pub settle: f64,
+
+    #[command(flatten)]
+    pub merge: MergeOptions,
+
+    #[command(flatten)]
+    pub output: OutputOptions,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StackArgs {
+    #[arg(short, long)]
+    pub output_file: PathBuf,
+
+    /// Merged images, or directories holding a sequence to merge first
+    #[arg(required = true, num_args = 2..)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Used for the directories given as inputs
+    #[command(flatten)]
+    pub merge: MergeOptions,
+
+    #[command(flatten)]
+    pub output: OutputOptions,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SimulateArgs {
+    /// Full color image to shoot, e.g. a PNG or a 16 bit TIFF
+    pub input: PathBuf,
+
+    /// Where the frames go, one TIFF each
+    #[arg(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Frames of the sequence, the shift pattern taking as many is used
+    #[arg(long, default_value_t = 4)]
+    pub shots: usize,
+
+    /// Standard deviation of the read noise of every sample, in DN
+    #[arg(long, default_value_t = 0.0)]
+    pub noise: f64,
+
+    /// Move every frame by up to this many sensor pixels on each axis, at
+    /// random, like a tripod that isn't quite steady
+    #[arg(long, value_name = "PIXELS", default_value_t = 0.0)]
+    pub misalign: f64,
+
+    /// Move the middle of the image to the right by this many of its pixels
+    /// every frame, like a subject that doesn't keep still
+    #[arg(long, value_name = "PIXELS", default_value_t = 0.0)]
+    pub motion: f64,
+
+    /// The image is linear already, e.g. a merge, rather than sRGB
+    #[arg(long)]
+    pub linear: bool,
+
+    /// Seed of the noise and the misalignment, the same one gives the same
+    /// frames
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+}
+
+/// Raw files dropped on the executable, or on a shortcut to it, are merged
+/// next to the first one with the default options and the result opened in
+/// the image viewer, no terminal needed. It stands in for a GUI, there is
+/// no window to pick the options in.
+fn dropped_files(args: &[OsString]) -> Option<Vec<OsString>> {
+    let files = args.get(1..)?;
+    if files.is_empty()
+        || !files
+            .iter()
+            .all(|file| is_raw(Path::new(file)) && Path::new(file).is_file())
+    {
+        return None;
+    }
+
+    let first = Path::new(&files[0]);
+    let mut name = first.file_stem().unwrap().to_os_string();
+    name.push("_merged.tiff");
+    let output = first.with_file_name(name);
+
+    let mut merge = vec![
+        args[0].clone(),
+        "merge".into(),
+        "--show".into(),
+        "-o".into(),
+        output.into_os_string(),
+        "-i".into(),
+    ];
+    merge.extend(files.iter().cloned());
+    Some(merge)
+}
+
+/// Command line arguments, with `merge` implied when no subcommand is given
+/// so that scripts written for the flat command line keep working
+pub fn args() -> Vec<OsString> {
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+
+    if let Some(merge) = dropped_files(&args) {
+        return merge;
+    }
+
+    // global options may come before the subcommand
+    let mut first = 1;
+    while let Some(arg) = args.get(first).map(|arg| arg.to_string_lossy()) {
+        match arg.as_ref() {
+            "-q" | "--quiet" | "--json-errors" => first += 1,
+            "--patterns" | "--cores" => first += 2,
+            arg if arg.starts_with("--patterns=") || arg.starts_with("--cores=") => first += 1,
+            _ => break,
+        }
+    }
+
+    let implied_merge = args.get(first).is_some_and(|first| {
+        let first = first.to_string_lossy();
+        first.starts_with('-') && !["-h", "--help", "-V", "--version"].contains(&first.as_ref())
+    });
+
+    if implied_merge {
+        args.insert(first, "merge".into());
+    }
+
+    args
+}
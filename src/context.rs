@@ -0,0 +1,77 @@
+use crate::planes::Planes;
+use crate::Merge;
+
+/// Buffers kept from one merge to the next, for the modes that merge sequence
+/// after sequence: watch, stack and bench. The planes and samples of a 16
+/// shots merge are gigabytes, which a reused buffer doesn't have to get from
+/// the kernel and fault in page by page all over again.
+#[derive(Default)]
+pub struct MergeContext {
+    floats: Vec<Vec<f32>>,
+    samples: Vec<Vec<u16>>,
+}
+
+/// The smallest buffer of `pool` with room for `len` values. When none has,
+/// the merges changed size, e.g. another camera, and the pool is freed.
+fn take<T>(pool: &mut Vec<Vec<T>>, len: usize) -> Option<Vec<T>> {
+    let fitting = pool
+        .iter()
+        .enumerate()
+        .filter(|(_, buffer)| buffer.capacity() >= len)
+        .min_by_key(|(_, buffer)| buffer.capacity())
+        .map(|(i, _)| i);
+
+    match fitting {
+        Some(i) => Some(pool.swap_remove(i)),
+        None => {
+            pool.clear();
+            None
+        }
+    }
+}
+
+impl MergeContext {
+    /// `len` zeros
+    pub fn floats(&mut self, len: usize) -> Vec<f32> {
+        match take(&mut self.floats, len) {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer.resize(len, 0.0);
+                buffer
+            }
+            None => vec![0.0; len],
+        }
+    }
+
+    /// `len` samples, to be overwritten
+    pub fn samples(&mut self, len: usize) -> Vec<u16> {
+        match take(&mut self.samples, len) {
+            Some(mut buffer) => {
+                buffer.resize(len, 0);
+                buffer.truncate(len);
+                buffer
+            }
+            None => vec![0; len],
+        }
+    }
+
+    pub fn recycle_floats(&mut self, floats: Vec<f32>) {
+        self.floats.push(floats);
+    }
+
+    pub fn recycle_planes<const N: usize>(&mut self, planes: Planes<N>) {
+        self.floats.extend(planes.channels);
+    }
+
+    pub fn recycle_samples(&mut self, samples: Vec<u16>) {
+        self.samples.push(samples);
+    }
+
+    /// Keeps the images of a merge that was saved
+    pub fn recycle(&mut self, merge: Merge) {
+        self.recycle_samples(merge.imgbuf.into_raw());
+        if let Some(rggb) = merge.rggb {
+            self.recycle_samples(rggb.into_raw());
+        }
+    }
+}
@@ -0,0 +1,297 @@
+// what gets used depends on what the system lets threads be put on
+#![cfg_attr(
+    not(any(target_os = "linux", target_os = "macos")),
+    allow(unused_imports)
+)]
+
+use log::{debug, info, warn};
+
+use crate::failure::fail;
+
+/// classes of cores running at least this fraction of the throughput of
+/// the fastest one count as performance cores; efficiency cores are at
+/// about half of it
+#[cfg(target_os = "linux")]
+const PERFORMANCE_SHARE: f64 = 0.8;
+
+/// best of this many runs of the benchmark on every core, against the noise
+/// of other processes and clocks ramping up
+#[cfg(target_os = "linux")]
+const RUNS: usize = 3;
+
+/// cores of every class that are timed, the median of them stands for the
+/// class so that one busy core doesn't move it
+#[cfg(target_os = "linux")]
+const TIMED_PER_CLASS: usize = 4;
+
+/// Which cores the parallel parts run on, --cores
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Cores {
+    /// the fastest cores of a hybrid CPU, every core of the others
+    #[default]
+    Performance,
+    /// every core, as rayon schedules them
+    All,
+    /// these logical CPUs
+    List(Vec<usize>),
+}
+
+/// --cores: `performance`, `all`, or logical CPUs like `0-7,16`
+pub fn parse(value: &str) -> Result<Cores, String> {
+    match value.trim() {
+        "performance" => return Ok(Cores::Performance),
+        "all" => return Ok(Cores::All),
+        _ => (),
+    }
+
+    cpu_list(value).map(Cores::List).ok_or_else(|| {
+        format!(
+            "{:?} is neither performance, all nor a list of CPUs like 0-7,16",
+            value
+        )
+    })
+}
+
+/// Logical CPUs like `0-7,16`, the way the kernel lists them too
+fn cpu_list(value: &str) -> Option<Vec<usize>> {
+    let number = |v: &str| v.trim().parse::<usize>().ok();
+
+    let mut cpus = Vec::new();
+    for range in value.trim().split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(number(range)?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+/// Time of a fixed amount of the kind of float work the merge does, about
+/// 10 ms on a desktop core so that timer resolution and a stray interrupt
+/// don't count, the best of `RUNS`
+#[cfg(target_os = "linux")]
+fn benchmark() -> std::time::Duration {
+    let mut values = [1.0f32; 1024];
+    (0..RUNS)
+        .map(|_| {
+            let start = std::time::Instant::now();
+            for _ in 0..100_000 {
+                for v in values.iter_mut() {
+                    *v = *v * 0.999 + 0.001;
+                }
+                std::hint::black_box(&mut values);
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+#[cfg(target_os = "linux")]
+mod affinity {
+    /// Logical CPUs the process may run on
+    pub fn allowed() -> Vec<usize> {
+        let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        if unsafe { libc::sched_getaffinity(0, size, &mut set) } != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect()
+    }
+
+    /// Keeps the calling thread on `cpus`
+    pub fn pin(cpus: &[usize]) -> bool {
+        let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+        for &cpu in cpus {
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        unsafe { libc::sched_setaffinity(0, size, &set) == 0 }
+    }
+}
+
+/// The kinds of cores of `allowed`, when the kernel tells them apart: the
+/// core and atom PMUs of Intel hybrid CPUs, or the capacities the scheduler
+/// gives the cores of ARM ones. Clock speeds don't tell, they differ on
+/// many CPUs whose cores are all alike, e.g. the favoured cores of Turbo
+/// Boost Max 3.0 and AMD preferred cores.
+#[cfg(target_os = "linux")]
+fn core_classes(allowed: &[usize]) -> Vec<Vec<usize>> {
+    let read = |path: String| std::fs::read_to_string(path).ok();
+    let within = |cpus: Vec<usize>| {
+        cpus.into_iter()
+            .filter(|cpu| allowed.contains(cpu))
+            .collect::<Vec<_>>()
+    };
+
+    let pmus = ["cpu_core", "cpu_atom"]
+        .map(|pmu| read(format!("/sys/devices/{}/cpus", pmu)).and_then(|cpus| cpu_list(&cpus)));
+    if let [Some(core), Some(atom)] = pmus {
+        return [within(core), within(atom)]
+            .into_iter()
+            .filter(|class| !class.is_empty())
+            .collect();
+    }
+
+    let mut classes = std::collections::BTreeMap::<u64, Vec<usize>>::new();
+    for &cpu in allowed {
+        let capacity = read(format!("/sys/devices/system/cpu/cpu{}/cpu_capacity", cpu))
+            .and_then(|capacity| capacity.trim().parse().ok());
+        if let Some(capacity) = capacity {
+            classes.entry(capacity).or_default().push(cpu);
+        }
+    }
+    classes.into_values().rev().collect()
+}
+
+/// Throughput of a thread pinned to `cpu`, relative to nothing in particular
+#[cfg(target_os = "linux")]
+fn throughput(cpu: usize) -> Option<f64> {
+    std::thread::spawn(move || affinity::pin(&[cpu]).then(benchmark))
+        .join()
+        .unwrap()
+        .map(|time| 1.0 / time.as_secs_f64())
+}
+
+/// The cores of the `classes` within `PERFORMANCE_SHARE` of the throughput
+/// of the fastest class, measured on a few cores of each, one at a time
+#[cfg(target_os = "linux")]
+fn performance_cores(classes: &[Vec<usize>]) -> Vec<usize> {
+    let medians = classes
+        .iter()
+        .map(|class| {
+            let step = class.len().div_ceil(TIMED_PER_CLASS);
+            let mut timed = class
+                .iter()
+                .step_by(step)
+                .filter_map(|&cpu| throughput(cpu))
+                .collect::<Vec<_>>();
+            timed.sort_by(f64::total_cmp);
+            timed.get(timed.len() / 2).copied()
+        })
+        .collect::<Vec<_>>();
+
+    let Some(fastest) = medians.iter().flatten().copied().reduce(f64::max) else {
+        return classes.concat();
+    };
+    for (class, median) in classes.iter().zip(&medians) {
+        if let Some(median) = median {
+            debug!(
+                "CPUs {:?}: {:.0}% of the fastest",
+                class,
+                median / fastest * 100.0
+            );
+        }
+    }
+
+    let mut cpus = classes
+        .iter()
+        .zip(medians)
+        .filter(|(_, median)| median.is_some_and(|median| median / fastest >= PERFORMANCE_SHARE))
+        .flat_map(|(class, _)| class.iter().copied())
+        .collect::<Vec<_>>();
+    cpus.sort_unstable();
+    cpus
+}
+
+/// Puts the threads of rayon on the cores `cores` asks for, pinned to them.
+/// Finding the performance cores takes a moment, it is only done for the
+/// subcommands that `merge`.
+#[cfg(target_os = "linux")]
+pub fn init(cores: &Cores, merge: bool) {
+    let allowed = affinity::allowed();
+    let cpus = match cores {
+        Cores::All => return,
+        Cores::Performance => {
+            let classes = core_classes(&allowed);
+            if !merge || classes.len() < 2 {
+                return;
+            }
+            let cpus = performance_cores(&classes);
+            if cpus.len() == allowed.len() {
+                debug!("the {} CPUs are alike, using all of them", cpus.len());
+                return;
+            }
+            cpus
+        }
+        Cores::List(cpus) => {
+            if let Some(cpu) = cpus.iter().find(|cpu| !allowed.contains(cpu)) {
+                fail!(
+                    Usage,
+                    "CPU {} isn't one this process can run on, those are {:?}",
+                    cpu,
+                    allowed
+                );
+            }
+            cpus.clone()
+        }
+    };
+
+    info!("running on CPUs {:?}", cpus);
+    let pinned = cpus.clone();
+    build_pool(cpus.len(), move || {
+        if !affinity::pin(&pinned) {
+            warn!("can't keep a thread on CPUs {:?}", pinned);
+        }
+    });
+}
+
+/// macOS can't pin threads, there are only as many threads as performance
+/// cores, in a QoS class that keeps them off the efficiency ones
+#[cfg(target_os = "macos")]
+pub fn init(cores: &Cores, merge: bool) {
+    match cores {
+        Cores::All => (),
+        Cores::Performance if !merge => (),
+        Cores::Performance => {
+            // the cores of the fastest performance level, 0
+            let mut count: libc::c_int = 0;
+            let mut size = std::mem::size_of::<libc::c_int>();
+            let found = unsafe {
+                libc::sysctlbyname(
+                    c"hw.perflevel0.logicalcpu".as_ptr(),
+                    (&mut count as *mut libc::c_int).cast(),
+                    &mut size,
+                    std::ptr::null_mut(),
+                    0,
+                )
+            } == 0;
+            if !found || count <= 0 {
+                debug!("no performance levels, using all the cores");
+                return;
+            }
+
+            info!("running on the {} performance cores", count);
+            build_pool(count as usize, || unsafe {
+                libc::pthread_set_qos_class_self_np(libc::qos_class_t::QOS_CLASS_USER_INITIATED, 0);
+            });
+        }
+        Cores::List(_) => warn!("macOS doesn't let threads be pinned to CPUs, --cores is ignored"),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn init(cores: &Cores, _merge: bool) {
+    if *cores != Cores::Performance && *cores != Cores::All {
+        warn!("threads can't be pinned to CPUs on this system, --cores is ignored");
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn build_pool(threads: usize, start: impl Fn() + Send + Sync + 'static) {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .start_handler(move |_| start())
+        .build_global()
+        .unwrap_or_else(|e| fail!(Internal, "can't start the threads: {}", e));
+}
@@ -0,0 +1,83 @@
+use crate::patterns::ShiftPattern;
+use crate::{Color, GreenMode, RawImage, RgbImage16};
+
+/// `x,y` of --debug-pixel
+pub fn parse_point(value: &str) -> Result<(u32, u32), String> {
+    let (x, y) = value
+        .split_once(',')
+        .ok_or_else(|| format!("expected x,y, got {:?}", value))?;
+    let coordinate = |c: &str| {
+        c.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("{:?} is not a pixel coordinate", c))
+    };
+    Ok((coordinate(x)?, coordinate(y)?))
+}
+
+fn color_name(color: Color) -> &'static str {
+    match color {
+        Color::Red => "R",
+        Color::Green => "G",
+        Color::Blue => "B",
+    }
+}
+
+/// Prints where every sample of the merged pixel `x`, `y` came from, the same
+/// way `merge` picks them, to check a shift pattern against real files. `x`
+/// and `y` are those of the output, whose `border` `imgbuf` still has.
+pub fn print_pixel(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    imgbuf: &RgbImage16,
+    point: (u32, u32),
+    border: (u32, u32),
+    green: GreenMode,
+) {
+    let (width, height) = (imgbuf.width() - border.0, imgbuf.height() - border.1);
+    if point.0 >= width || point.1 >= height {
+        println!(
+            "pixel {}, {} is outside of the {}x{} merge",
+            point.0, point.1, width, height
+        );
+        return;
+    }
+    let (x, y) = (point.0 + border.0, point.1 + border.1);
+
+    let scale = pattern.scale;
+    let (sx, sy) = (x % scale, y % scale);
+    let group = pattern.grid()[(sy * scale + sx) as usize] as u32;
+    let (gx, gy) = (x / scale, y / scale);
+
+    println!(
+        "pixel {}, {} = {:?}, green {:?}",
+        point.0,
+        point.1,
+        imgbuf.get_pixel(x, y).0,
+        green
+    );
+    println!(
+        "  {} pattern, grid position {}, {} is merged from group {} at {}, {}",
+        pattern.name, sx, sy, group, gx, gy
+    );
+
+    for file in files.iter().filter(|file| file.group == group) {
+        let offset = file.inter_group_offsets();
+        let name = file.path.file_name().unwrap().to_string_lossy();
+
+        match (gx.checked_sub(offset.1), gy.checked_sub(offset.0)) {
+            (Some(fx), Some(fy)) if fx < file.width && fy < file.height => println!(
+                "  {}  {:>5}  from {} (sequence number {}) at {}, {}",
+                color_name(file.color(fx, fy)),
+                file.get_pixel(fx, fy),
+                name,
+                file.sequence_number,
+                fx,
+                fy
+            ),
+            _ => println!(
+                "  -      -  from {} (sequence number {}), shifted outside of the frame",
+                name, file.sequence_number
+            ),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use rayon::prelude::*;
+
+use crate::calibration::Calibration;
+use crate::{Color, RawImage, RgbImage16};
+
+/// Bilinear demosaic of a single frame, aligned on the grid of a 4 shots merge
+/// and scaled like it, so that the two can be compared pixel by pixel. The
+/// samples are taken as the merge takes them, see `RawImage::sample`.
+pub fn demosaic(
+    file: &RawImage,
+    calibration: Option<&Calibration>,
+    channel_samples: [u32; 3],
+) -> RgbImage16 {
+    let (ox, oy) = {
+        let offset = file.inter_group_offsets();
+        (offset.1 as i64, offset.0 as i64)
+    };
+
+    let mut imgbuf = RgbImage16::new(file.width, file.height);
+
+    imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+        let (fx, fy) = (x as i64 - ox, y as i64 - oy);
+        let mut sum = [0f32; 3];
+        let mut count = [0u32; 3];
+
+        for sy in fy - 1..=fy + 1 {
+            for sx in fx - 1..=fx + 1 {
+                if sx < 0 || sy < 0 || sx >= file.width as i64 || sy >= file.height as i64 {
+                    continue;
+                }
+
+                let c = match file.color(sx as u32, sy as u32) {
+                    Color::Red => 0,
+                    Color::Green => 1,
+                    Color::Blue => 2,
+                };
+
+                sum[c] += file.sample(sx as u32, sy as u32, calibration);
+                count[c] += 1;
+            }
+        }
+
+        for c in 0..3 {
+            let value = sum[c] * channel_samples[c] as f32 / count[c].max(1) as f32;
+            pixel.0[c] = value.min(u16::MAX as f32) as u16;
+        }
+    });
+
+    imgbuf
+}
@@ -1,25 +1,335 @@
+use std::path::Path;
 use std::process::Command;
+use std::sync::{Condvar, Mutex};
 
+use log::{debug, error, info};
+
+use crate::failure::fail;
+use crate::{cameras, ifd};
+
+/// exiftool is a whole perl interpreter, rayon would happily start one per
+/// core, so the number of processes alive at the same time is capped
+const MAX_RUNNING: usize = 4;
+
+static RUNNING: Mutex<usize> = Mutex::new(0);
+static SLOT_FREED: Condvar = Condvar::new();
+
+/// exiftool binary to run, can be overridden with the EXIFTOOL environment variable
+fn exiftool() -> String {
+    std::env::var("EXIFTOOL").unwrap_or_else(|_| "exiftool".to_string())
+}
+
+/// Makes sure exiftool can be run before any file gets loaded, exiting with
+/// installation hints and the way around it otherwise
+pub fn check_exiftool() {
+    let exiftool = exiftool();
+
+    match Command::new(&exiftool).arg("-ver").output() {
+        Ok(output) if output.status.success() => {
+            info!(
+                "using exiftool {}",
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+        }
+        _ => {
+            error!(
+                "could not run `{}`, it is needed to read the raw files metadata",
+                exiftool
+            );
+            error!("install it with your package manager, e.g. `apt install libimage-exiftool-perl` or `brew install exiftool`, or get it from https://exiftool.org");
+            error!("if it is installed somewhere outside of PATH, point the EXIFTOOL environment variable at it");
+            error!("without exiftool, merge the mosaics another raw decoder takes out of the raws, e.g. `dcraw -D -4 -j -t 0`: PGMs or gray TIFFs with their metadata in a `.txt` sidecar, or numbered with `--sequence-numbers 1,2,3,4`");
+            fail!(Metadata, "exiftool is missing");
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn exiftool_output(path: &Path) -> std::io::Result<std::process::Output> {
+    Command::new(exiftool()).arg(path).output()
+}
+
+/// Perl gets its arguments in the ANSI code page, which can't hold most file
+/// names, so the name goes through a UTF-8 argument file on stdin instead
+#[cfg(windows)]
+fn exiftool_output(path: &Path) -> std::io::Result<std::process::Output> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(exiftool())
+        .args([
+            "-charset",
+            "filename=utf8",
+            "-api",
+            "WindowsLongPath=1",
+            "-@",
+            "-",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(path.to_string_lossy().as_bytes())?;
+    child.wait_with_output()
+}
+
+/// exiftool reading the file from stdin, for frames that only exist in memory
+fn exiftool_output_bytes(bytes: &[u8]) -> std::io::Result<std::process::Output> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(exiftool())
+        .args(["-fast", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            // exiftool stops reading once it has the metadata
+            let _ = stdin.write_all(bytes);
+        });
+        child.wait_with_output()
+    })
+}
+
+/// Runs `exiftool` on `path` once a process slot is free
+fn run_exiftool(
+    path: &Path,
+    exiftool: impl FnOnce() -> std::io::Result<std::process::Output>,
+) -> std::process::Output {
+    let mut running = RUNNING.lock().unwrap();
+    while *running >= MAX_RUNNING {
+        running = SLOT_FREED.wait(running).unwrap();
+    }
+    *running += 1;
+    drop(running);
+
+    let output = exiftool();
+
+    *RUNNING.lock().unwrap() -= 1;
+    SLOT_FREED.notify_one();
+
+    let output = output.unwrap_or_else(|e| fail!(Metadata, "failed to run exiftool: {}", e));
+    if !output.status.success() {
+        fail!(
+            Metadata,
+            "exiftool failed on {} ({}): {}",
+            path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    output
+}
+
+#[derive(Debug, Clone)]
 pub struct ExifData {
     pub width: u32,
     pub height: u32,
-    pub sequence_number: u32,
+    pub sequence_number: u32, // 0 when the file isn't part of a sequence
     pub offset: u32,
+    pub black_level: u32,
+    pub white_level: u32,           // u16::MAX when unknown
+    pub field_of_view: Option<f32>, // horizontal, in degrees
+    pub strip_byte_count: Option<u32>,
+    pub rows_per_strip: Option<u32>,
+    pub wb_rggb_levels: Option<[u32; 4]>,
+    pub cfa_pattern: Option<String>, // as printed by exiftool, e.g. "[Red,Green][Green,Blue]"
+    pub exposure_time: Option<f64>,  // in seconds
+    pub iso: Option<u32>,
+    pub baseline_exposure: Option<f32>, // in EV, DNG only
+    pub date_time: Option<String>,
+    pub model: Option<String>,           // e.g. "ILCE-7RM4"
+    pub serial_number: Option<String>,   // of the body
+    pub color_matrix: Option<[f64; 9]>,  // XYZ to camera RGB for D65, DNG only
+    pub burst_id: Option<String>,        // shared by all the frames of a pixel shift sequence
+    pub settings: Vec<(String, String)>, // the SEQUENCE_SETTINGS the file has
+}
+
+/// Capture settings every frame of a sequence is expected to share, as
+/// exiftool names them
+pub const SEQUENCE_SETTINGS: [&str; 4] = [
+    "Shutter Type",
+    "Drive Mode",
+    "Release Mode",
+    "Pixel Shift Interval",
+];
+
+/// Characters that only ever group the digits of a number, never separate
+/// the values of a list: no-break, narrow no-break and thin spaces, and the
+/// apostrophe of Swiss locales
+const GROUP_MARKS: [char; 4] = ['\u{a0}', '\u{202f}', '\u{2009}', '\''];
+
+/// The leading integer of a value, its digits grouped in threes or not, and
+/// whatever unit follows it: "1 234 567", "1,234,567", "6000 pixels" or
+/// "2 of 4"
+fn integer(value: &str) -> Option<u32> {
+    let value = value.trim().replace(GROUP_MARKS, "");
+    let first = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let mut digits = value[..first].to_string();
+    let mut rest = &value[first..];
+
+    // the same separator before every group of exactly 3 digits
+    if (1..=3).contains(&first) {
+        if let Some(separator) = rest.chars().next().filter(|c| matches!(c, ' ' | ',' | '.')) {
+            while let Some(group) = rest
+                .strip_prefix(separator)
+                .and_then(|r| r.get(..3))
+                .filter(|group| group.bytes().all(|b| b.is_ascii_digit()))
+            {
+                let after = &rest[1 + group.len()..];
+                if after.starts_with(|c: char| c.is_ascii_digit()) {
+                    break;
+                }
+                digits += group;
+                rest = after;
+            }
+        }
+    }
+
+    digits.parse().ok()
 }
 
-pub fn read_exif(path: &str) -> ExifData {
-    let exifs = Command::new("exiftool")
-        .arg(path)
-        .output()
-        .expect("failed to execute process");
+/// A decimal number with its unit, if any, like "39.6 deg", in either
+/// locale: "2,5" is 2.5, the last of "1.234,5" or "1,234.5" is the decimal
+/// separator, and one that comes more than once groups digits
+fn decimal(value: &str) -> Option<f64> {
+    let value = value
+        .trim()
+        .trim_end_matches([',', ';'])
+        .replace(GROUP_MARKS, "");
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | ',' | 'e' | 'E')))
+        .unwrap_or(value.len());
+    let number = value[..end].trim_start_matches('+');
 
-    let exifs = String::from_utf8_lossy(&exifs.stdout);
+    let count = |c: char| number.matches(c).count();
+    let number = match (number.rfind('.'), number.rfind(',')) {
+        (Some(dot), Some(comma)) if comma > dot => number.replace('.', "").replace(',', "."),
+        (Some(_), Some(_)) => number.replace(',', ""),
+        // only grouping separators come more than once
+        (None, Some(_)) if count(',') > 1 => number.replace(',', ""),
+        (Some(_), None) if count('.') > 1 => number.replace('.', ""),
+        (None, Some(_)) => number.replace(',', "."),
+        _ => number.to_string(),
+    };
+    number.parse().ok()
+}
+
+/// Seconds of an exposure time, as exiftool prints it or close: "1/125",
+/// "2.5", "0,5 s" or "30\""
+pub fn seconds(value: &str) -> Option<f64> {
+    let value = value.trim().trim_end_matches(['s', '"', ' ']);
+    let seconds = match value.split_once('/') {
+        Some((num, den)) => decimal(num)? / decimal(den)?,
+        None => decimal(value)?,
+    };
+    (seconds.is_finite() && seconds > 0.0).then_some(seconds)
+}
+
+/// An exposure time the way cameras show it: "1/125" below a second
+pub fn exposure_label(seconds: f64) -> String {
+    let inverse = 1.0 / seconds;
+    if seconds < 1.0 && (inverse - inverse.round()).abs() < 0.01 {
+        format!("1/{}", inverse.round())
+    } else {
+        format!("{}", (seconds * 1000.0).round() / 1000.0)
+    }
+}
+
+/// exiftool leaves long arrays out, e.g. "(Binary data 480 bytes, use -b
+/// option to extract)", whose numbers aren't the values
+fn is_binary(value: &str) -> bool {
+    value.starts_with("(Binary data")
+}
 
-    let exifs = exifs.lines().map(|line| {
-        let mut parts = line.splitn(2, ':');
-        let key = parts.next().unwrap().trim().to_string();
-        let value = parts.next().unwrap().trim().to_string();
-        (key, value)
+/// All the numbers of a whitespace separated list, like "512 512 512 512",
+/// each of them parsed on its own; only the marks that never separate values
+/// group digits, "16\u{a0}383"
+fn numbers(value: &str) -> Vec<u32> {
+    if is_binary(value) {
+        return Vec::new();
+    }
+    value
+        .replace(GROUP_MARKS, "")
+        .split_whitespace()
+        .filter_map(integer)
+        .collect()
+}
+
+/// All the decimal numbers of a whitespace separated list
+fn decimals(value: &str) -> Vec<f64> {
+    if is_binary(value) {
+        return Vec::new();
+    }
+    value
+        .replace(GROUP_MARKS, "")
+        .split_whitespace()
+        .filter_map(decimal)
+        .collect()
+}
+
+/// The strips of a raw, a single value with its digits grouped by spaces,
+/// "1 234 567", or else the values of a list, "8 86408": strips are never
+/// smaller than a thousand bytes. Only for the strip tags, lists of other
+/// tags can well hold numbers of 3 digits.
+fn strip_values(value: &str) -> Vec<u32> {
+    let tokens = value.split_whitespace().collect::<Vec<_>>();
+    let digits = |token: &str| token.bytes().all(|b| b.is_ascii_digit());
+    let grouped = tokens.len() > 1
+        && tokens[0].len() <= 3
+        && tokens.iter().all(|token| digits(token))
+        && tokens[1..].iter().all(|token| token.len() == 3)
+        && tokens.iter().any(|token| *token != tokens[0]);
+
+    if grouped {
+        integer(value).into_iter().collect()
+    } else {
+        numbers(value)
+    }
+}
+
+pub fn read_exif(path: &Path) -> ExifData {
+    if let Err(e) = std::fs::metadata(path) {
+        fail!(Io, "can't read {}: {}", path.display(), e);
+    }
+    let output = run_exiftool(path, || exiftool_output(path));
+    parse(path, &output.stdout, ifd::find_raw(path))
+}
+
+/// Metadata of a raw held in memory, e.g. taken out of an archive; `path`
+/// only names it in messages
+pub fn read_exif_bytes(path: &Path, bytes: &[u8]) -> ExifData {
+    let output = run_exiftool(path, || exiftool_output_bytes(bytes));
+    parse(path, &output.stdout, ifd::find_raw_in(path, bytes))
+}
+
+/// `read_exif_bytes` for the start of a raw, up to its strips, whose CFA
+/// image was already found in the whole file
+#[cfg(feature = "remote")]
+pub fn read_exif_head(path: &Path, head: &[u8], raw: Option<ifd::RawIfd>) -> ExifData {
+    let output = run_exiftool(path, || exiftool_output_bytes(head));
+    parse(path, &output.stdout, raw)
+}
+
+/// The fields of exiftool output, `Key : Value` lines, levels the file lacks
+/// taken from the camera database; later lines win
+pub fn fields(exiftool_output: &[u8]) -> ExifData {
+    let exifs = String::from_utf8_lossy(exiftool_output);
+
+    let exifs = exifs.lines().filter_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        Some((key.trim().to_string(), value.trim().to_string()))
     });
 
     let mut exif_data = ExifData {
@@ -27,25 +337,200 @@ pub fn read_exif(path: &str) -> ExifData {
         height: 0,
         sequence_number: 0,
         offset: 0,
+        black_level: 0,
+        white_level: u16::MAX as u32,
+        field_of_view: None,
+        strip_byte_count: None,
+        rows_per_strip: None,
+        wb_rggb_levels: None,
+        cfa_pattern: None,
+        exposure_time: None,
+        iso: None,
+        baseline_exposure: None,
+        date_time: None,
+        model: None,
+        serial_number: None,
+        color_matrix: None,
+        burst_id: None,
+        settings: Vec::new(),
     };
 
+    let mut black_level = None;
+    let mut white_level = None;
+    let mut color_matrix_1 = None;
+    let mut image_number = None;
+
     for (key, value) in exifs {
+        if SEQUENCE_SETTINGS.contains(&key.as_str()) {
+            exif_data.settings.push((key.clone(), value.clone()));
+        }
+
         match key.as_str() {
-            "Strip Offsets" => exif_data.offset = value.parse::<u32>().unwrap(),
-            "Image Width" => exif_data.width = value.parse::<u32>().unwrap(),
-            "Image Height" => exif_data.height = value.parse::<u32>().unwrap(),
-            "Sequence Number" => exif_data.sequence_number = value.parse::<u32>().unwrap(),
+            "Strip Offsets" => {
+                exif_data.offset = strip_values(&value).first().copied().unwrap_or(0)
+            }
+            "Image Width" => exif_data.width = integer(&value).unwrap_or(0),
+            "Image Height" => exif_data.height = integer(&value).unwrap_or(0),
+            "Sequence Number" => exif_data.sequence_number = integer(&value).unwrap_or(0),
+            // one value per CFA color, they are always the same on the supported cameras
+            "Black Level" => black_level = numbers(&value).first().copied(),
+            "White Level" => white_level = numbers(&value).first().copied(),
+            // e.g. "39.6 deg" or "39.6 deg (3.12 m)"
+            "Field Of View" => exif_data.field_of_view = decimal(&value).map(|v| v as f32),
+            "Strip Byte Counts" => {
+                let counts = strip_values(&value);
+                exif_data.strip_byte_count = (!counts.is_empty()).then(|| counts.iter().sum())
+            }
+            "Rows Per Strip" => exif_data.rows_per_strip = integer(&value),
+            "WB RGGB Levels" => exif_data.wb_rggb_levels = numbers(&value).try_into().ok(),
+            "CFA Pattern" => exif_data.cfa_pattern = Some(value),
+            "Exposure Time" => exif_data.exposure_time = seconds(&value),
+            "ISO" => exif_data.iso = integer(&value),
+            "Baseline Exposure" => exif_data.baseline_exposure = decimal(&value).map(|v| v as f32),
+            "Date/Time Original" => exif_data.date_time = Some(value),
+            "Camera Model Name" => exif_data.model = Some(value),
+            "Serial Number" => exif_data.serial_number = Some(value),
+            // the second is usually the D65 one, the first for tungsten
+            "Color Matrix 2" => exif_data.color_matrix = decimals(&value).try_into().ok(),
+            "Color Matrix 1" => color_matrix_1 = decimals(&value).try_into().ok(),
+            "Pixel Shift Group ID" => exif_data.burst_id = Some(value),
+            // TIFF/EP, e.g. frames of `simulate`
+            "Image Number" => image_number = integer(&value),
             _ => (),
         }
     }
 
-    if exif_data.width == 0
-        || exif_data.height == 0
-        || exif_data.sequence_number == 0
-        || exif_data.offset == 0
-    {
-        panic!("Failed to read exif data");
+    exif_data.color_matrix = exif_data.color_matrix.or(color_matrix_1);
+    if exif_data.sequence_number == 0 {
+        exif_data.sequence_number = image_number.unwrap_or(0);
+    }
+
+    // levels the file doesn't have come from the camera database
+    let camera = cameras::lookup(exif_data.model.as_deref());
+    if let Some(black_level) = black_level.or(camera.and_then(|camera| camera.black_level)) {
+        exif_data.black_level = black_level;
+    }
+    if let Some(white_level) = white_level.or(camera.and_then(|camera| camera.white_level)) {
+        exif_data.white_level = white_level;
+    }
+
+    exif_data
+}
+
+fn parse(path: &Path, exiftool_output: &[u8], raw: Option<ifd::RawIfd>) -> ExifData {
+    let mut exif_data = fields(exiftool_output);
+
+    // exiftool reports the strips of whichever IFD it met first, which can
+    // be a preview
+    match raw {
+        Some(raw) => {
+            if raw.compression != 1 {
+                fail!(
+                    UnsupportedCamera,
+                    "{} is compressed (compression {}), only uncompressed raws can be merged",
+                    path.display(),
+                    raw.compression
+                );
+            }
+
+            if raw.strip_offset != exif_data.offset {
+                debug!(
+                    "{}: raw strips at {}, exiftool said {}",
+                    path.display(),
+                    raw.strip_offset,
+                    exif_data.offset
+                );
+            }
+
+            exif_data.width = raw.width;
+            exif_data.height = raw.height;
+            exif_data.offset = raw.strip_offset;
+            exif_data.strip_byte_count = Some(raw.strip_byte_count);
+            exif_data.rows_per_strip = raw.rows_per_strip;
+        }
+        None => debug!("{}: no CFA IFD found, going by exiftool", path.display()),
+    }
+
+    if exif_data.width == 0 || exif_data.height == 0 || exif_data.offset == 0 {
+        fail!(
+            Metadata,
+            "can't read the metadata of {}, exiftool gave no image size or raw data offset",
+            path.display()
+        );
     }
 
     exif_data
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_grouped_or_with_units() {
+        assert_eq!(integer("1 234 567"), Some(1234567));
+        assert_eq!(integer("1,234,567"), Some(1234567));
+        assert_eq!(integer("1.234.567"), Some(1234567));
+        assert_eq!(integer("16\u{a0}383"), Some(16383));
+        assert_eq!(integer("6000 pixels"), Some(6000));
+        assert_eq!(integer("2 of 4"), Some(2));
+        assert_eq!(integer("512 (0x200)"), Some(512));
+        assert_eq!(integer("pixels"), None);
+    }
+
+    #[test]
+    fn decimals_in_either_locale() {
+        assert_eq!(decimal("39.6 deg"), Some(39.6));
+        assert_eq!(decimal("2,5"), Some(2.5));
+        assert_eq!(decimal("+0,35"), Some(0.35));
+        assert_eq!(decimal("1.234,5"), Some(1234.5));
+        assert_eq!(decimal("1,234.5"), Some(1234.5));
+        assert_eq!(decimal("1,234,567"), Some(1234567.0));
+        assert_eq!(decimal("1.234.567"), Some(1234567.0));
+    }
+
+    #[test]
+    fn exposure_seconds() {
+        assert_eq!(seconds("1/125"), Some(1.0 / 125.0));
+        assert_eq!(seconds("0,5 s"), Some(0.5));
+        assert_eq!(seconds("2.5"), Some(2.5));
+        assert_eq!(seconds("30\""), Some(30.0));
+        assert_eq!(seconds("0"), None);
+        assert_eq!(exposure_label(1.0 / 125.0), "1/125");
+        assert_eq!(exposure_label(2.5), "2.5");
+    }
+
+    #[test]
+    fn strips_grouped_or_listed() {
+        assert_eq!(strip_values("1 234 567"), [1234567]);
+        assert_eq!(strip_values("86 400"), [86400]);
+        assert_eq!(strip_values("8 86408"), [8, 86408]);
+        assert_eq!(strip_values("512 512"), [512, 512]);
+    }
+
+    #[test]
+    fn lists_parse_every_value() {
+        assert_eq!(numbers("512 513 512 513"), [512, 513, 512, 513]);
+        assert_eq!(numbers("16\u{a0}383 16\u{a0}383"), [16383, 16383]);
+        assert_eq!(
+            numbers("(Binary data 480 bytes, use -b option to extract)"),
+            []
+        );
+        assert_eq!(decimals("0,5 1.25 -0,1"), [0.5, 1.25, -0.1]);
+    }
+
+    #[test]
+    fn levels_of_every_color() {
+        let exif =
+            fields(b"Black Level : 512 513 512 513\nWhite Level : 16\xc2\xa0383 16\xc2\xa0383\n");
+        assert_eq!(exif.black_level, 512);
+        assert_eq!(exif.white_level, 16383);
+
+        let exif = fields(
+            b"Exposure Time : 0,5 s\nStrip Offsets : 1 234 567\nImage Width : 6000 pixels\n",
+        );
+        assert_eq!(exif.exposure_time, Some(0.5));
+        assert_eq!(exif.offset, 1234567);
+        assert_eq!(exif.width, 6000);
+    }
+}
@@ -0,0 +1,114 @@
+use log::info;
+use rayon::prelude::*;
+
+use crate::exif::ExifData;
+use crate::planes::Planes;
+use crate::RawImage;
+
+/// Histogram of the black subtracted CFA samples that are part of the image
+fn raw_histogram(file: &RawImage) -> Vec<u64> {
+    let samples = (file.width * file.height) as usize;
+    let samples = &file.data_pixels[..samples.min(file.data_pixels.len())];
+
+    samples
+        .par_chunks(1 << 16)
+        .fold(
+            || vec![0u64; 1 << 16],
+            |mut hist, chunk| {
+                for &v in chunk {
+                    hist[v.saturating_sub(file.black_level as u16) as usize] += 1;
+                }
+                hist
+            },
+        )
+        .reduce(
+            || vec![0u64; 1 << 16],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                a
+            },
+        )
+}
+
+fn median(hist: &[u64]) -> f64 {
+    let total = hist.iter().sum::<u64>();
+    let mut seen = 0;
+
+    for (v, &count) in hist.iter().enumerate() {
+        seen += count;
+        if seen * 2 >= total {
+            return v.max(1) as f64;
+        }
+    }
+
+    1.0
+}
+
+/// Gain that brings a frame of the sequence to the brightness of `reference`.
+///
+/// It is the ratio of the median raw levels of the reference and of the
+/// frame, both single exposures of the same sensor, so it doesn't depend on
+/// how the merge combined the samples.
+pub fn gain(frame: &RawImage, reference: &RawImage) -> f64 {
+    let gain = median(&raw_histogram(reference)) / median(&raw_histogram(frame));
+
+    info!("exposure gain {:.3} ({:+.2} EV)", gain, gain.log2());
+
+    gain
+}
+
+/// Scales the merged image by `gain` above the black level, each channel
+/// being the sum of `channel_samples` samples of `frame`
+pub fn apply<const N: usize>(
+    planes: &mut Planes<N>,
+    frame: &RawImage,
+    gain: f64,
+    channel_samples: [u32; N],
+) {
+    let black = channel_samples.map(|n| (frame.black_level * n) as f32);
+    planes.map(|c, v| (v - black[c]).max(0.0) * gain as f32 + black[c]);
+}
+
+/// Gain of the sensor for a frame, relative: its ISO, less the Baseline
+/// Exposure a DNG asks to be brightened by on top
+fn sensitivity(exif: &ExifData) -> Option<f64> {
+    let iso = exif.iso? as f64;
+    Some(iso * (-exif.baseline_exposure.unwrap_or(0.0) as f64).exp2())
+}
+
+/// Factor bringing every frame to the gain of the first one of the sequence.
+///
+/// Some bodies switch conversion gain within a sequence, e.g. under auto ISO,
+/// and the frames shot at the other gain would show up as a brightness and
+/// noise pattern repeating with the shift pattern. Frames whose gain isn't
+/// known are left alone.
+pub fn frame_gains(exifs: &[ExifData]) -> Vec<f32> {
+    let reference = exifs
+        .iter()
+        .filter(|exif| exif.sequence_number > 0)
+        .min_by_key(|exif| exif.sequence_number)
+        .and_then(sensitivity);
+
+    exifs
+        .iter()
+        .map(|exif| {
+            let (Some(reference), Some(sensitivity)) = (reference, sensitivity(exif)) else {
+                return 1.0;
+            };
+            if sensitivity == reference {
+                return 1.0;
+            }
+
+            let gain = reference / sensitivity;
+            info!(
+                "frame {} was shot at ISO {}, scaling it by {:.3} ({:+.2} EV) to the gain \
+                 of the sequence",
+                exif.sequence_number,
+                exif.iso.unwrap(),
+                gain,
+                gain.log2()
+            );
+            gain as f32
+        })
+        .collect()
+}
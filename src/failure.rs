@@ -0,0 +1,204 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::inspect::json_string;
+
+/// What went wrong, for scripts to branch on: the exit code, and the `error`
+/// of the --json-errors object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// options that don't go together, or an existing output
+    Usage,
+    /// the sequence is incomplete, or isn't one
+    MissingFrames,
+    /// a file whose metadata can't be read or doesn't add up
+    Metadata,
+    /// a camera or raw format the merge can't handle
+    UnsupportedCamera,
+    /// a file that can't be read or written
+    Io,
+    /// a merge scoring above --max-artifact-score
+    Artifacts,
+    /// a run that won't fit in the memory available
+    Resources,
+    /// interrupted with Ctrl-C or killed with SIGTERM
+    Cancelled,
+    /// anything else, bugs included
+    Internal,
+}
+
+impl Kind {
+    pub fn code(self) -> i32 {
+        match self {
+            // what clap exits with on bad arguments
+            Kind::Usage => 2,
+            Kind::MissingFrames => 3,
+            Kind::Metadata => 4,
+            Kind::UnsupportedCamera => 5,
+            Kind::Io => 6,
+            Kind::Artifacts => 7,
+            Kind::Resources => 8,
+            // 128 + SIGINT, as shells report it
+            Kind::Cancelled => 130,
+            // what a plain panic exits with
+            Kind::Internal => 101,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Usage => "usage",
+            Kind::MissingFrames => "missing_frames",
+            Kind::Metadata => "metadata",
+            Kind::UnsupportedCamera => "unsupported_camera",
+            Kind::Io => "io",
+            Kind::Artifacts => "artifacts",
+            Kind::Resources => "resources",
+            Kind::Cancelled => "cancelled",
+            Kind::Internal => "internal",
+        }
+    }
+}
+
+/// Panic payload of `fail!`
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: Kind,
+    pub message: String,
+}
+
+/// `panic!` telling what kind of failure it is, e.g.
+/// `fail!(Io, "can't open {}", path.display())`
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)*) => {
+        std::panic::panic_any($crate::failure::Failure {
+            kind: $crate::failure::Kind::$kind,
+            message: format!($($arg)*),
+        })
+    };
+}
+pub(crate) use fail;
+
+static JSON: OnceLock<bool> = OnceLock::new();
+
+/// Outputs being written, removed if the run is cancelled
+static WRITING: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+fn describe(payload: &(dyn Any + Send)) -> (Kind, String) {
+    if let Some(failure) = payload.downcast_ref::<Failure>() {
+        (failure.kind, failure.message.clone())
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        (Kind::Internal, message.to_string())
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        (Kind::Internal, message.clone())
+    } else {
+        (Kind::Internal, "unknown error".to_string())
+    }
+}
+
+fn json(kind: Kind, message: &str) -> String {
+    format!(
+        "{{\"error\": {}, \"code\": {}, \"message\": {}}}",
+        json_string(kind.name()),
+        kind.code(),
+        json_string(message)
+    )
+}
+
+/// Reports failures as they happen: `fail!` ones as a one line error, other
+/// panics as usual, or every one of them as a JSON object on its own line
+/// with `json`
+pub fn init(json_errors: bool) {
+    JSON.set(json_errors).unwrap();
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let (kind, message) = describe(info.payload());
+        if json_errors {
+            eprintln!("{}", json(kind, &message));
+        } else if kind == Kind::Internal {
+            default_hook(info);
+        } else {
+            eprintln!("error: {}", message);
+        }
+    }));
+
+    #[cfg(unix)]
+    signals::init();
+}
+
+/// Exit code of the run that ended with `payload`
+pub fn exit_code(payload: &(dyn Any + Send)) -> i32 {
+    describe(payload).0.code()
+}
+
+/// Runs `write` on `path`, an output that must not be left behind if the run
+/// gets cancelled meanwhile
+pub fn writing<T>(path: &Path, write: impl FnOnce() -> T) -> T {
+    WRITING
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(path.to_path_buf());
+    let result = write();
+    if let Some(paths) = WRITING.lock().unwrap().as_mut() {
+        paths.remove(path);
+    }
+    result
+}
+
+/// Cleans up and exits once Ctrl-C or SIGTERM arrives. The handler itself only
+/// writes to a pipe, a thread waiting on it does the rest outside of the
+/// signal context.
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    use super::{json, Kind, JSON, WRITING};
+
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handler(_: libc::c_int) {
+        let fd = PIPE.load(Ordering::Relaxed);
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+
+    pub fn init() {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        PIPE.store(fds[1], Ordering::Relaxed);
+
+        std::thread::spawn(move || {
+            let mut byte = 0u8;
+            if unsafe { libc::read(fds[0], (&mut byte as *mut u8).cast(), 1) } != 1 {
+                return;
+            }
+
+            // the lock may be held by a thread that won't release it anymore
+            if let Ok(mut writing) = WRITING.try_lock() {
+                for path in writing.iter().flat_map(|paths| paths.iter()) {
+                    let _ = std::fs::remove_file(path);
+                }
+                *writing = None;
+            }
+
+            let message = "cancelled";
+            if JSON.get() == Some(&true) {
+                eprintln!("{}", json(Kind::Cancelled, message));
+            } else {
+                eprintln!("error: {}", message);
+            }
+            std::process::exit(Kind::Cancelled.code());
+        });
+
+        let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+    }
+}
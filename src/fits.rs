@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::output::Metadata;
+use crate::RgbImage16;
+
+/// FITS files are made of blocks of this many bytes, headers and data alike
+const BLOCK: usize = 2880;
+
+const CARD: usize = 80;
+
+pub fn is_fits(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| {
+            ["fits", "fit", "fts"]
+                .iter()
+                .any(|fits| ext.eq_ignore_ascii_case(fits))
+        })
+        .unwrap_or(false)
+}
+
+/// "2024:05:01 21:03:44" to the ISO 8601 DATE-OBS wants
+fn date_obs(value: &str) -> Option<String> {
+    let (date, time) = value.split_once(' ')?;
+    // drop any sub seconds or time zone exiftool appended
+    let time = time.get(..8)?;
+    Some(format!("{}T{}", date.replace(':', "-"), time))
+}
+
+/// An 80 columns header record, in the fixed format: strings start right
+/// after the `= `, numbers end at column 30
+fn card(key: &str, value: &str, comment: &str) -> String {
+    let value = if value.starts_with('\'') {
+        format!("{:<20}", value)
+    } else {
+        format!("{:>20}", value)
+    };
+    let card = if comment.is_empty() {
+        format!("{:<8}= {}", key, value)
+    } else {
+        format!("{:<8}= {} / {}", key, value, comment)
+    };
+    format!("{:<width$.width$}", card, width = CARD)
+}
+
+fn string(value: &str) -> String {
+    format!("'{:<8}'", value.replace('\'', "''"))
+}
+
+fn header(width: u32, height: u32, float: bool, metadata: &Metadata) -> Vec<String> {
+    let mut cards = vec![
+        card("SIMPLE", "T", "conforms to FITS standard"),
+        card("BITPIX", if float { "-32" } else { "16" }, ""),
+        card("NAXIS", "3", ""),
+        card("NAXIS1", &width.to_string(), ""),
+        card("NAXIS2", &height.to_string(), ""),
+        card("NAXIS3", "3", "R, G, B"),
+    ];
+
+    if !float {
+        // 16 bit FITS integers are signed, this makes them unsigned
+        cards.push(card("BZERO", "32768", ""));
+        cards.push(card("BSCALE", "1", ""));
+    }
+
+    cards.push(card("ROWORDER", &string("TOP-DOWN"), ""));
+    cards.push(card(
+        "CREATOR",
+        &string(concat!("psmsmerge ", env!("CARGO_PKG_VERSION"))),
+        "",
+    ));
+
+    if let Some(seconds) = metadata.exposure_time {
+        cards.push(card(
+            "EXPTIME",
+            &seconds.to_string(),
+            "seconds, of every frame",
+        ));
+    }
+
+    if let Some(iso) = metadata.iso {
+        cards.push(card("ISOSPEED", &iso.to_string(), ""));
+    }
+
+    if let Some(date) = metadata.date_time.as_deref().and_then(date_obs) {
+        cards.push(card("DATE-OBS", &string(&date), "camera clock"));
+    }
+
+    if let Some(black_level) = &metadata.black_level {
+        cards.push(card(
+            "CBLACK",
+            &black_level[1].to_string(),
+            "green black level",
+        ));
+    }
+
+    if let Some(white_level) = &metadata.white_level {
+        cards.push(card(
+            "CWHITE",
+            &white_level[1].to_string(),
+            "green white level",
+        ));
+    }
+
+    for (key, value) in &metadata.description {
+        cards.push(format!(
+            "{:<width$.width$}",
+            format!("COMMENT {}={}", key, value),
+            width = CARD
+        ));
+    }
+
+    cards.push(format!("{:<width$}", "END", width = CARD));
+    cards
+}
+
+fn pad(out: &mut impl Write, written: usize, fill: u8) -> std::io::Result<()> {
+    let padding = (BLOCK - written % BLOCK) % BLOCK;
+    out.write_all(&vec![fill; padding])
+}
+
+/// Writes the merge as a 3 plane FITS cube, 16 bit unsigned or 32 bit float
+pub fn save(
+    imgbuf: &RgbImage16,
+    path: &Path,
+    metadata: &Metadata,
+    float: bool,
+) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    let header = header(imgbuf.width(), imgbuf.height(), float, metadata).concat();
+    out.write_all(header.as_bytes())?;
+    pad(&mut out, header.len(), b' ')?;
+
+    let mut written = 0;
+    for channel in 0..3 {
+        for &v in imgbuf.as_raw().iter().skip(channel).step_by(3) {
+            if float {
+                out.write_all(&(v as f32).to_be_bytes())?;
+                written += 4;
+            } else {
+                out.write_all(&((v as i32 - 32768) as i16).to_be_bytes())?;
+                written += 2;
+            }
+        }
+    }
+    pad(&mut out, written, 0)?;
+
+    out.flush()
+}
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::failure::fail;
+
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const SUB_IFDS: u16 = 330;
+
+const PHOTOMETRIC_CFA: u32 = 32803;
+
+/// past this many IFDs the file is more likely broken than that rich
+const MAX_IFDS: usize = 64;
+
+/// The strips of the full resolution CFA image of a raw
+#[derive(Debug)]
+pub struct RawIfd {
+    pub width: u32,
+    pub height: u32,
+    pub strip_offset: u32,
+    /// of all the strips together
+    pub strip_byte_count: u32,
+    pub rows_per_strip: Option<u32>,
+    pub compression: u32,
+}
+
+/// The tags of one IFD that matter here, every value widened to u32
+#[derive(Debug)]
+struct Ifd {
+    tags: Vec<(u16, Vec<u32>)>,
+}
+
+impl Ifd {
+    fn get(&self, tag: u16) -> Option<&[u32]> {
+        self.tags
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, values)| values.as_slice())
+    }
+
+    fn first(&self, tag: u16) -> Option<u32> {
+        self.get(tag).and_then(|values| values.first().copied())
+    }
+}
+
+struct Reader<R> {
+    file: R,
+    little_endian: bool,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    fn bytes<const N: usize>(&mut self, offset: u64) -> Option<[u8; N]> {
+        let mut buf = [0; N];
+        self.file.seek(SeekFrom::Start(offset)).ok()?;
+        self.file.read_exact(&mut buf).ok()?;
+        Some(buf)
+    }
+
+    fn u16(&mut self, offset: u64) -> Option<u16> {
+        let b = self.bytes(offset)?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&mut self, offset: u64) -> Option<u32> {
+        let b = self.bytes(offset)?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    /// Values of a SHORT, LONG or IFD entry, the other types aren't needed
+    fn values(&mut self, entry: u64) -> Option<(u16, Vec<u32>)> {
+        let tag = self.u16(entry)?;
+        let kind = self.u16(entry + 2)?;
+        let count = self.u32(entry + 4)? as u64;
+
+        let size = match kind {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Some((tag, Vec::new())),
+        };
+
+        // values that don't fit in the entry are stored elsewhere
+        let start = if size * count > 4 {
+            self.u32(entry + 8)? as u64
+        } else {
+            entry + 8
+        };
+
+        let values = (0..count.min(1 << 20))
+            .map(|i| match size {
+                2 => self.u16(start + i * 2).map(u32::from),
+                _ => self.u32(start + i * 4),
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some((tag, values))
+    }
+
+    fn ifd(&mut self, offset: u64) -> Option<(Ifd, u32)> {
+        let count = self.u16(offset)? as u64;
+
+        let tags = (0..count)
+            .map(|i| self.values(offset + 2 + i * 12))
+            .collect::<Option<Vec<_>>>()?;
+
+        let next = self.u32(offset + 2 + count * 12)?;
+        Some((Ifd { tags }, next))
+    }
+}
+
+/// Every IFD of the file: the main chain and the SubIFDs hanging off it
+fn read_ifds(file: impl Read + Seek) -> Option<Vec<Ifd>> {
+    let mut reader = Reader {
+        file,
+        little_endian: true,
+    };
+
+    reader.little_endian = match &reader.bytes::<2>(0)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+
+    if reader.u16(2)? != 42 {
+        return None;
+    }
+
+    let mut pending = vec![reader.u32(4)?];
+    let mut seen = HashSet::new();
+    let mut ifds = Vec::new();
+
+    while let Some(offset) = pending.pop() {
+        if offset == 0 || !seen.insert(offset) || ifds.len() >= MAX_IFDS {
+            continue;
+        }
+
+        let (ifd, next) = reader.ifd(offset as u64)?;
+        pending.push(next);
+        pending.extend(ifd.get(SUB_IFDS).unwrap_or_default());
+        ifds.push(ifd);
+    }
+
+    Some(ifds)
+}
+
+/// Finds the full resolution CFA image among the IFDs of a TIFF based raw,
+/// previews and thumbnails often come first. None when the file can't be
+/// parsed, or has no such image.
+pub fn find_raw(path: &Path) -> Option<RawIfd> {
+    raw_ifd(path, read_ifds(File::open(path).ok()?)?)
+}
+
+/// Whether a TIFF based file holds a CFA image, tagged as one, rather than
+/// only ordinary images
+pub fn has_cfa(path: &Path) -> bool {
+    let Some(ifds) = File::open(path).ok().and_then(read_ifds) else {
+        return false;
+    };
+    ifds.iter()
+        .any(|ifd| ifd.first(PHOTOMETRIC_INTERPRETATION) == Some(PHOTOMETRIC_CFA))
+}
+
+/// `find_raw` for a raw held in memory, `path` only names it in messages
+pub fn find_raw_in(path: &Path, bytes: &[u8]) -> Option<RawIfd> {
+    find_raw_from(path, Cursor::new(bytes))
+}
+
+/// `find_raw` reading the IFDs from `file`, `path` only names it in messages
+pub fn find_raw_from(path: &Path, file: impl Read + Seek) -> Option<RawIfd> {
+    raw_ifd(path, read_ifds(file)?)
+}
+
+fn raw_ifd(path: &Path, ifds: Vec<Ifd>) -> Option<RawIfd> {
+    let is_cfa = |ifd: &&Ifd| ifd.first(PHOTOMETRIC_INTERPRETATION) == Some(PHOTOMETRIC_CFA);
+    // raws not tagged as CFA still mark previews as reduced resolution
+    let is_full_single_channel = |ifd: &&Ifd| {
+        ifd.first(NEW_SUBFILE_TYPE).unwrap_or(0) & 1 == 0
+            && ifd.first(SAMPLES_PER_PIXEL).unwrap_or(1) == 1
+    };
+
+    let with_strips = ifds.iter().filter(|ifd| ifd.get(STRIP_OFFSETS).is_some());
+    let area = |ifd: &&Ifd| {
+        ifd.first(IMAGE_WIDTH).unwrap_or(0) as u64 * ifd.first(IMAGE_LENGTH).unwrap_or(0) as u64
+    };
+
+    let raw = with_strips
+        .clone()
+        .filter(is_cfa)
+        .max_by_key(area)
+        .or_else(|| with_strips.filter(is_full_single_channel).max_by_key(area))?;
+
+    let offsets = raw.get(STRIP_OFFSETS)?;
+    let counts = raw.get(STRIP_BYTE_COUNTS).unwrap_or_default();
+
+    // the frame is memory mapped in one piece
+    let contiguous = offsets
+        .windows(2)
+        .zip(counts)
+        .all(|(pair, &count)| pair[0].checked_add(count) == Some(pair[1]));
+    if !contiguous {
+        fail!(
+            UnsupportedCamera,
+            "the raw strips of {} are not stored one after the other",
+            path.display()
+        );
+    }
+
+    Some(RawIfd {
+        width: raw.first(IMAGE_WIDTH)?,
+        height: raw.first(IMAGE_LENGTH)?,
+        strip_offset: *offsets.first()?,
+        strip_byte_count: counts.iter().sum(),
+        rows_per_strip: raw.first(ROWS_PER_STRIP),
+        compression: raw.first(COMPRESSION).unwrap_or(1),
+    })
+}
@@ -0,0 +1,247 @@
+use std::path::Path;
+
+use crate::cli::InspectArgs;
+use crate::exif::{read_exif, ExifData};
+use crate::patterns::{registry, ShiftPattern};
+use crate::{bayer_pattern, cameras, Color};
+
+/// The 2x2 CFA the merge assumes for the file, top left first
+fn assumed_cfa(exif: &ExifData) -> String {
+    let (px, py) = cameras::cfa_phase(exif);
+    [(0, 0), (1, 0), (0, 1), (1, 1)]
+        .iter()
+        .map(|&(x, y)| match bayer_pattern(x + px, y + py) {
+            Color::Red => 'R',
+            Color::Green => 'G',
+            Color::Blue => 'B',
+        })
+        .collect()
+}
+
+/// What a file means for the merge, on top of its metadata
+struct Placement {
+    group: u32,
+    id_in_group: u32,
+    offset: (u32, u32),
+}
+
+/// The files are taken as one sequence, as they would be by merge; a file
+/// that doesn't make a sequence with the others, e.g. one looked at on its
+/// own, is placed by its sequence number in the smallest pattern covering it
+fn pattern_for(exif: &ExifData, files: usize) -> Option<&'static ShiftPattern> {
+    let model = exif.model.as_deref();
+    registry().find(model, files).or_else(|| {
+        (exif.sequence_number > 0)
+            .then(|| registry().covering(model, exif.sequence_number))
+            .flatten()
+    })
+}
+
+fn placement(exif: &ExifData, pattern: Option<&ShiftPattern>) -> Option<Placement> {
+    if exif.sequence_number == 0 {
+        return None;
+    }
+
+    let shot = pattern?.shot(exif.sequence_number);
+    Some(Placement {
+        group: shot.group,
+        id_in_group: shot.id_in_group(),
+        offset: shot.offset,
+    })
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "null".to_string())
+}
+
+fn print_text(path: &Path, exif: &ExifData, pattern: Option<&ShiftPattern>) {
+    let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "unknown".to_string());
+
+    println!("{}", path.display());
+    println!("  size            {}x{}", exif.width, exif.height);
+    println!("  strip offset    {}", exif.offset);
+    println!(
+        "  strip bytes     {}",
+        or_unknown(exif.strip_byte_count.map(|v| v.to_string()))
+    );
+    println!(
+        "  rows per strip  {}",
+        or_unknown(exif.rows_per_strip.map(|v| v.to_string()))
+    );
+    println!("  sequence number {}", exif.sequence_number);
+    println!(
+        "  shift pattern   {}",
+        or_unknown(pattern.map(|p| p.name.clone()))
+    );
+
+    match placement(exif, pattern) {
+        Some(placement) => {
+            println!(
+                "  group / id      {} / {}",
+                placement.group, placement.id_in_group
+            );
+            println!(
+                "  offset (y, x)   {}, {}",
+                placement.offset.0, placement.offset.1
+            );
+        }
+        None => println!("  not part of a pixel shift sequence"),
+    }
+
+    println!(
+        "  camera          {}",
+        or_unknown(cameras::lookup(exif.model.as_deref()).map(|camera| camera.name.to_string()))
+    );
+    println!("  black level     {}", exif.black_level);
+    println!("  white level     {}", exif.white_level);
+    println!(
+        "  WB RGGB levels  {}",
+        or_unknown(exif.wb_rggb_levels.map(|wb| format!("{:?}", wb)))
+    );
+    println!(
+        "  CFA pattern     {} (merge assumes {})",
+        or_unknown(exif.cfa_pattern.clone()),
+        assumed_cfa(exif)
+    );
+}
+
+fn json(path: &Path, exif: &ExifData, pattern: Option<&ShiftPattern>) -> String {
+    let placement = placement(exif, pattern);
+
+    let fields = [
+        ("path", json_string(&path.to_string_lossy())),
+        ("width", exif.width.to_string()),
+        ("height", exif.height.to_string()),
+        ("strip_offset", exif.offset.to_string()),
+        ("strip_byte_count", json_option(exif.strip_byte_count)),
+        ("rows_per_strip", json_option(exif.rows_per_strip)),
+        ("sequence_number", exif.sequence_number.to_string()),
+        (
+            "shift_pattern",
+            json_option(pattern.map(|p| json_string(&p.name))),
+        ),
+        ("group", json_option(placement.as_ref().map(|p| p.group))),
+        (
+            "id_in_group",
+            json_option(placement.as_ref().map(|p| p.id_in_group)),
+        ),
+        (
+            "offset",
+            json_option(
+                placement
+                    .as_ref()
+                    .map(|p| format!("[{}, {}]", p.offset.0, p.offset.1)),
+            ),
+        ),
+        (
+            "camera",
+            json_option(
+                cameras::lookup(exif.model.as_deref()).map(|camera| json_string(camera.name)),
+            ),
+        ),
+        ("black_level", exif.black_level.to_string()),
+        ("white_level", exif.white_level.to_string()),
+        (
+            "wb_rggb_levels",
+            json_option(
+                exif.wb_rggb_levels
+                    .map(|wb| format!("[{}, {}, {}, {}]", wb[0], wb[1], wb[2], wb[3])),
+            ),
+        ),
+        (
+            "cfa_pattern",
+            json_option(exif.cfa_pattern.as_deref().map(json_string)),
+        ),
+        ("assumed_cfa", json_string(&assumed_cfa(exif))),
+    ];
+
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("    \"{}\": {}", key, value))
+        .collect::<Vec<_>>()
+        .join(",\n");
+
+    format!("  {{\n{}\n  }}", fields)
+}
+
+pub fn run(args: &InspectArgs) {
+    let exifs = args
+        .files
+        .iter()
+        .map(|path| (path, read_exif(path)))
+        .collect::<Vec<_>>();
+
+    let pattern = |exif: &ExifData| pattern_for(exif, exifs.len());
+
+    if args.json {
+        let objects = exifs
+            .iter()
+            .map(|(path, exif)| json(path, exif, pattern(exif)))
+            .collect::<Vec<_>>();
+        println!("[\n{}\n]", objects.join(",\n"));
+    } else {
+        for (path, exif) in &exifs {
+            print_text(path, exif, pattern(exif));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::fields;
+
+    /// A frame of `model` numbered `sequence_number`, inspected on its own
+    fn single(model: &str, sequence_number: u32) -> (Option<String>, Option<Placement>) {
+        let exif = fields(
+            format!(
+                "Camera Model Name : {}\nSequence Number : {}\n",
+                model, sequence_number
+            )
+            .as_bytes(),
+        );
+        let pattern = pattern_for(&exif, 1);
+        (
+            pattern.map(|pattern| pattern.name.clone()),
+            placement(&exif, pattern),
+        )
+    }
+
+    #[test]
+    fn single_frames_are_placed() {
+        for (sequence_number, name) in [(3, "4 shots"), (11, "16 shots")] {
+            let (pattern, placement) = single("ILCE-7RM4", sequence_number);
+            assert_eq!(pattern.as_deref(), Some(name));
+
+            let shot = registry()
+                .find(Some("ILCE-7RM4"), if name == "4 shots" { 4 } else { 16 })
+                .unwrap()
+                .shot(sequence_number);
+            let placement = placement.expect("a numbered frame is placed");
+            assert_eq!(
+                (placement.group, placement.id_in_group, placement.offset),
+                (shot.group, shot.id_in_group(), shot.offset)
+            );
+        }
+
+        // not numbered, or past every pattern
+        assert!(single("ILCE-7RM4", 0).1.is_none());
+        assert!(single("ILCE-7RM4", 17).1.is_none());
+    }
+}
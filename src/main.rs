@@ -1,20 +1,63 @@
-use clap::Parser;
-use exif::read_exif;
-use log::info;
+use archive::Archive;
+use calibration::Calibration;
+use cameras::Quirk;
+use clap::{Parser, ValueEnum};
+use cli::{Cli, Command, MergeArgs, MergeOptions, OutputOptions};
+use context::MergeContext;
+use exif::{read_exif, read_exif_bytes, ExifData};
+use failure::fail;
+use log::{info, warn};
 use memmap::{Mmap, MmapOptions};
+use patterns::{registry, ShiftPattern, Shot};
+use planes::Planes;
 use rayon::prelude::*;
+use reconstruct::Reconstruct;
+use sequence::Numbering;
+use std::path::{Path, PathBuf};
 
+mod archive;
+mod artifacts;
+mod bench;
+mod calibration;
+mod cameras;
+mod check;
+mod chroma;
+mod cli;
+mod context;
+mod cores;
+mod debug;
+mod demosaic;
 mod exif;
+mod exposure;
+mod failure;
+mod fits;
+mod ifd;
+mod inspect;
+mod memory;
+mod mosaic;
+mod motion;
+mod output;
+mod panorama;
+mod patterns;
+mod pdaf;
+mod planes;
+mod preview;
+mod provenance;
+mod quality;
+mod reconstruct;
+#[cfg(feature = "remote")]
+mod remote;
+mod sequence;
+mod simulate;
+mod stack;
+mod temperature;
+mod transform;
+mod watch;
+mod weights;
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    output_file: String,
-
-    #[arg(short, long, value_parser, num_args = 1.., value_delimiter = ' ')]
-    input_files: Vec<String>,
-}
+type RgbImage16 = image::ImageBuffer<image::Rgb<u16>, Vec<u16>>;
+/// R, G1, G2, B in the 4 channels: the green of the red rows, then of the blue ones
+type RggbImage16 = image::ImageBuffer<image::Rgba<u16>, Vec<u16>>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Color {
@@ -57,23 +100,78 @@ fn bayer_pattern(x: u32, y: u32) -> Color {
     }
 }
 
+/// (y, x) shift of the frames of a group, by their position in it
+fn id_offsets(id_in_group: u32) -> (u32, u32) {
+    match id_in_group {
+        0 => (1, 1),
+        1 => (0, 1),
+        2 => (0, 0),
+        3 => (1, 0),
+        _ => unreachable!(),
+    }
+}
+
 #[derive(Debug)]
 struct RawImage<'a> {
-    _path: String,
+    path: PathBuf,
     width: u32,
     height: u32,
-    _sequence_number: u32,
+    sequence_number: u32,
+    black_level: u32,
+    white_level: u32,
+    field_of_view: Option<f32>,
     group: u32, // which group of 4 images this image belongs to, every group has 4 images
     id_in_group: u32, // which image in the group this image is
-    _mmap: Mmap,
+    offset: (u32, u32), // (y, x) shift of the image within its group
+    cfa_phase: (u32, u32), // (x, y) of the red sample in the top left 2x2 cell
+    exif: ExifData, // everything else exiftool told about the file
+    pixels: Pixels,
     data_pixels: &'a [u16],
+    /// brings the samples to the gain of the sequence, see `exposure::frame_gains`
+    gain: f32,
+    /// the PDAF rows --pdaf-fix takes off, see `pdaf::detect`
+    banding: Option<pdaf::Banding>,
+}
+
+/// Where the samples of a frame live
+#[derive(Debug)]
+enum Pixels {
+    /// the raw file itself, from the start of its strips
+    Mapped(Mmap),
+    /// a copy of the strips, for frames that weren't read from a file
+    Owned(Vec<u16>),
+}
+
+impl Pixels {
+    /// The samples of an in memory raw, from `offset` on
+    fn copy(bytes: &[u8], offset: u32) -> Self {
+        let strips = bytes.get(offset as usize..).unwrap_or_default();
+        Pixels::Owned(
+            strips
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect(),
+        )
+    }
 }
 
 impl<'a> RawImage<'a> {
-    fn new(path: &str) -> Self {
-        let exif = read_exif(path);
+    /// Loads a raw that isn't part of the sequence, e.g. a reference exposure
+    fn new_single(path: &Path) -> Self {
+        let shot = Shot {
+            group: 0,
+            offset: id_offsets(0),
+        };
+        if mosaic::is_decoded(path) {
+            let (exif, pixels) = mosaic::read(path);
+            return Self::new(path, exif, shot, pixels);
+        }
+        Self::open(path, read_exif(path), shot)
+    }
 
-        let file = std::fs::File::open(path).unwrap();
+    fn open(path: &Path, exif: ExifData, shot: Shot) -> Self {
+        let file = std::fs::File::open(path)
+            .unwrap_or_else(|e| fail!(Io, "can't open {}: {}", path.display(), e));
         let data = unsafe {
             MmapOptions::new()
                 .offset(exif.offset as u64)
@@ -81,23 +179,67 @@ impl<'a> RawImage<'a> {
                 .unwrap()
         };
 
-        let data_slice_u16 =
-            unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u16, data.len() / 2) };
+        Self::new(path, exif, shot, Pixels::Mapped(data))
+    }
 
-        let gi = sequence_to_group_id(exif.sequence_number);
+    fn new(path: &Path, exif: ExifData, shot: Shot, pixels: Pixels) -> Self {
+        // both live on the heap, moving `pixels` into the struct doesn't move them
+        let data_slice_u16 = match &pixels {
+            Pixels::Mapped(data) => unsafe {
+                std::slice::from_raw_parts(data.as_ptr() as *const u16, data.len() / 2)
+            },
+            Pixels::Owned(data) => unsafe { std::slice::from_raw_parts(data.as_ptr(), data.len()) },
+        };
 
         Self {
-            _path: path.to_string(),
+            path: path.to_path_buf(),
             width: exif.width,
             height: exif.height,
-            _sequence_number: exif.sequence_number,
-            group: gi.0,
-            id_in_group: gi.1,
-            _mmap: data,
+            sequence_number: exif.sequence_number,
+            black_level: exif.black_level,
+            white_level: exif.white_level,
+            field_of_view: exif.field_of_view,
+            group: shot.group,
+            id_in_group: shot.id_in_group(),
+            offset: shot.offset,
+            cfa_phase: cameras::cfa_phase(&exif),
+            exif,
+            pixels,
             data_pixels: data_slice_u16,
+            gain: 1.0,
+            banding: None,
         }
     }
 
+    /// Warns when a frame taken for a dark frame isn't one: every 97th
+    /// sample is enough to tell
+    fn check_dark(&self) {
+        let samples = self.data_pixels.iter().step_by(97);
+        let mean = samples.clone().map(|&v| v as f64).sum::<f64>() / samples.count().max(1) as f64;
+        let range = self.white_level.saturating_sub(self.black_level) as f64;
+        if mean - self.black_level as f64 > range * 0.02 {
+            warn!(
+                "{} doesn't look like a dark frame, its samples average {:.0} for a black level of {}",
+                self.path.display(),
+                mean,
+                self.black_level
+            );
+        }
+    }
+
+    /// Bytes of the samples held in memory rather than mapped from the file
+    pub fn in_memory_bytes(&self) -> u64 {
+        match &self.pixels {
+            Pixels::Mapped(_) => 0,
+            Pixels::Owned(data) => data.len() as u64 * 2,
+        }
+    }
+
+    /// CFA color of the sample at `x`, `y`
+    fn color(&self, x: u32, y: u32) -> Color {
+        bayer_pattern(x + self.cfa_phase.0, y + self.cfa_phase.1)
+    }
+
     fn get_pixel(&self, x: u32, y: u32) -> u16 {
         let offset = (y * self.width + x) as usize;
 
@@ -108,118 +250,1026 @@ impl<'a> RawImage<'a> {
         self.data_pixels[offset]
     }
 
+    /// Value of the sample at `x`, `y` as the merge takes it: calibrated,
+    /// without PDAF banding and at the gain of the sequence
+    fn sample(&self, x: u32, y: u32, calibration: Option<&Calibration>) -> f32 {
+        let raw = self.get_pixel(x, y) as u32;
+        let val = match calibration {
+            Some(calibration) => calibration.apply(self, x, y, raw),
+            None => raw as f32,
+        };
+        let val = match &self.banding {
+            Some(banding) => val - banding.offset(y),
+            None => val,
+        };
+
+        if self.gain == 1.0 {
+            return val;
+        }
+        let black = self.black_level as f32;
+        (val - black) * self.gain + black
+    }
+
     fn inter_group_offsets(&self) -> (u32, u32) {
-        match self.id_in_group {
-            0 => (1, 1),
-            1 => (0, 1),
-            2 => (0, 0),
-            3 => (1, 0),
-            _ => unreachable!(),
+        self.offset
+    }
+}
+
+/// How the two green samples every 4 shots merge gets are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum GreenMode {
+    /// add them up, keeps all the precision but doubles the green level
+    #[default]
+    Sum,
+    /// average them, same noise benefit as sum at the level of red and blue
+    Average,
+    /// keep the one with the most local contrast, for the finest detail
+    Sharper,
+}
+
+impl GreenMode {
+    /// How many raw samples get summed into each output channel of the merge
+    fn channel_samples(self) -> [u32; 3] {
+        match self {
+            GreenMode::Sum => [1, 2, 1],
+            GreenMode::Average | GreenMode::Sharper => [1, 1, 1],
+        }
+    }
+
+    /// How many raw samples `merge_4` adds up into each channel, before the
+    /// merge gets normalized to `channel_samples`
+    fn accumulated_samples(self) -> [u32; 3] {
+        match self {
+            GreenMode::Sum | GreenMode::Average => [1, 2, 1],
+            GreenMode::Sharper => [1, 1, 1],
         }
     }
 }
 
-fn merge_4(files: &[RawImage], x: u32, y: u32) -> image::Rgb<u16> {
-    let mut px = image::Rgb([0u16, 0, 0]);
+/// How far a green sample stands out from the 4 greens diagonally around it
+/// in its own frame
+fn green_contrast(file: &RawImage, x: u32, y: u32) -> u32 {
+    let val = file.get_pixel(x, y) as i32;
 
-    for file in files {
+    let mut sum = 0;
+    let mut count = 0;
+
+    for (dx, dy) in [(-1, -1), (1, -1), (-1, 1), (1, 1)] {
+        let nx = x.checked_add_signed(dx).filter(|&nx| nx < file.width);
+        let ny = y.checked_add_signed(dy).filter(|&ny| ny < file.height);
+        if let (Some(nx), Some(ny)) = (nx, ny) {
+            sum += file.get_pixel(nx, ny) as i32;
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        return 0;
+    }
+
+    (val - sum / count).unsigned_abs()
+}
+
+/// Sums of the samples of every channel, see `GreenMode::accumulated_samples`
+fn merge_4(
+    files: &[RawImage],
+    x: u32,
+    y: u32,
+    green: GreenMode,
+    calibration: Option<&Calibration>,
+) -> [f32; 3] {
+    let mut px = [0f32; 3];
+    // value, frame and position of the two green samples of a group
+    let mut greens = [(0.0, 0, 0, 0); 2];
+    let mut found = 0;
+
+    for (i, file) in files.iter().enumerate() {
         let offset = file.inter_group_offsets();
+        // shifted away from the border, see `border`
+        let (Some(fx), Some(fy)) = (x.checked_sub(offset.1), y.checked_sub(offset.0)) else {
+            continue;
+        };
 
-        let val = file.get_pixel(x - offset.1, y - offset.0) as u32;
-        let color = bayer_pattern(x - offset.1, y - offset.0);
+        let val = file.sample(fx, fy, calibration);
 
-        match color {
-            Color::Red => px.0[0] += (val) as u16,
-            Color::Green => px.0[1] += (val) as u16,
-            Color::Blue => px.0[2] += (val) as u16,
+        match file.color(fx, fy) {
+            Color::Red => px[0] += val,
+            Color::Green if found < greens.len() => {
+                greens[found] = (val, i, fx, fy);
+                found += 1;
+            }
+            Color::Green => {}
+            Color::Blue => px[2] += val,
         }
     }
 
+    let greens = &greens[..found];
+    px[1] = match green {
+        GreenMode::Sum | GreenMode::Average => greens.iter().map(|g| g.0).sum::<f32>(),
+        GreenMode::Sharper => greens
+            .iter()
+            .max_by_key(|g| green_contrast(&files[g.1], g.2, g.3))
+            .map(|g| g.0)
+            .unwrap_or(0.0),
+    };
+
     px
 }
 
-fn main() {
-    env_logger::init_from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
-    );
+fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("arw"))
+        .unwrap_or(false)
+}
 
-    let now = std::time::Instant::now();
+/// Lanczos3 resize straight on the merged values, the raw data is linear so
+/// this low-pass filters in linear light and avoids the usual gamma darkening
+fn downscale<P: image::Pixel + 'static>(
+    imgbuf: &image::ImageBuffer<P, Vec<P::Subpixel>>,
+    factor: u32,
+) -> image::ImageBuffer<P, Vec<P::Subpixel>> {
+    image::imageops::resize(
+        imgbuf,
+        (imgbuf.width() / factor).max(1),
+        (imgbuf.height() / factor).max(1),
+        image::imageops::FilterType::Lanczos3,
+    )
+}
+
+/// One aligned row per frame, so that long batch logs stay readable
+fn log_table(files: &[RawImage]) {
+    let unknown = || "-".to_string();
+
+    let mut rows = vec![[
+        "path", "seq", "group", "id", "size", "exposure", "ISO", "time",
+    ]
+    .map(String::from)];
+
+    for file in files {
+        rows.push([
+            file.path.display().to_string(),
+            file.sequence_number.to_string(),
+            file.group.to_string(),
+            file.id_in_group.to_string(),
+            format!("{}x{}", file.width, file.height),
+            file.exif
+                .exposure_time
+                .map(exif::exposure_label)
+                .unwrap_or_else(unknown),
+            file.exif
+                .iso
+                .map(|iso| iso.to_string())
+                .unwrap_or_else(unknown),
+            file.exif.date_time.clone().unwrap_or_else(unknown),
+        ]);
+    }
+
+    let widths: [usize; 8] =
+        std::array::from_fn(|c| rows.iter().map(|row| row[c].len()).max().unwrap());
+
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        info!("{}", line.trim_end());
+    }
+}
 
-    let args = Args::parse();
+/// Loads a sequence, placing every frame with the shift pattern matching the
+/// camera and the number of frames, and setting aside the noise reduction
+/// frame of the camera if there is one
+fn load_files<'a>(
+    paths: &'a [PathBuf],
+    numbering: Numbering,
+) -> (
+    Vec<RawImage<'a>>,
+    &'static ShiftPattern,
+    Option<RawImage<'a>>,
+) {
+    #[cfg(feature = "remote")]
+    if paths.iter().any(|path| remote::is_remote(path)) {
+        return load_remote(paths, numbering);
+    }
 
     info!("loading files");
-    let mut files = args
-        .input_files
+    // decoded frames come with their samples, raws get mapped once placed
+    let (exifs, pixels): (Vec<_>, Vec<_>) = paths
+        .par_iter()
+        .map(|path| {
+            if mosaic::is_decoded(path) {
+                let (exif, pixels) = mosaic::read(path);
+                (exif, Some(pixels))
+            } else {
+                (read_exif(path), None)
+            }
+        })
+        .unzip();
+
+    place_frames(
+        paths,
+        exifs,
+        pixels,
+        numbering,
+        |path, exif, shot, pixels| match pixels {
+            Some(pixels) => RawImage::new(path, exif, shot, pixels),
+            None => RawImage::open(path, exif, shot),
+        },
+    )
+}
+
+/// Loads a sequence out of an archive, the `names` of its frames in it
+fn load_archive(
+    archive: &Archive,
+    names: &[PathBuf],
+    numbering: Numbering,
+) -> (
+    Vec<RawImage<'static>>,
+    &'static ShiftPattern,
+    Option<RawImage<'static>>,
+) {
+    info!(
+        "loading {} files from {}",
+        names.len(),
+        archive.path.display()
+    );
+    let paths = names
+        .iter()
+        .map(|name| archive.path.join(archive.resolve(name)))
+        .collect::<Vec<_>>();
+    if let Some(path) = paths
+        .iter()
+        .enumerate()
+        .find_map(|(i, path)| paths[..i].contains(path).then_some(path))
+    {
+        fail!(Usage, "{} is given more than once", path.display());
+    }
+
+    // every frame is decompressed once, its samples kept and the rest dropped
+    let (exifs, pixels): (Vec<_>, Vec<_>) = names
+        .par_iter()
+        .zip(&paths)
+        .map(|(name, path)| {
+            let bytes = archive.read(name);
+            let exif = read_exif_bytes(path, &bytes);
+            let pixels = Pixels::copy(&bytes, exif.offset);
+            (exif, pixels)
+        })
+        .unzip();
+
+    place_frames(&paths, exifs, pixels, numbering, RawImage::new)
+}
+
+/// Loads a sequence from URLs, downloading only the metadata and the strips
+/// of every frame; local files can be mixed in
+#[cfg(feature = "remote")]
+fn load_remote(
+    paths: &[PathBuf],
+    numbering: Numbering,
+) -> (
+    Vec<RawImage<'static>>,
+    &'static ShiftPattern,
+    Option<RawImage<'static>>,
+) {
+    info!("loading {} files, some remote", paths.len());
+    let (exifs, pixels): (Vec<_>, Vec<_>) = paths
+        .par_iter()
+        .map(|path| {
+            if remote::is_remote(path) {
+                remote::read(path)
+            } else {
+                let bytes = std::fs::read(path)
+                    .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e));
+                let exif = read_exif_bytes(path, &bytes);
+                let pixels = Pixels::copy(&bytes, exif.offset);
+                (exif, pixels)
+            }
+        })
+        .unzip();
+
+    place_frames(paths, exifs, pixels, numbering, RawImage::new)
+}
+
+/// Numbers, checks and places the frames of a sequence, `open` gets each
+/// one its samples along with its share of `pixels`
+fn place_frames<'a, T: Send>(
+    paths: &[PathBuf],
+    mut exifs: Vec<ExifData>,
+    mut pixels: Vec<T>,
+    numbering: Numbering,
+    open: impl Fn(&Path, ExifData, Shot, T) -> RawImage<'a> + Sync,
+) -> (
+    Vec<RawImage<'a>>,
+    &'static ShiftPattern,
+    Option<RawImage<'a>>,
+) {
+    // given numbers go first, 0 for a dark frame like the cameras do
+    if let Numbering::Given(numbers) = numbering {
+        sequence::number_given(paths, &mut exifs, numbers);
+    }
+
+    let model = exifs[0].model.as_deref().map(str::to_string);
+    let model = model.as_deref();
+    let camera = cameras::lookup(model);
+    if let Some(camera) = camera {
+        info!("camera: {}", camera.name);
+    }
+
+    // a frame too many may be the dark frame of long exposure noise
+    // reduction, shot after the sequence
+    let mut paths = paths.to_vec();
+    let mut dark_frame = None;
+    if registry().find(model, paths.len()).is_none()
+        && registry().find(model, paths.len() - 1).is_some()
+    {
+        if let Some(i) = sequence::find_dark_frame(&exifs) {
+            info!(
+                "{} is a noise reduction frame, leaving it out of the sequence",
+                paths[i].display()
+            );
+            dark_frame = Some((paths.remove(i), exifs.remove(i), pixels.remove(i)));
+        }
+    }
+    let paths = &paths[..];
+
+    let no_sequence_numbers = camera.is_some_and(|camera| camera.has(Quirk::NoSequenceNumbers));
+    match numbering {
+        Numbering::Given(_) => (),
+        Numbering::FileNames => sequence::number_by_file_name(paths, &mut exifs),
+        Numbering::Metadata if no_sequence_numbers => {
+            info!(
+                "the {} doesn't number its frames, going by the file names",
+                camera.unwrap().name
+            );
+            sequence::number_by_file_name(paths, &mut exifs);
+        }
+        Numbering::Metadata => sequence::check_order(paths, &exifs),
+    }
+    sequence::check_settings(paths, &exifs);
+
+    if let Some((path, _)) = paths
+        .iter()
+        .zip(&exifs)
+        .find(|(_, exif)| exif.sequence_number == 0)
+    {
+        match camera.filter(|camera| camera.has(Quirk::SingleFileSequence)) {
+            Some(camera) => fail!(
+                UnsupportedCamera,
+                "{} is not part of a pixel shift sequence: the {} stores the whole sequence \
+                 in a single raw, split it into one file per frame first",
+                path.display(),
+                camera.name
+            ),
+            None if mosaic::is_decoded(path) => fail!(
+                MissingFrames,
+                "{} has no sequence number, give it in {} or with --sequence-numbers",
+                path.display(),
+                mosaic::sidecar(path).display()
+            ),
+            None => fail!(
+                MissingFrames,
+                "{} is not part of a pixel shift sequence",
+                path.display()
+            ),
+        }
+    }
+
+    let pattern = registry().find(model, paths.len()).unwrap_or_else(|| {
+        fail!(
+            MissingFrames,
+            "no shift pattern takes {} frames from {}, some files may be missing \
+             (known frame counts: {:?}), or add one with --patterns",
+            paths.len(),
+            model.unwrap_or("an unknown camera"),
+            registry().frame_counts()
+        )
+    });
+    info!("using the {} shift pattern", pattern.name);
+
+    let gains = exposure::frame_gains(&exifs);
+
+    let mut files = paths
         .par_iter()
-        .map(|path| RawImage::new(path))
+        .zip(exifs)
+        .zip(pixels)
+        .zip(gains)
+        .map(|(((path, exif), pixels), gain)| {
+            let shot = pattern.shot(exif.sequence_number);
+            let mut file = open(path, exif, shot, pixels);
+            file.gain = gain;
+            file
+        })
         .collect::<Vec<_>>();
 
     files.sort_by_key(|file| (file.group, file.id_in_group));
 
-    for file in &files {
+    log_table(&files);
+
+    if let Some(pair) = files
+        .windows(2)
+        .find(|pair| (pair[0].group, pair[0].offset) == (pair[1].group, pair[1].offset))
+    {
+        fail!(
+            MissingFrames,
+            "{} and {} are the same shot, some files are missing",
+            pair[0].path.display(),
+            pair[1].path.display()
+        );
+    }
+
+    let dark_frame = dark_frame.map(|(path, exif, pixels)| {
+        let shot = Shot {
+            group: 0,
+            offset: id_offsets(0),
+        };
+        let dark_frame = open(&path, exif, shot, pixels);
+        dark_frame.check_dark();
+        dark_frame
+    });
+
+    (files, pattern, dark_frame)
+}
+
+/// Fills every output pixel with what `merge_4` gives for the frames of the
+/// group the pattern puts there
+fn accumulate<const N: usize>(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    merge_4: impl Fn(&[RawImage], u32, u32) -> [f32; N] + Sync,
+    context: &mut MergeContext,
+) -> Planes<N> {
+    let groups = files
+        .chunk_by(|a, b| a.group == b.group)
+        .collect::<Vec<&[RawImage]>>();
+    let grid = pattern.grid();
+    let scale = pattern.scale;
+
+    info!("creating buffer");
+    let mut planes = Planes::new(files[0].width * scale, files[0].height * scale, context);
+
+    info!("merging {}", files.len());
+
+    planes.fill(|x, y| {
+        // multi group modes work by doing the 4-way bayer merge once per group,
+        // each shifted by a fraction of a pixel in the scale x scale grid of
+        // every output pixel, so the resulting image is scale² larger.
+        // e.g. the 16 shots one:
+        // +----+----+
+        // | 0  | 1  |
+        // +----+----+
+        // | 2  | 3  |
+        // +----+----+
+        let group = groups[grid[((y % scale) * scale + x % scale) as usize]];
+        merge_4(group, x / scale, y / scale)
+    });
+
+    planes
+}
+
+/// Brings the sums of `merge_4` to `GreenMode::channel_samples`
+fn normalize(planes: &mut Planes, green: GreenMode) {
+    let samples = green.channel_samples();
+    let accumulated = green.accumulated_samples();
+    planes.map(|c, v| v * samples[c] as f32 / accumulated[c] as f32);
+}
+
+/// Accumulates the samples of every output pixel, then normalizes them to
+/// `GreenMode::channel_samples` in a separate pass
+fn merge(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    green: GreenMode,
+    calibration: Option<&Calibration>,
+    context: &mut MergeContext,
+) -> Planes {
+    let mut planes = accumulate(
+        files,
+        pattern,
+        |group, x, y| merge_4(group, x, y, green, calibration),
+        context,
+    );
+
+    normalize(&mut planes, green);
+    planes
+}
+
+/// Preview merge for --fast: the first group alone, at every other CFA quad,
+/// so a quarter of the sensor resolution whatever the pattern
+fn merge_fast(
+    files: &[RawImage],
+    green: GreenMode,
+    calibration: Option<&Calibration>,
+    context: &mut MergeContext,
+) -> Planes {
+    let group = files.chunk_by(|a, b| a.group == b.group).next().unwrap();
+
+    info!("fast merging {} of {} frames", group.len(), files.len());
+    let mut planes = Planes::new(files[0].width / 2, files[0].height / 2, context);
+
+    // the odd quad corner is never shifted out of the frames
+    planes.fill(|x, y| merge_4(group, 2 * x + 1, 2 * y + 1, green, calibration));
+
+    normalize(&mut planes, green);
+    planes
+}
+
+/// Like `merge_4`, but keeps the green of the red rows apart from the green
+/// of the blue rows, each pixel gets a single sample of both
+fn merge_4_rggb(files: &[RawImage], x: u32, y: u32, calibration: Option<&Calibration>) -> [f32; 4] {
+    let mut px = [0f32; 4];
+
+    for file in files {
+        let offset = file.inter_group_offsets();
+        let (Some(fx), Some(fy)) = (x.checked_sub(offset.1), y.checked_sub(offset.0)) else {
+            continue;
+        };
+
+        let val = file.sample(fx, fy, calibration);
+
+        match file.color(fx, fy) {
+            Color::Red => px[0] += val,
+            Color::Green if (fy + file.cfa_phase.1) % 2 == 0 => px[1] += val,
+            Color::Green => px[2] += val,
+            Color::Blue => px[3] += val,
+        }
+    }
+
+    px
+}
+
+/// The merge with the two greens as separate channels, for --rggb-out
+fn merge_rggb(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    calibration: Option<&Calibration>,
+    context: &mut MergeContext,
+) -> Planes<4> {
+    accumulate(
+        files,
+        pattern,
+        |group, x, y| merge_4_rggb(group, x, y, calibration),
+        context,
+    )
+}
+
+/// Columns on the left and rows at the top of a merge that some frames of
+/// their group are shifted away from, as (x, y): they miss those samples
+fn border(files: &[RawImage], pattern: &ShiftPattern) -> (u32, u32) {
+    let max =
+        |offset: fn(&RawImage) -> u32| files.iter().map(offset).max().unwrap_or(0) * pattern.scale;
+    (max(|file| file.offset.1), max(|file| file.offset.0))
+}
+
+/// A merged sequence, along with what it was made from
+struct Merge<'a> {
+    files: Vec<RawImage<'a>>,
+    pattern: &'static ShiftPattern,
+    imgbuf: RgbImage16,
+    /// --rggb-out, written instead of `imgbuf`
+    rggb: Option<RggbImage16>,
+    weights: Option<weights::WeightMap>,
+    /// brightness scaling applied on top of the black level, by --match-exposure
+    gain: f64,
+    /// R, G, B multipliers of --temp and --tint
+    white_balance: Option<[f64; 3]>,
+    /// how far the frames were from a still scene, see `artifacts`
+    artifacts: artifacts::ArtifactScore,
+}
+
+/// Loads and merges a full sequence, applying the requested post processing,
+/// in the buffers of `context` where it has some
+fn process<'a>(paths: &'a [PathBuf], args: &MergeOptions, context: &mut MergeContext) -> Merge<'a> {
+    let numbering = Numbering::new(args.trust_filename_order, &args.sequence_numbers);
+    let (files, pattern, dark_frame) = load_files(paths, numbering);
+    merge_loaded(files, pattern, dark_frame, args, context)
+}
+
+/// `process` for a sequence read out of an archive, all of it if `names` is
+/// empty
+fn process_archive(
+    path: &Path,
+    names: &[PathBuf],
+    args: &MergeOptions,
+    context: &mut MergeContext,
+) -> Merge<'static> {
+    let archive = Archive::open(path);
+    let names = if names.is_empty() {
+        archive.raws()
+    } else {
+        names.to_vec()
+    };
+    if names.is_empty() {
+        fail!(MissingFrames, "{} has no raw files", path.display());
+    }
+
+    let numbering = Numbering::new(args.trust_filename_order, &args.sequence_numbers);
+    let (files, pattern, dark_frame) = load_archive(&archive, &names, numbering);
+    drop(archive);
+    merge_loaded(files, pattern, dark_frame, args, context)
+}
+
+fn merge_loaded<'a>(
+    mut files: Vec<RawImage<'a>>,
+    pattern: &'static ShiftPattern,
+    dark_frame: Option<RawImage<'a>>,
+    args: &MergeOptions,
+    context: &mut MergeContext,
+) -> Merge<'a> {
+    memory::preflight(&files, pattern, args);
+
+    let calibration = args.calibration.as_deref().map(|path| {
+        let calibration = Calibration::load(path);
+        calibration.check(&files[0]);
+        calibration
+    });
+    let calibration = match dark_frame {
+        Some(dark_frame) if args.nr_dark_frame => {
+            info!("subtracting {}", dark_frame.path.display());
+            Some(Calibration::with_dark_frame(calibration, &dark_frame))
+        }
+        None if args.nr_dark_frame => {
+            warn!("no noise reduction frame in the sequence, nothing to subtract");
+            calibration
+        }
+        _ => calibration,
+    };
+
+    if args.pdaf_fix {
+        pdaf::fix(&mut files);
+    }
+
+    let mut planes = if args.fast {
+        merge_fast(&files, args.green, calibration.as_ref(), context)
+    } else if args.reconstruct == Reconstruct::Mlr {
+        reconstruct::mlr(&files, pattern, args.green, calibration.as_ref(), context)
+    } else {
+        merge(&files, pattern, args.green, calibration.as_ref(), context)
+    };
+    let mut rggb = args
+        .rggb_out
+        .then(|| merge_rggb(&files, pattern, calibration.as_ref(), context));
+
+    let artifacts = artifacts::check(&files, args.max_artifact_score);
+
+    // --fast never reads past the frames
+    let border = if args.fast || args.no_border_crop {
+        (0, 0)
+    } else {
+        border(&files, pattern)
+    };
+
+    // kept for the weight map
+    let mask = args.motion_mask.as_ref().map(|path| {
+        let mut mask =
+            motion::MotionMask::load(path, planes.width, planes.height, pattern.scale, border);
+        mask.feather(args.feather);
+        mask
+    });
+    if let Some(mask) = &mask {
+        motion::apply(
+            &mut planes,
+            mask,
+            &files[0],
+            pattern.scale,
+            calibration.as_ref(),
+            args.green.channel_samples(),
+        );
+    }
+
+    if args.debug_pixel.is_some() || args.quality_report {
+        // both look at the merge as it came out of the frames
+        let imgbuf = planes.encode(context);
+
+        if let Some(point) = args.debug_pixel {
+            debug::print_pixel(&files, pattern, &imgbuf, point, border, args.green);
+        }
+
+        if args.quality_report {
+            quality::report(
+                &files,
+                &imgbuf,
+                calibration.as_ref(),
+                args.green.channel_samples(),
+            );
+        }
+        context.recycle_samples(imgbuf.into_raw());
+    }
+    drop(calibration);
+
+    if let Some(strength) = args.chroma_smooth {
+        let channel_samples = args.green.channel_samples();
+        chroma::smooth(
+            &mut planes,
+            channel_samples.map(|n| (files[0].black_level * n) as f32),
+            channel_samples,
+            args.chroma_filter,
+            args.chroma_radius,
+            strength,
+            context,
+        );
+    }
+
+    let mut gain = 1.0;
+    if let Some(reference) = &args.match_exposure {
+        info!("matching exposure of {}", reference.display());
+        let reference = RawImage::new_single(reference);
+        gain = exposure::gain(&files[0], &reference);
+        exposure::apply(&mut planes, &files[0], gain, args.green.channel_samples());
+        if let Some(rggb) = &mut rggb {
+            exposure::apply(rggb, &files[0], gain, [1; 4]);
+        }
+    }
+
+    let white_balance = args.temp.map(|kelvin| {
+        let multipliers = temperature::multipliers(&files[0], kelvin, args.tint);
+        temperature::apply(
+            &mut planes,
+            &files[0],
+            multipliers,
+            args.green.channel_samples(),
+        );
+        if let Some(rggb) = &mut rggb {
+            let [r, g, b] = multipliers;
+            temperature::apply(rggb, &files[0], [r, g, g, b], [1; 4]);
+        }
+        multipliers
+    });
+
+    if border != (0, 0) {
         info!(
-            "{}: {}x{} ({}mpx), group {}, id {}",
-            file._path,
-            file.width,
-            file.height,
-            file.data_pixels.len() / 1000 / 1000,
-            file.group,
-            file.id_in_group
+            "cropping {} columns and {} rows missing the samples of shifted frames",
+            border.0, border.1
         );
+        planes.crop(border.0, border.1);
+        if let Some(rggb) = &mut rggb {
+            rggb.crop(border.0, border.1);
+        }
+    }
+
+    let mut imgbuf = planes.encode(context);
+    context.recycle_planes(planes);
+    let mut rggb = rggb.map(|planes| {
+        let rggb = planes.encode(context);
+        context.recycle_planes(planes);
+        rggb
+    });
+
+    let mut weights = args.weight_map.then(|| {
+        info!("computing weight map");
+        let weights = weights::contributions(&files, pattern, mask.as_ref());
+        let (width, height) = (weights.width() - border.0, weights.height() - border.1);
+        image::imageops::crop_imm(&weights, border.0, border.1, width, height).to_image()
+    });
+
+    if let Some(factor) = args.downscale.filter(|&f| f > 1) {
+        info!("downscaling by {}", factor);
+        imgbuf = downscale(&imgbuf, factor);
+        rggb = rggb.map(|rggb| downscale(&rggb, factor));
+        weights = weights.map(|weights| downscale(&weights, factor));
+    }
+
+    if args.rotate.is_some() || args.flip.is_some() {
+        info!("rotating / flipping");
+        imgbuf = transform::apply(imgbuf, args.rotate, args.flip);
+        rggb = rggb.map(|rggb| transform::apply(rggb, args.rotate, args.flip));
+        weights = weights.map(|weights| transform::apply(weights, args.rotate, args.flip));
+    }
+
+    Merge {
+        files,
+        pattern,
+        imgbuf,
+        rggb,
+        weights,
+        gain,
+        white_balance,
+        artifacts,
     }
+}
 
-    if files
+/// Black and white levels of every channel of the merged image, the frame
+/// levels times the number of samples summed into the channel
+fn output_levels(merge: &Merge, options: &MergeOptions) -> (Vec<u32>, Vec<u32>) {
+    let file = &merge.files[0];
+    let samples = match merge.rggb {
+        Some(_) => vec![1; 4],
+        None => options.green.channel_samples().to_vec(),
+    };
+    let [r, g, b] = merge.white_balance.unwrap_or([1.0; 3]);
+    let multipliers = match merge.rggb {
+        Some(_) => vec![r, g, g, b],
+        None => vec![r, g, b],
+    };
+
+    let black = samples
         .iter()
-        .enumerate()
-        .any(|(i, file)| file.id_in_group + file.group * 4 != i as u32)
-    {
-        panic!("some files are missing");
+        .map(|n| file.black_level * n)
+        .collect::<Vec<_>>();
+    let white = samples
+        .iter()
+        .zip(&black)
+        .zip(multipliers)
+        .map(|((&n, &black), multiplier)| {
+            let range = (file.white_level - file.black_level) as f64 * n as f64;
+            (black as f64 + range * merge.gain * multiplier)
+                .round()
+                .min(u16::MAX as f64) as u32
+        })
+        .collect();
+
+    (black, white)
+}
+
+/// Whether the merge should be written to `path`, checked before starting so
+/// that an existing output doesn't waste a whole merge
+fn should_write(path: &Path, options: &MergeOptions, args: &OutputOptions) -> bool {
+    if options.rggb_out && !output::is_tiff(path) {
+        fail!(
+            Usage,
+            "--rggb-out needs a .tif or .tiff output, not {}",
+            path.display()
+        );
     }
 
-    let imgbuf = match files.len() {
-        4 => {
-            info!("creating buffer");
-            let mut imgbuf = image::ImageBuffer::new(files[0].width, files[0].height);
+    if args.compress.is_some() && (args.pyramid || args.planar == Some(output::Planar::Single)) {
+        fail!(
+            Usage,
+            "--compress doesn't work with --pyramid or --planar single yet"
+        );
+    }
 
-            info!("merging 4");
-            imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
-                *pixel = merge_4(&files[..4], x, y);
-            });
+    if (args.pyramid || args.planar.is_some()) && !output::is_tiff(path) {
+        fail!(
+            Usage,
+            "--pyramid and --planar need a .tif or .tiff output, not {}",
+            path.display()
+        );
+    }
 
-            imgbuf
-        }
-        16 => {
-            let groups = files.chunks(4).collect::<Vec<&[RawImage]>>();
+    let Some(existing) = output::written_paths(path, options, args)
+        .into_iter()
+        .find(|path| path.exists())
+    else {
+        return true;
+    };
 
-            info!("creating buffer");
-            let mut imgbuf = image::ImageBuffer::new(files[0].width * 2, files[0].height * 2);
+    if args.overwrite {
+        return true;
+    }
+
+    if args.no_clobber {
+        info!("{} already exists, skipping", existing.display());
+        return false;
+    }
+
+    fail!(
+        Usage,
+        "{} already exists, pass --overwrite to replace it or --no-clobber to skip it",
+        existing.display()
+    );
+}
+
+/// Writes the merge of `files` to `path`, along with the panorama metadata
+fn save(merge: &Merge, path: &Path, options: &MergeOptions, args: &OutputOptions) {
+    let imgbuf = &merge.imgbuf;
+    let mut metadata = output::Metadata::default();
+
+    let (black_level, white_level) = output_levels(merge, options);
+    metadata.black_level = Some(black_level);
+    metadata.white_level = Some(white_level);
+    metadata.describe("shift_pattern", &merge.pattern.name);
 
-            info!("merging 16");
+    let exif = &merge.files[0].exif;
+    metadata.exposure_time = exif.exposure_time;
+    metadata.iso = exif.iso;
+    metadata.date_time = exif.date_time.clone();
 
-            imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
-                // 16 images mode works by doing the 4-way bayer merge 4 times but shifted by half a pixel in a 2x2 grid
-                // the 2x2 grid is for each pixel, so the resulting image is quadrupled in size
-                // +----+----+
-                // | 0  | 1  |
-                // +----+----+
-                // | 2  | 3  |
-                // +----+----+
-                match (x % 2, y % 2) {
-                    (0, 0) => *pixel = merge_4(groups[0], x / 2, y / 2),
-                    (1, 0) => *pixel = merge_4(groups[1], x / 2, y / 2),
-                    (0, 1) => *pixel = merge_4(groups[2], x / 2, y / 2),
-                    (1, 1) => *pixel = merge_4(groups[3], x / 2, y / 2),
-                    _ => unreachable!(),
-                }
-            });
+    if let Some(tile_name) = &args.tile_name {
+        metadata.page_name = Some(tile_name.clone());
+        metadata.describe("tile", tile_name);
+        metadata.describe("overlap_pct", args.overlap_pct);
 
-            imgbuf
+        if let Some((row, col)) = panorama::grid_position(tile_name) {
+            metadata.describe("row", row);
+            metadata.describe("col", col);
         }
-        _ => panic!("unsupported number of files"),
+    }
+
+    if let Some(rotation) = options.rotate {
+        metadata.describe("rotate", rotation.degrees());
+    }
+
+    if let Some(flip) = options.flip {
+        metadata.describe("flip", flip.to_possible_value().unwrap().get_name());
+    }
+
+    if let Some(kelvin) = options.temp {
+        metadata.describe("temperature", kelvin);
+        metadata.describe("tint", options.tint);
+    }
+    metadata.describe("artifact_score", format!("{:.1}", merge.artifacts.total()));
+
+    if !args.no_provenance && output::is_tiff(path) {
+        metadata.xmp = Some(provenance::xmp(merge, options));
+    }
+
+    match &merge.rggb {
+        Some(rggb) => output::save_rggb(rggb, path, &metadata, args),
+        None => output::write(imgbuf, path, &metadata, args),
+    }
+
+    if let Some(weights) = &merge.weights {
+        output::save_weights(weights, &weights::path_for(path), args);
+    }
+
+    if let Some(project) = &args.project {
+        panorama::append_to_project(
+            Path::new(project),
+            path,
+            imgbuf.dimensions(),
+            merge.files[0].field_of_view,
+            args.tile_name.as_deref(),
+            args.overlap_pct,
+        );
+    }
+}
+
+fn run_merge(args: &MergeArgs) {
+    if !should_write(Path::new(&args.output_file), &args.merge, &args.output) {
+        return;
+    }
+
+    let now = std::time::Instant::now();
+
+    let merge = match &args.archive {
+        Some(archive) => process_archive(
+            archive,
+            &args.input_files,
+            &args.merge,
+            &mut MergeContext::default(),
+        ),
+        None => process(&args.input_files, &args.merge, &mut MergeContext::default()),
     };
 
     info!("saving");
-    imgbuf.save(&args.output_file).unwrap();
+    save(
+        &merge,
+        Path::new(&args.output_file),
+        &args.merge,
+        &args.output,
+    );
 
     info!("done in {:?}", now.elapsed());
+
+    if let Some(max_size) = args.show {
+        preview::show(&merge.imgbuf, max_size, merge.white_balance.is_some());
+    }
+}
+
+fn main() {
+    let cli = Cli::parse_from(cli::args());
+
+    let level = if cli.quiet { "warn" } else { "info" };
+    env_logger::init_from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, level),
+    );
+
+    failure::init(cli.json_errors);
+
+    let result = std::panic::catch_unwind(|| {
+        let merge = matches!(
+            cli.command,
+            Command::Merge(_) | Command::Bench(_) | Command::Watch(_) | Command::Stack(_)
+        );
+        cores::init(&cli.cores, merge);
+        // decoded frames carry their metadata, simulated ones are made here
+        let needs_exiftool = match &cli.command {
+            Command::Simulate(_) => false,
+            Command::Merge(args) => {
+                args.archive.is_some() || !args.input_files.iter().all(|p| mosaic::is_decoded(p))
+            }
+            Command::Check(args) => !args.input_files.iter().all(|p| mosaic::is_decoded(p)),
+            _ => true,
+        };
+        if needs_exiftool {
+            exif::check_exiftool();
+        }
+        patterns::init(cli.patterns.as_deref());
+
+        match &cli.command {
+            Command::Merge(args) => run_merge(args),
+            Command::Inspect(args) => inspect::run(args),
+            Command::Check(args) => check::run(args),
+            Command::Bench(args) => bench::run(args),
+            Command::Watch(args) => watch::run(args),
+            Command::Stack(args) => stack::run(args),
+            Command::Simulate(args) => simulate::run(args),
+        }
+    });
+
+    if let Err(payload) = result {
+        std::process::exit(failure::exit_code(&*payload));
+    }
 }
@@ -0,0 +1,120 @@
+use log::{debug, info};
+
+use crate::failure::fail;
+use crate::patterns::ShiftPattern;
+use crate::transform::Rotation;
+use crate::{MergeOptions, RawImage};
+
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// bytes per pixel of the merged image, 3 channels of u16
+const PIXEL_BYTES: u64 = 6;
+
+/// bytes per pixel of the f32 planes the merge is accumulated in
+const PLANES_BYTES: u64 = 12;
+
+/// MemAvailable from /proc/meminfo, None where that isn't a thing
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Rough peak of the memory allocated by a run, in bytes.
+///
+/// The raws themselves are memory mapped, their pages can always be dropped
+/// by the kernel so they don't count, unless they were read from an archive.
+pub fn estimate(files: &[RawImage], pattern: &ShiftPattern, args: &MergeOptions) -> u64 {
+    let sensor = files[0].width as u64 * files[0].height as u64;
+    let scale = (pattern.scale * pattern.scale) as u64;
+    // --fast merges a quarter of the sensor
+    let pixels = if args.fast {
+        sensor / 4
+    } else {
+        sensor * scale
+    };
+    let merged = pixels * PIXEL_BYTES;
+
+    // the planes are encoded into the merged image, both are there at once
+    let mut total = merged + pixels * PLANES_BYTES;
+
+    total += files.iter().map(RawImage::in_memory_bytes).sum::<u64>();
+
+    if args.rggb_out {
+        // 4 more planes, then the 4 channels u16 image they become
+        total += sensor * scale * (16 + 8);
+    }
+
+    if args.motion_mask.is_some() {
+        // single frame demosaic and one f32 per mask pixel
+        total += sensor * PIXEL_BYTES + sensor * scale * 4;
+    }
+
+    if args.calibration.is_some() || args.nr_dark_frame {
+        // gain and offset, one f32 each per sample
+        total += sensor * 8;
+    }
+
+    if args.quality_report {
+        // single frame demosaic and the merge brought to its size
+        total += 2 * sensor * PIXEL_BYTES;
+    }
+
+    if args.chroma_smooth.is_some() {
+        // the two chroma planes and a filtered one, and the box filter's pass
+        total += pixels * 16;
+    }
+
+    if args.weight_map {
+        // one f32 per merged pixel
+        total += sensor * scale * 4;
+    }
+
+    let mut result = merged;
+
+    if let Some(factor) = args.downscale.filter(|&f| f > 1) {
+        let factor = factor as u64;
+        // the resize goes through a f32 buffer downscaled on one axis only
+        total += merged * 2 / factor + merged / (factor * factor);
+        result /= factor * factor;
+    }
+
+    if matches!(args.rotate, Some(Rotation::Cw90 | Rotation::Cw270)) {
+        total += result;
+    }
+
+    total
+}
+
+/// Refuses to go on when the run is not going to fit in memory, rather than
+/// being killed halfway through by the OOM killer
+pub fn preflight(files: &[RawImage], pattern: &ShiftPattern, args: &MergeOptions) {
+    let needed = estimate(files, pattern, args);
+
+    let Some(available) = available_memory() else {
+        debug!("can't tell the available memory, skipping the memory check");
+        return;
+    };
+
+    info!(
+        "estimated memory use {:.1}GB, {:.1}GB available",
+        needed as f64 / GB,
+        available as f64 / GB
+    );
+
+    if needed > available && !args.no_memory_check {
+        fail!(
+            Resources,
+            "the merge needs about {:.1}GB of memory but only {:.1}GB are available, \
+             close other applications, drop --quality-report or --downscale, \
+             or pass --no-memory-check to try anyway",
+            needed as f64 / GB,
+            available as f64 / GB
+        );
+    }
+}
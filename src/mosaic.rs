@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+use crate::exif::{self, ExifData};
+use crate::failure::fail;
+use crate::ifd;
+use crate::Pixels;
+
+// the TIFF/EP and DNG tags `simulate` writes too
+const CFA_REPEAT_PATTERN_DIM: Tag = Tag::Unknown(33421);
+const CFA_PATTERN: Tag = Tag::Unknown(33422);
+const IMAGE_NUMBER: Tag = Tag::Unknown(37393);
+const BLACK_LEVEL: Tag = Tag::Unknown(50714);
+const WHITE_LEVEL: Tag = Tag::Unknown(50717);
+
+/// Whether `path` is a mosaic some other raw decoder already took out of its
+/// raw, e.g. with `dcraw -D -4`: a PGM, or a TIFF of plain gray. TIFFs tagged
+/// as CFA, DNGs or `simulate` frames, are read like raws.
+pub fn is_decoded(path: &Path) -> bool {
+    let is = |name: &str| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(name))
+    };
+    is("pgm") || ((is("tif") || is("tiff")) && !ifd::has_cfa(path))
+}
+
+/// `DSC00001.txt` next to `DSC00001.pgm`, what `exiftool -w txt` writes for
+/// the raw the frame was decoded from
+pub fn sidecar(path: &Path) -> PathBuf {
+    path.with_extension("txt")
+}
+
+/// A decoded mosaic: its size, samples, and what the file itself says about
+/// them, as exiftool would print it
+struct Mosaic {
+    width: u32,
+    height: u32,
+    samples: Vec<u16>,
+    fields: Vec<String>,
+}
+
+/// `P5` binary PGM, 16 bit samples big endian as the format has them
+fn read_pgm(path: &Path, bytes: &[u8]) -> Mosaic {
+    let invalid =
+        |what: &str| -> ! { fail!(Metadata, "{} is not a PGM: {}", path.display(), what) };
+
+    // magic, width, height and maxval, separated by whitespace and comments
+    let mut header = Vec::new();
+    let mut at = 0;
+    while header.len() < 4 {
+        match bytes.get(at) {
+            None => invalid("the header is cut short"),
+            Some(b'#') => {
+                while bytes.get(at).is_some_and(|&b| b != b'\n') {
+                    at += 1;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() => at += 1,
+            Some(_) => {
+                let start = at;
+                while bytes.get(at).is_some_and(|b| !b.is_ascii_whitespace()) {
+                    at += 1;
+                }
+                header.push(String::from_utf8_lossy(&bytes[start..at]).into_owned());
+            }
+        }
+    }
+    // a single whitespace byte before the samples
+    at += 1;
+
+    if header[0] != "P5" {
+        invalid("only binary graymaps, P5, hold a mosaic");
+    }
+    let number = |v: &str| {
+        v.parse::<u32>()
+            .ok()
+            .filter(|&v| v > 0)
+            .unwrap_or_else(|| invalid("bad header"))
+    };
+    let (width, height, maxval) = (number(&header[1]), number(&header[2]), number(&header[3]));
+    if maxval > u16::MAX as u32 {
+        invalid("maxval above 65535");
+    }
+
+    let count = width as usize * height as usize;
+    let data = bytes.get(at..).unwrap_or_default();
+    let samples = if maxval < 256 {
+        data.iter()
+            .take(count)
+            .map(|&b| b as u16)
+            .collect::<Vec<_>>()
+    } else {
+        data.chunks_exact(2)
+            .take(count)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>()
+    };
+    if samples.len() < count {
+        invalid("the samples are cut short");
+    }
+
+    Mosaic {
+        width,
+        height,
+        samples,
+        fields: vec![format!("White Level : {}", maxval)],
+    }
+}
+
+/// exiftool's name of the colors of a CFAPattern tag
+fn cfa_colors(pattern: &[u8]) -> Option<String> {
+    let names = pattern
+        .iter()
+        .map(|color| match color {
+            0 => Some("Red"),
+            1 => Some("Green"),
+            2 => Some("Blue"),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match names[..] {
+        [a, b, c, d] => Some(format!("[{},{}][{},{}]", a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// Single channel TIFF, with the levels, CFA pattern and image number of its
+/// tags if it has them
+fn read_tiff(path: &Path) -> tiff::TiffResult<Mosaic> {
+    let file = std::fs::File::open(path)
+        .unwrap_or_else(|e| fail!(Io, "can't open {}: {}", path.display(), e));
+    let mut decoder = Decoder::new(std::io::BufReader::new(file))?;
+    let (width, height) = decoder.dimensions()?;
+    let bits = match decoder.colortype()? {
+        ColorType::Gray(bits @ (8 | 16)) => bits,
+        colortype => fail!(
+            UnsupportedCamera,
+            "{} is {:?}, a mosaic is a single 8 or 16 bit channel",
+            path.display(),
+            colortype
+        ),
+    };
+
+    let mut fields = vec![format!("White Level : {}", (1u32 << bits) - 1)];
+    let first = |values: tiff::TiffResult<Vec<u32>>| values.ok()?.first().copied();
+    if let Some(black) = first(decoder.get_tag_u32_vec(BLACK_LEVEL)) {
+        fields.push(format!("Black Level : {}", black));
+    }
+    if let Some(white) = first(decoder.get_tag_u32_vec(WHITE_LEVEL)) {
+        fields.push(format!("White Level : {}", white));
+    }
+    if let Ok(number) = decoder.get_tag_u32(IMAGE_NUMBER) {
+        fields.push(format!("Image Number : {}", number));
+    }
+    if let Ok(model) = decoder.get_tag_ascii_string(Tag::Model) {
+        fields.push(format!(
+            "Camera Model Name : {}",
+            model.trim_end_matches('\0')
+        ));
+    }
+    let repeat = decoder.get_tag_u16_vec(CFA_REPEAT_PATTERN_DIM).ok();
+    if repeat.as_deref().is_none_or(|dim| dim == [2, 2]) {
+        let pattern = decoder.get_tag_u8_vec(CFA_PATTERN).ok();
+        if let Some(colors) = pattern.as_deref().and_then(cfa_colors) {
+            fields.push(format!("CFA Pattern : {}", colors));
+        }
+    }
+
+    let samples = match decoder.read_image()? {
+        DecodingResult::U16(samples) => samples,
+        DecodingResult::U8(samples) => samples.into_iter().map(u16::from).collect(),
+        _ => unreachable!("8 or 16 bit gray"),
+    };
+
+    Ok(Mosaic {
+        width,
+        height,
+        samples,
+        fields,
+    })
+}
+
+/// Reads a decoded mosaic and its metadata: what the file has, then its
+/// sidecar, exiftool output or `Key : Value` lines of the same names, e.g.
+/// `Sequence Number : 3` or `CFA Pattern : [Red,Green][Green,Blue]`
+pub fn read(path: &Path) -> (ExifData, Pixels) {
+    let is_pgm = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pgm"));
+    let mosaic = if is_pgm {
+        let bytes = std::fs::read(path)
+            .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e));
+        read_pgm(path, &bytes)
+    } else {
+        read_tiff(path)
+            .unwrap_or_else(|e| fail!(Metadata, "can't decode {}: {}", path.display(), e))
+    };
+
+    let mut fields = mosaic.fields.join("\n");
+    let sidecar = sidecar(path);
+    match std::fs::read_to_string(&sidecar) {
+        Ok(text) => {
+            debug!("{}: metadata from {}", path.display(), sidecar.display());
+            fields = fields + "\n" + &text;
+        }
+        Err(_) => debug!("{}: no {}", path.display(), sidecar.display()),
+    }
+
+    let mut exif = exif::fields(fields.as_bytes());
+    // the decoded size, whatever the raw it came from said
+    exif.width = mosaic.width;
+    exif.height = mosaic.height;
+    exif.offset = 0;
+    exif.strip_byte_count = None;
+    exif.rows_per_strip = None;
+
+    (exif, Pixels::Owned(mosaic.samples))
+}
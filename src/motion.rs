@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use log::info;
+use rayon::prelude::*;
+
+use crate::calibration::Calibration;
+use crate::demosaic::demosaic;
+use crate::failure::fail;
+use crate::planes::Planes;
+use crate::stack::box_blur;
+use crate::RawImage;
+
+/// Regions painted by the user where the frames can't be merged, e.g. moving
+/// water or foliage: the white parts of a grayscale image, the size of the
+/// merge, with or without its border, or of the sensor.
+pub struct MotionMask {
+    width: u32,
+    /// 1 where the single frame is used, 0 where the merge is kept, for
+    /// every merged pixel
+    weights: Vec<f32>,
+}
+
+impl MotionMask {
+    /// Loads the mask for a `width` x `height` merge of frames `scale` times
+    /// smaller, whose `border` gets cropped
+    pub fn load(path: &Path, width: u32, height: u32, scale: u32, border: (u32, u32)) -> Self {
+        let mask = image::open(path)
+            .unwrap_or_else(|e| fail!(Io, "can't read the motion mask {}: {}", path.display(), e))
+            .into_luma8();
+
+        let cropped = (width - border.0, height - border.1);
+        let (scale, origin) = if mask.dimensions() == (width, height) {
+            (1, (0, 0))
+        } else if mask.dimensions() == cropped {
+            (1, border)
+        } else if mask.dimensions() == (width / scale, height / scale) {
+            (scale, (0, 0))
+        } else {
+            fail!(
+                Usage,
+                "the motion mask {} is {}x{}, it must be the size of the merge, {}x{}, \
+                 or of the sensor, {}x{}",
+                path.display(),
+                mask.width(),
+                mask.height(),
+                cropped.0,
+                cropped.1,
+                width / scale,
+                height / scale
+            );
+        };
+
+        let weights = (0..width * height)
+            .into_par_iter()
+            .map(|i| {
+                // the border takes the edge of a mask that doesn't cover it
+                let x = (i % width / scale)
+                    .saturating_sub(origin.0)
+                    .min(mask.width() - 1);
+                let y = (i / width / scale)
+                    .saturating_sub(origin.1)
+                    .min(mask.height() - 1);
+                if mask.get_pixel(x, y).0[0] >= 128 {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .collect::<Vec<f32>>();
+
+        let masked = weights.iter().filter(|&&w| w > 0.0).count();
+        info!(
+            "motion mask covers {:.1}% of the image",
+            masked as f64 * 100.0 / weights.len() as f64
+        );
+
+        Self { width, weights }
+    }
+
+    /// Turns the hard edge of the mask into a ramp `radius` merged pixels
+    /// wide on each side, so that the resolution and noise of the merge
+    /// fade into the ones of the single frame instead of switching at once
+    pub fn feather(&mut self, radius: u32) {
+        if radius == 0 {
+            return;
+        }
+
+        // two box blurs make a triangle, smoother than a linear ramp ends
+        let half = (radius as usize).div_ceil(2);
+        let blurred = box_blur(&self.weights, self.width as usize, half);
+        self.weights = box_blur(&blurred, self.width as usize, half);
+    }
+
+    /// How much of the merged pixel `x`, `y` comes from the single frame
+    pub fn weight(&self, x: u32, y: u32) -> f32 {
+        self.weights[(y * self.width + x) as usize]
+    }
+}
+
+/// Replaces the masked parts of the merge by a demosaic of `frame`, scaled
+/// and calibrated like the merge. `scale` is the one of the shift pattern.
+/// Both are linear, so the feathered edges blend in linear light.
+pub fn apply(
+    planes: &mut Planes,
+    mask: &MotionMask,
+    frame: &RawImage,
+    scale: u32,
+    calibration: Option<&Calibration>,
+    channel_samples: [u32; 3],
+) {
+    info!("replacing the masked regions by {}", frame.path.display());
+    let single = demosaic(frame, calibration, channel_samples);
+    let width = planes.width;
+
+    for (c, channel) in planes.channels.iter_mut().enumerate() {
+        channel
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let y = y as u32;
+                for (x, v) in row.iter_mut().enumerate() {
+                    let x = x as u32;
+                    let weight = mask.weight(x, y);
+                    if weight > 0.0 {
+                        let fallback = single.get_pixel(x / scale, y / scale).0[c] as f32;
+                        *v = *v * (1.0 - weight) + fallback * weight;
+                    }
+                }
+            });
+    }
+}
@@ -0,0 +1,572 @@
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use tiff::encoder::colortype::{self, ColorType};
+use tiff::encoder::compression::{Compression, Deflate, Lzw, Packbits};
+use tiff::encoder::{
+    DirectoryEncoder, ImageEncoder, TiffEncoder, TiffKind, TiffKindStandard, TiffValue,
+};
+use tiff::tags::{PhotometricInterpretation, SampleFormat, Tag};
+
+use crate::cli::OutputOptions;
+use crate::failure::{self, fail};
+use crate::weights::{self, WeightMap};
+use crate::{fits, MergeOptions};
+use crate::{RgbImage16, RggbImage16};
+
+const PAGE_NAME: Tag = Tag::Unknown(285);
+// DNG tags, raw editors use them for highlight reconstruction
+const BLACK_LEVEL: Tag = Tag::Unknown(50714);
+const WHITE_LEVEL: Tag = Tag::Unknown(50717);
+const XMP: Tag = Tag::Unknown(700);
+
+/// edge of the square tiles of pyramid TIFFs, overviews stop once they fit in one
+const TILE_SIZE: u32 = 256;
+
+/// Extra information stored alongside the pixels, only TIFF outputs carry it
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// `key=value` pairs, written one per line to ImageDescription
+    pub description: Vec<(String, String)>,
+    pub page_name: Option<String>,
+    /// per channel levels of the merged data
+    pub black_level: Option<Vec<u32>>,
+    pub white_level: Option<Vec<u32>>,
+    /// of a single frame, in seconds
+    pub exposure_time: Option<f64>,
+    pub iso: Option<u32>,
+    /// as printed by exiftool, e.g. "2024:05:01 21:03:44"
+    pub date_time: Option<String>,
+    /// XMP packet, e.g. the provenance record
+    pub xmp: Option<String>,
+}
+
+impl Metadata {
+    pub fn describe(&mut self, key: &str, value: impl Display) {
+        self.description.push((key.to_string(), value.to_string()));
+    }
+}
+
+/// How --planar lays the channels out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Planar {
+    /// one grayscale TIFF per channel, `photo.R.tiff`, `photo.G.tiff`, `photo.B.tiff`
+    Separate,
+    /// a single TIFF storing the channels one after the other
+    Single,
+}
+
+/// How --compress packs the strips of TIFF outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compress {
+    Deflate,
+    Lzw,
+    Packbits,
+}
+
+/// R, G1, G2, B samples: there is no photometric interpretation for CFA
+/// colors that aren't a mosaic, so this is a gray image with the other 3
+/// channels as unspecified extra samples
+struct Rggb16;
+
+impl ColorType for Rggb16 {
+    type Inner = u16;
+    const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::BlackIsZero;
+    const BITS_PER_SAMPLE: &'static [u16] = &[16; 4];
+    const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint; 4];
+}
+
+const EXTRA_SAMPLES: Tag = Tag::Unknown(338);
+
+const CHANNEL_NAMES: [&str; 3] = ["R", "G", "B"];
+
+pub fn is_tiff(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"))
+        .unwrap_or(false)
+}
+
+/// Writes `metadata`, with the levels of the `channels` stored in the image
+fn write_metadata<W: Write + Seek, K: TiffKind>(
+    encoder: &mut DirectoryEncoder<W, K>,
+    metadata: &Metadata,
+    channels: Range<usize>,
+) -> tiff::TiffResult<()> {
+    encoder.write_tag(
+        Tag::Software,
+        concat!("psmsmerge ", env!("CARGO_PKG_VERSION")),
+    )?;
+
+    if !metadata.description.is_empty() {
+        let description = metadata
+            .description
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        encoder.write_tag(Tag::ImageDescription, description.as_str())?;
+    }
+
+    if let Some(page_name) = &metadata.page_name {
+        encoder.write_tag(PAGE_NAME, page_name.as_str())?;
+    }
+
+    if let Some(black_level) = &metadata.black_level {
+        encoder.write_tag(BLACK_LEVEL, &black_level[channels.clone()])?;
+    }
+
+    if let Some(white_level) = &metadata.white_level {
+        encoder.write_tag(WHITE_LEVEL, &white_level[channels])?;
+    }
+
+    if let Some(xmp) = &metadata.xmp {
+        encoder.write_tag(XMP, xmp.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Horizontal differencing, TIFF predictor 2: every sample becomes its
+/// difference with the same channel of the pixel on its left
+fn predict(data: &[u16], width: u32, samples: usize) -> Vec<u16> {
+    let mut out = data.to_vec();
+
+    out.par_chunks_mut(width as usize * samples)
+        .for_each(|row| {
+            for i in (samples..row.len()).rev() {
+                row[i] = row[i].wrapping_sub(row[i - samples]);
+            }
+        });
+
+    out
+}
+
+fn finish_image<C: ColorType, D: Compression>(
+    mut image: ImageEncoder<BufWriter<File>, C, TiffKindStandard, D>,
+    data: &[C::Inner],
+    metadata: Option<(&Metadata, Range<usize>)>,
+    predictor: bool,
+) -> tiff::TiffResult<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let samples = C::BITS_PER_SAMPLE.len();
+    if C::TIFF_VALUE == PhotometricInterpretation::BlackIsZero && samples > 1 {
+        image
+            .encoder()
+            .write_tag(EXTRA_SAMPLES, &vec![0u16; samples - 1][..])?;
+    }
+
+    if let Some((metadata, channels)) = metadata {
+        write_metadata(image.encoder(), metadata, channels)?;
+    }
+
+    if predictor {
+        image.encoder().write_tag(Tag::Predictor, 2u16)?;
+    }
+
+    image.write_data(data)
+}
+
+/// Writes a single image TIFF, compressed the way `compress` asks for.
+/// `data` must already be differenced when `predictor` is set.
+fn write_image<C: ColorType>(
+    path: &Path,
+    (width, height): (u32, u32),
+    data: &[C::Inner],
+    metadata: Option<(&Metadata, Range<usize>)>,
+    compress: Option<Compress>,
+    predictor: bool,
+) -> tiff::TiffResult<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut tiff = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+
+    match compress {
+        None => finish_image(
+            tiff.new_image::<C>(width, height)?,
+            data,
+            metadata,
+            predictor,
+        ),
+        Some(Compress::Deflate) => finish_image(
+            tiff.new_image_with_compression::<C, _>(width, height, Deflate::default())?,
+            data,
+            metadata,
+            predictor,
+        ),
+        Some(Compress::Lzw) => finish_image(
+            tiff.new_image_with_compression::<C, _>(width, height, Lzw)?,
+            data,
+            metadata,
+            predictor,
+        ),
+        Some(Compress::Packbits) => finish_image(
+            tiff.new_image_with_compression::<C, _>(width, height, Packbits)?,
+            data,
+            metadata,
+            predictor,
+        ),
+    }
+}
+
+/// 16 bit samples, differenced first when --predictor is set
+fn predicted<'a>(
+    data: &'a [u16],
+    width: u32,
+    samples: usize,
+    args: &OutputOptions,
+) -> Cow<'a, [u16]> {
+    if args.predictor {
+        Cow::Owned(predict(data, width, samples))
+    } else {
+        Cow::Borrowed(data)
+    }
+}
+
+fn save_tiff(
+    imgbuf: &RgbImage16,
+    path: &Path,
+    metadata: &Metadata,
+    args: &OutputOptions,
+) -> tiff::TiffResult<()> {
+    write_image::<colortype::RGB16>(
+        path,
+        imgbuf.dimensions(),
+        &predicted(imgbuf.as_raw(), imgbuf.width(), 3, args),
+        Some((metadata, 0..3)),
+        args.compress,
+        args.predictor,
+    )
+}
+
+/// One `TILE_SIZE` square tile of `imgbuf`, zero padded past its edges
+fn tile(imgbuf: &RgbImage16, tx: u32, ty: u32) -> Vec<u16> {
+    let mut tile = vec![0u16; (TILE_SIZE * TILE_SIZE * 3) as usize];
+
+    let x0 = tx * TILE_SIZE;
+    let width = (imgbuf.width() - x0).min(TILE_SIZE) as usize;
+
+    for y in 0..TILE_SIZE.min(imgbuf.height() - ty * TILE_SIZE) {
+        let row = (ty * TILE_SIZE + y) as usize * imgbuf.width() as usize + x0 as usize;
+        let start = (y * TILE_SIZE * 3) as usize;
+        tile[start..start + width * 3]
+            .copy_from_slice(&imgbuf.as_raw()[row * 3..(row + width) * 3]);
+    }
+
+    tile
+}
+
+/// Writes `imgbuf` as a tiled page, followed by its IFD
+fn write_tiled<W: Write + Seek, K: TiffKind>(
+    tiff: &mut TiffEncoder<W, K>,
+    imgbuf: &RgbImage16,
+    overview: bool,
+    metadata: Option<&Metadata>,
+) -> tiff::TiffResult<()> {
+    let mut encoder = tiff.new_directory()?;
+
+    let tiles_x = imgbuf.width().div_ceil(TILE_SIZE);
+    let tiles_y = imgbuf.height().div_ceil(TILE_SIZE);
+
+    let mut offsets = Vec::new();
+    let mut byte_counts = Vec::new();
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let tile = tile(imgbuf, tx, ty);
+            let offset = encoder.write_data(&tile[..])?;
+            offsets.push(u32::try_from(offset)?);
+            byte_counts.push((tile.len() * 2) as u32);
+        }
+    }
+
+    // 1 marks a reduced resolution version of the first page
+    encoder.write_tag(Tag::NewSubfileType, overview as u32)?;
+    encoder.write_tag(Tag::ImageWidth, imgbuf.width())?;
+    encoder.write_tag(Tag::ImageLength, imgbuf.height())?;
+    encoder.write_tag(Tag::BitsPerSample, &[16u16, 16, 16][..])?;
+    encoder.write_tag(Tag::Compression, 1u16)?;
+    encoder.write_tag(Tag::PhotometricInterpretation, 2u16)?;
+    encoder.write_tag(Tag::SamplesPerPixel, 3u16)?;
+    encoder.write_tag(Tag::PlanarConfiguration, 1u16)?;
+    encoder.write_tag(Tag::SampleFormat, &[1u16, 1, 1][..])?;
+    encoder.write_tag(Tag::TileWidth, TILE_SIZE)?;
+    encoder.write_tag(Tag::TileLength, TILE_SIZE)?;
+    encoder.write_tag(Tag::TileOffsets, &offsets[..])?;
+    encoder.write_tag(Tag::TileByteCounts, &byte_counts[..])?;
+
+    if let Some(metadata) = metadata {
+        write_metadata(&mut encoder, metadata, 0..3)?;
+    }
+
+    encoder.finish()
+}
+
+/// Tiled TIFF with halved overviews down to a single tile, viewers can pan
+/// and zoom it without decoding the whole image
+fn save_pyramid_tiff(
+    imgbuf: &RgbImage16,
+    path: &Path,
+    metadata: &Metadata,
+) -> tiff::TiffResult<()> {
+    let mut tiff = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+
+    write_tiled(&mut tiff, imgbuf, false, Some(metadata))?;
+
+    let mut level = imgbuf.clone();
+    while level.width().max(level.height()) > TILE_SIZE {
+        level = image::imageops::resize(
+            &level,
+            (level.width() / 2).max(1),
+            (level.height() / 2).max(1),
+            image::imageops::FilterType::Triangle,
+        );
+        write_tiled(&mut tiff, &level, true, None)?;
+    }
+
+    Ok(())
+}
+
+/// Hidden file next to `path`, on the same filesystem so it can be renamed over it
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Flushes `path` to the disk, so that a rename over it can't reach the disk
+/// before its contents do and leave an empty file after a power loss
+fn sync(path: &Path) -> std::io::Result<()> {
+    // Windows only flushes files opened for writing
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .sync_all()
+}
+
+/// Flushes the directory entry of a renamed `path`, which unix only makes
+/// durable when its directory is synced; Windows can't open directories
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    if cfg!(unix) {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes a temporary file with `write`, syncs it and renames it to `path`
+/// once complete, so an interrupted run or a power loss never leaves a
+/// truncated output behind
+pub fn save_atomically(path: &Path, write: impl FnOnce(&Path) -> Result<(), String>) {
+    let temp = temp_path(path);
+
+    let result = failure::writing(&temp, || write(&temp))
+        .and_then(|_| sync(&temp).map_err(|e| e.to_string()))
+        .and_then(|_| std::fs::rename(&temp, path).map_err(|e| e.to_string()))
+        .and_then(|_| sync_dir(path).map_err(|e| e.to_string()));
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp);
+        fail!(Io, "failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// Saves the merged image, the format is picked from the file extension
+pub fn save(imgbuf: &RgbImage16, path: &Path, metadata: &Metadata, args: &OutputOptions) {
+    save_atomically(path, |temp| {
+        if is_tiff(path) {
+            save_tiff(imgbuf, temp, metadata, args).map_err(|e| e.to_string())
+        } else {
+            image::ImageFormat::from_path(path)
+                .and_then(|format| imgbuf.save_with_format(temp, format))
+                .map_err(|e| e.to_string())
+        }
+    });
+}
+
+/// Saves the merged image as a tiled multi resolution TIFF, see `save_pyramid_tiff`
+fn save_pyramid(imgbuf: &RgbImage16, path: &Path, metadata: &Metadata) {
+    save_atomically(path, |temp| {
+        save_pyramid_tiff(imgbuf, temp, metadata).map_err(|e| e.to_string())
+    });
+}
+
+fn plane(imgbuf: &RgbImage16, channel: usize) -> Vec<u16> {
+    imgbuf
+        .as_raw()
+        .iter()
+        .skip(channel)
+        .step_by(3)
+        .copied()
+        .collect()
+}
+
+fn to_float(plane: &[u16]) -> Vec<f32> {
+    plane.iter().map(|&v| v as f32).collect()
+}
+
+/// `photo.tiff` gets its red channel in `photo.R.tiff`
+fn channel_path(path: &Path, channel: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap().to_os_string();
+    name.push(".");
+    name.push(CHANNEL_NAMES[channel]);
+    name.push(".");
+    name.push(path.extension().unwrap());
+    path.with_file_name(name)
+}
+
+fn save_channel_tiff(
+    imgbuf: &RgbImage16,
+    channel: usize,
+    path: &Path,
+    metadata: &Metadata,
+    args: &OutputOptions,
+) -> tiff::TiffResult<()> {
+    let plane = plane(imgbuf, channel);
+    let metadata = Some((metadata, channel..channel + 1));
+
+    if args.float {
+        // predictor 2 is for integers, floats would need predictor 3
+        write_image::<colortype::Gray32Float>(
+            path,
+            imgbuf.dimensions(),
+            &to_float(&plane),
+            metadata,
+            args.compress,
+            false,
+        )
+    } else {
+        write_image::<colortype::Gray16>(
+            path,
+            imgbuf.dimensions(),
+            &predicted(&plane, imgbuf.width(), 1, args),
+            metadata,
+            args.compress,
+            args.predictor,
+        )
+    }
+}
+
+/// One RGB TIFF with PlanarConfiguration 2, a strip per channel
+fn save_planar_tiff(
+    imgbuf: &RgbImage16,
+    path: &Path,
+    metadata: &Metadata,
+    float: bool,
+) -> tiff::TiffResult<()> {
+    let mut tiff = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let mut encoder = tiff.new_directory()?;
+
+    let mut offsets = Vec::new();
+    let mut byte_counts = Vec::new();
+    for channel in 0..3 {
+        let plane = plane(imgbuf, channel);
+        let offset = if float {
+            encoder.write_data(&to_float(&plane)[..])?
+        } else {
+            encoder.write_data(&plane[..])?
+        };
+        offsets.push(u32::try_from(offset)?);
+        byte_counts.push(u32::try_from(plane.len() * if float { 4 } else { 2 })?);
+    }
+
+    let (bits, format) = if float { (32u16, 3u16) } else { (16, 1) };
+    encoder.write_tag(Tag::ImageWidth, imgbuf.width())?;
+    encoder.write_tag(Tag::ImageLength, imgbuf.height())?;
+    encoder.write_tag(Tag::BitsPerSample, &[bits; 3][..])?;
+    encoder.write_tag(Tag::Compression, 1u16)?;
+    encoder.write_tag(Tag::PhotometricInterpretation, 2u16)?;
+    encoder.write_tag(Tag::SamplesPerPixel, 3u16)?;
+    encoder.write_tag(Tag::PlanarConfiguration, 2u16)?;
+    encoder.write_tag(Tag::SampleFormat, &[format; 3][..])?;
+    encoder.write_tag(Tag::RowsPerStrip, imgbuf.height())?;
+    encoder.write_tag(Tag::StripOffsets, &offsets[..])?;
+    encoder.write_tag(Tag::StripByteCounts, &byte_counts[..])?;
+    write_metadata(&mut encoder, metadata, 0..3)?;
+
+    encoder.finish()
+}
+
+fn save_planar(imgbuf: &RgbImage16, path: &Path, metadata: &Metadata, args: &OutputOptions) {
+    match args.planar {
+        Some(Planar::Separate) => {
+            for channel in 0..3 {
+                let path = channel_path(path, channel);
+                save_atomically(&path, |temp| {
+                    save_channel_tiff(imgbuf, channel, temp, metadata, args)
+                        .map_err(|e| e.to_string())
+                });
+            }
+        }
+        Some(Planar::Single) => save_atomically(path, |temp| {
+            save_planar_tiff(imgbuf, temp, metadata, args.float).map_err(|e| e.to_string())
+        }),
+        None => unreachable!(),
+    }
+}
+
+/// Saves the R, G1, G2, B merge of --rggb-out as a 4 samples TIFF
+pub fn save_rggb(rggb: &RggbImage16, path: &Path, metadata: &Metadata, args: &OutputOptions) {
+    save_atomically(path, |temp| {
+        write_image::<Rggb16>(
+            temp,
+            rggb.dimensions(),
+            &predicted(rggb.as_raw(), rggb.width(), 4, args),
+            Some((metadata, 0..4)),
+            args.compress,
+            args.predictor,
+        )
+        .map_err(|e| e.to_string())
+    });
+}
+
+/// Every file a merge saved to `path` creates: what `write` does, and the
+/// weight map of --weight-map
+pub fn written_paths(path: &Path, options: &MergeOptions, args: &OutputOptions) -> Vec<PathBuf> {
+    let mut paths = match args.planar {
+        Some(Planar::Separate) => (0..3).map(|c| channel_path(path, c)).collect(),
+        _ => vec![path.to_path_buf()],
+    };
+    if options.weight_map {
+        paths.push(weights::path_for(path));
+    }
+    paths
+}
+
+/// Writes the merged image the way the output options ask for
+pub fn write(imgbuf: &RgbImage16, path: &Path, metadata: &Metadata, args: &OutputOptions) {
+    if args.pyramid {
+        save_pyramid(imgbuf, path, metadata);
+    } else if args.planar.is_some() {
+        save_planar(imgbuf, path, metadata, args);
+    } else if fits::is_fits(path) {
+        save_atomically(path, |temp| {
+            fits::save(imgbuf, temp, metadata, args.float).map_err(|e| e.to_string())
+        });
+    } else {
+        save(imgbuf, path, metadata, args);
+    }
+}
+
+/// Saves a weight map as a single channel float TIFF
+pub fn save_weights(weights: &WeightMap, path: &Path, args: &OutputOptions) {
+    save_atomically(path, |temp| {
+        write_image::<colortype::Gray32Float>(
+            temp,
+            weights.dimensions(),
+            weights.as_raw(),
+            None,
+            args.compress,
+            false,
+        )
+        .map_err(|e| e.to_string())
+    });
+}
@@ -0,0 +1,76 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::failure::fail;
+
+/// used when the raws don't say, a 35mm on full frame
+const DEFAULT_FIELD_OF_VIEW: f32 = 54.4;
+
+/// Row and column of tile names like `r2c3`
+pub fn grid_position(tile_name: &str) -> Option<(u32, u32)> {
+    let (row, col) = tile_name.strip_prefix('r')?.split_once('c')?;
+    Some((row.parse().ok()?, col.parse().ok()?))
+}
+
+/// Appends `image` to a Hugin/PTGui project stub, writing the header first
+/// when the project doesn't exist yet.
+///
+/// Tiles named after their grid position get a first guess of yaw and pitch
+/// from the field of view and the overlap, the control points are left to the
+/// stitcher.
+pub fn append_to_project(
+    project: &Path,
+    image: &Path,
+    (width, height): (u32, u32),
+    field_of_view: Option<f32>,
+    tile_name: Option<&str>,
+    overlap_pct: f32,
+) {
+    let new_project = !project.exists();
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(project)
+        .unwrap_or_else(|e| fail!(Io, "can't open {}: {}", project.display(), e));
+
+    if new_project {
+        writeln!(file, "# hugin project stub written by psmsmerge").unwrap();
+        writeln!(file, "p f2 w{} h{} v360 n\"TIFF_m\"", width * 2, height).unwrap();
+        writeln!(file, "m i0").unwrap();
+    }
+
+    let hfov = field_of_view.unwrap_or_else(|| {
+        warn!(
+            "unknown field of view, assuming {} degrees",
+            DEFAULT_FIELD_OF_VIEW
+        );
+        DEFAULT_FIELD_OF_VIEW
+    });
+    let vfov = hfov * height as f32 / width as f32;
+    let step = 1.0 - overlap_pct / 100.0;
+
+    let (yaw, pitch) = match tile_name.and_then(grid_position) {
+        Some((row, col)) => (col as f32 * hfov * step, -(row as f32) * vfov * step),
+        None => (0.0, 0.0),
+    };
+
+    let image = std::path::absolute(image).unwrap_or_else(|_| image.to_path_buf());
+
+    writeln!(
+        file,
+        "i w{} h{} f0 v{} y{} p{} r0 n\"{}\"",
+        width,
+        height,
+        hfov,
+        yaw,
+        pitch,
+        image.display()
+    )
+    .unwrap();
+
+    info!("added {} to {}", image.display(), project.display());
+}
@@ -0,0 +1,562 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::info;
+
+use crate::failure::fail;
+use crate::{cameras, id_offsets, sequence_to_group_id};
+
+/// Where a frame of a sequence goes in the merge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shot {
+    pub group: u32,
+    /// (y, x) whole pixel shift of the frame within its group
+    pub offset: (u32, u32),
+}
+
+impl Shot {
+    /// Position of the shot in its group, the inverse of `id_offsets`
+    pub fn id_in_group(&self) -> u32 {
+        (0..4).find(|&id| id_offsets(id) == self.offset).unwrap()
+    }
+}
+
+/// How a camera mode moves the sensor between the frames of a sequence.
+///
+/// Every group is 4 frames covering a full 2x2 bayer cell, merged on their
+/// own, and the groups are interleaved in a `scale` x `scale` grid. Patterns
+/// can take any multiple of 4 frames, but a mode whose frames don't split
+/// into such groups, e.g. sub pixel shifts without the whole pixel ones,
+/// can't be described: every output pixel is the 4 shots merge of a group.
+#[derive(Debug, Clone)]
+pub struct ShiftPattern {
+    pub name: String,
+    /// Camera Model Name the pattern is for, any camera when unset
+    pub camera: Option<String>,
+    pub scale: u32,
+    /// one per frame, in shooting order
+    pub shots: Vec<Shot>,
+    /// (x, y) position of every group in the output grid
+    pub groups: Vec<(u32, u32)>,
+}
+
+impl ShiftPattern {
+    pub fn frames(&self) -> usize {
+        self.shots.len()
+    }
+
+    /// Shot of the frame with this sequence number, some bodies keep counting
+    /// across sequences
+    pub fn shot(&self, sequence_number: u32) -> Shot {
+        self.shots[(sequence_number as usize - 1) % self.frames()]
+    }
+
+    /// Group to merge for every (x, y) of the output grid, by row. Positions
+    /// the pattern leaves out get the nearest group.
+    pub fn grid(&self) -> Vec<usize> {
+        (0..self.scale * self.scale)
+            .map(|i| {
+                let (x, y) = (i % self.scale, i / self.scale);
+                (0..self.groups.len())
+                    .min_by_key(|&g| {
+                        let (gx, gy) = self.groups[g];
+                        gx.abs_diff(x) + gy.abs_diff(y)
+                    })
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.scale == 0 {
+            return Err("scale must be at least 1".to_string());
+        }
+
+        if self.groups.is_empty() {
+            return Err("no groups".to_string());
+        }
+
+        if let Some(&(x, y)) = self
+            .groups
+            .iter()
+            .find(|&&(x, y)| x >= self.scale || y >= self.scale)
+        {
+            return Err(format!("group at {}, {} is outside of the grid", x, y));
+        }
+
+        for group in 0..self.groups.len() as u32 {
+            let mut offsets = self
+                .shots
+                .iter()
+                .filter(|shot| shot.group == group)
+                .map(|shot| shot.offset)
+                .collect::<Vec<_>>();
+            offsets.sort();
+
+            if offsets != [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                return Err(format!(
+                    "group {} must be 4 shots offset by every one of (0, 0), (0, 1), (1, 0), (1, 1), \
+                     the merge only takes frames in such groups",
+                    group
+                ));
+            }
+        }
+
+        if let Some(shot) = self
+            .shots
+            .iter()
+            .find(|shot| shot.group as usize >= self.groups.len())
+        {
+            return Err(format!("shot in unknown group {}", shot.group));
+        }
+
+        Ok(())
+    }
+}
+
+/// The modes of the cameras in the database, 4 and 16 shots; 8 or 12 shots
+/// modes are added from a patterns file
+fn builtin() -> Vec<ShiftPattern> {
+    let shots_16 = (1..=16)
+        .map(|sequence_number| {
+            let (group, id) = sequence_to_group_id(sequence_number);
+            Shot {
+                group,
+                offset: id_offsets(id),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    vec![
+        ShiftPattern {
+            name: "4 shots".to_string(),
+            camera: None,
+            scale: 1,
+            shots: shots_16[..4]
+                .iter()
+                .map(|shot| Shot { group: 0, ..*shot })
+                .collect(),
+            groups: vec![(0, 0)],
+        },
+        ShiftPattern {
+            name: "16 shots".to_string(),
+            camera: None,
+            scale: 2,
+            shots: shots_16,
+            groups: vec![(0, 0), (1, 0), (0, 1), (1, 1)],
+        },
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Array(Vec<Value>),
+}
+
+/// Parses the TOML values patterns use: strings, integers and arrays of them
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    let text = text.trim_start();
+
+    if let Some(rest) = text.strip_prefix('"') {
+        let end = rest.find('"').ok_or("unterminated string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in array".to_string());
+            }
+        }
+    }
+
+    let end = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '-' || c == '+' || c == '_'))
+        .unwrap_or(text.len());
+    let number = text[..end].replace('_', "");
+    let number = number
+        .parse::<i64>()
+        .map_err(|_| format!("unexpected value {:?}", text.lines().next().unwrap_or("")))?;
+    Ok((Value::Integer(number), &text[end..]))
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn integer(value: &Value) -> Result<u32, String> {
+    match value {
+        Value::Integer(n) => u32::try_from(*n).map_err(|_| format!("{} is out of range", n)),
+        _ => Err("expected an integer".to_string()),
+    }
+}
+
+fn integers<const N: usize>(value: &Value) -> Result<[u32; N], String> {
+    match value {
+        Value::Array(items) if items.len() == N => {
+            let mut out = [0; N];
+            for (o, item) in out.iter_mut().zip(items) {
+                *o = integer(item)?;
+            }
+            Ok(out)
+        }
+        _ => Err(format!("expected an array of {} integers", N)),
+    }
+}
+
+fn list(value: &Value) -> Result<&[Value], String> {
+    match value {
+        Value::Array(items) => Ok(items),
+        _ => Err("expected an array".to_string()),
+    }
+}
+
+fn pattern(table: &[(String, Value)]) -> Result<ShiftPattern, String> {
+    let get = |key: &str| {
+        table
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .ok_or(format!("missing {}", key))
+    };
+
+    let name = match get("name")? {
+        Value::String(name) => name.clone(),
+        _ => return Err("name must be a string".to_string()),
+    };
+
+    let camera = match get("camera") {
+        Ok(Value::String(camera)) => Some(camera.clone()),
+        Ok(_) => return Err("camera must be a string".to_string()),
+        Err(_) => None,
+    };
+
+    let scale = integer(get("scale")?)?;
+
+    let shots = list(get("shots")?)?
+        .iter()
+        .map(|shot| {
+            let [group, y, x] = integers(shot)?;
+            Ok(Shot {
+                group,
+                offset: (y, x),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let groups = list(get("groups")?)?
+        .iter()
+        .map(|group| integers(group).map(|[x, y]| (x, y)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let pattern = ShiftPattern {
+        name,
+        camera,
+        scale,
+        shots,
+        groups,
+    };
+    pattern
+        .validate()
+        .map_err(|e| format!("{}: {}", pattern.name, e))?;
+    Ok(pattern)
+}
+
+/// Reads the `[[pattern]]` tables of a patterns file
+fn parse(text: &str) -> Result<Vec<ShiftPattern>, String> {
+    let mut tables: Vec<Vec<(String, Value)>> = Vec::new();
+    let mut lines = text.lines().enumerate();
+
+    while let Some((number, line)) = lines.next() {
+        let at = |e: String| format!("line {}: {}", number + 1, e);
+        let line = strip_comment(line).trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "[[pattern]]" {
+            tables.push(Vec::new());
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| at("expected [[pattern]] or key = value".to_string()))?;
+        let table = tables
+            .last_mut()
+            .ok_or_else(|| at("key outside of a [[pattern]] table".to_string()))?;
+
+        // arrays may span several lines
+        let mut value = value.to_string();
+        while value.matches('[').count() > value.matches(']').count() {
+            let (_, next) = lines
+                .next()
+                .ok_or_else(|| at("unterminated array".to_string()))?;
+            value.push(' ');
+            value.push_str(strip_comment(next));
+        }
+
+        let (value, rest) = parse_value(&value).map_err(at)?;
+        if !rest.trim().is_empty() {
+            return Err(at(format!("unexpected {:?}", rest.trim())));
+        }
+
+        table.push((key.trim().to_string(), value));
+    }
+
+    tables.iter().map(|table| pattern(table)).collect()
+}
+
+/// `~/.config/psmsmerge/patterns.toml`, or under `$XDG_CONFIG_HOME`
+fn default_file() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("psmsmerge").join("patterns.toml"))
+}
+
+fn load_file(path: &Path) -> Vec<ShiftPattern> {
+    let text = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e));
+    let patterns = parse(&text).unwrap_or_else(|e| fail!(Usage, "{}: {}", path.display(), e));
+    info!(
+        "loaded {} shift patterns from {}",
+        patterns.len(),
+        path.display()
+    );
+    patterns
+}
+
+/// Built-in patterns followed by the user ones, later entries take precedence
+pub struct Registry {
+    patterns: Vec<ShiftPattern>,
+}
+
+impl Registry {
+    fn load(user_file: Option<&Path>) -> Self {
+        let mut patterns = builtin();
+
+        if let Some(path) = default_file().filter(|path| path.is_file()) {
+            patterns.extend(load_file(&path));
+        }
+
+        if let Some(path) = user_file {
+            patterns.extend(load_file(path));
+        }
+
+        Self { patterns }
+    }
+
+    /// Pattern for a sequence of `frames` shot by `model`: one made for the
+    /// camera wins over the ones the camera database lists for it, which win
+    /// over any other generic one
+    pub fn find(&self, model: Option<&str>, frames: usize) -> Option<&ShiftPattern> {
+        let candidates = || {
+            self.patterns
+                .iter()
+                .rev()
+                .filter(move |pattern| pattern.frames() == frames)
+        };
+
+        let known = cameras::lookup(model).map_or(&[][..], |camera| camera.shift_patterns);
+
+        candidates()
+            .find(|pattern| pattern.camera.is_some() && pattern.camera.as_deref() == model)
+            .or_else(|| {
+                candidates().find(|pattern| {
+                    pattern.camera.is_none() && known.contains(&pattern.name.as_str())
+                })
+            })
+            .or_else(|| candidates().find(|pattern| pattern.camera.is_none()))
+    }
+
+    /// The pattern of the fewest frames `model` can shoot that holds a frame
+    /// numbered `sequence_number`, for a frame looked at on its own
+    pub fn covering(&self, model: Option<&str>, sequence_number: u32) -> Option<&ShiftPattern> {
+        self.frame_counts()
+            .into_iter()
+            .filter(|&frames| frames >= sequence_number as usize)
+            .find_map(|frames| self.find(model, frames))
+    }
+
+    /// Every frame count some pattern takes
+    pub fn frame_counts(&self) -> Vec<usize> {
+        let mut counts = self
+            .patterns
+            .iter()
+            .map(|pattern| pattern.frames())
+            .collect::<Vec<_>>();
+        counts.sort();
+        counts.dedup();
+        counts
+    }
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// Loads the patterns once, before any sequence is
+pub fn init(user_file: Option<&Path>) {
+    REGISTRY.get_or_init(|| Registry::load(user_file));
+}
+
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| Registry::load(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EIGHT_SHOTS: &str = r#"
+# a comment line
+[[pattern]]
+name = "8 # shots"   # a comment after a string holding a #
+camera = "ILCE-7RM4"
+scale = 2
+shots = [
+  [0, 1, 1], [0, 0, 1],   # first group
+  [0, 0, 0], [0, 1, 0],
+  [1, 1, 1], [1, 0, 1], [1, 0, 0], [1, 1, 0],
+]
+groups = [[0, 0], [1, 1]]
+"#;
+
+    fn generic(name: &str, frames: usize) -> ShiftPattern {
+        let base = &builtin()[if frames == 4 { 0 } else { 1 }];
+        ShiftPattern {
+            name: name.to_string(),
+            camera: None,
+            ..base.clone()
+        }
+    }
+
+    #[test]
+    fn multi_line_arrays_and_comments() {
+        let patterns = parse(EIGHT_SHOTS).unwrap();
+        assert_eq!(patterns.len(), 1);
+
+        let pattern = &patterns[0];
+        assert_eq!(pattern.name, "8 # shots");
+        assert_eq!(pattern.camera.as_deref(), Some("ILCE-7RM4"));
+        assert_eq!(pattern.scale, 2);
+        assert_eq!(pattern.frames(), 8);
+        assert_eq!(
+            pattern.shots[1],
+            Shot {
+                group: 0,
+                offset: (0, 1)
+            }
+        );
+        assert_eq!(pattern.groups, [(0, 0), (1, 1)]);
+        // the positions left out take the nearest group
+        assert_eq!(pattern.grid(), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(
+            parse_value("[1, [2, -3], \"a]\"] rest"),
+            Ok((
+                Value::Array(vec![
+                    Value::Integer(1),
+                    Value::Array(vec![Value::Integer(2), Value::Integer(-3)]),
+                    Value::String("a]".to_string()),
+                ]),
+                " rest"
+            ))
+        );
+        assert_eq!(parse_value("1_000"), Ok((Value::Integer(1000), "")));
+        assert!(parse_value("\"open").is_err());
+        assert!(parse_value("[1 2]").is_err());
+        assert!(parse_value("true").is_err());
+    }
+
+    #[test]
+    fn out_of_range_integers() {
+        for scale in ["-1", "4294967296", "99999999999999999999"] {
+            let text = EIGHT_SHOTS.replace("scale = 2", &format!("scale = {}", scale));
+            assert!(parse(&text).is_err(), "scale = {} was accepted", scale);
+        }
+    }
+
+    #[test]
+    fn malformed_files() {
+        let errors = [
+            ("scale = 1", "outside of a [[pattern]]"),
+            ("[[pattern]]\nshots = [[0, 0, 0],", "unterminated array"),
+            ("[[pattern]]\nname = \"a\" \"b\"", "unexpected"),
+            ("[[pattern]]\nname = \"a\"", "missing scale"),
+        ];
+        for (text, error) in errors {
+            let e = parse(text).unwrap_err();
+            assert!(e.contains(error), "{:?} gave {:?}", text, e);
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let invalid = [
+            ("scale = 2", "scale = 0"),
+            ("groups = [[0, 0], [1, 1]]", "groups = [[0, 0], [2, 1]]"),
+            ("groups = [[0, 0], [1, 1]]", "groups = [[0, 0]]"),
+            ("[1, 1, 0],\n]", "[1, 1, 1],\n]"),
+        ];
+        for (from, to) in invalid {
+            let text = EIGHT_SHOTS.replace(from, to);
+            assert!(parse(&text).is_err(), "{:?} was accepted", to);
+        }
+        for pattern in builtin() {
+            pattern.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn camera_then_database_then_generic() {
+        let mut patterns = builtin();
+        patterns.extend(parse(&EIGHT_SHOTS.replace("\"8 # shots\"", "\"A7R IV 4\"")).unwrap());
+        patterns[2].shots.truncate(4);
+        patterns[2].groups.truncate(1);
+        patterns.push(generic("other 4", 4));
+        let registry = Registry { patterns };
+
+        let name = |model: Option<&str>, frames| {
+            registry
+                .find(model, frames)
+                .map(|pattern| pattern.name.as_str())
+        };
+        // made for the camera
+        assert_eq!(name(Some("ILCE-7RM4"), 4), Some("A7R IV 4"));
+        // the one the camera database lists, though a later generic one exists
+        assert_eq!(name(Some("ILCE-7RM5"), 4), Some("4 shots"));
+        // the last generic one for anything else
+        assert_eq!(name(Some("NIKON Z 8"), 4), Some("other 4"));
+        assert_eq!(name(None, 4), Some("other 4"));
+        assert_eq!(name(None, 16), Some("16 shots"));
+        assert_eq!(name(None, 8), None);
+
+        assert_eq!(registry.frame_counts(), [4, 16]);
+    }
+}
@@ -0,0 +1,281 @@
+use log::{debug, info};
+use rayon::prelude::*;
+
+use crate::RawImage;
+
+/// samples this close to the black level, as a fraction of the range, are
+/// the shadows the banding is measured in
+const SHADOWS: f64 = 0.01;
+
+/// longest row period searched for
+const MAX_PERIOD: usize = 64;
+
+/// every this many columns are measured, plenty for a row mean
+const COLUMN_STEP: usize = 5;
+
+/// same color rows on either side a row is compared with, what it should
+/// be if it weren't a PDAF row
+const REFERENCE_ROWS: usize = 4;
+
+/// how many standard errors off a row of the period has to be to count as
+/// a PDAF row
+const SIGNIFICANCE: f64 = 5.0;
+
+/// passes measuring the rows again with the banding found so far taken off,
+/// the PDAF rows among the reference rows skew the first one a little
+const PASSES: usize = 3;
+
+/// The PDAF rows of a frame: the offset, in DN, of every row of a period
+/// repeating down the sensor, 0 for the rows that aren't PDAF rows
+#[derive(Debug, Clone)]
+pub struct Banding {
+    offsets: Vec<f32>,
+}
+
+impl Banding {
+    /// What to take off the samples of row `y`
+    pub fn offset(&self, y: u32) -> f32 {
+        self.offsets[y as usize % self.offsets.len()]
+    }
+}
+
+/// Mean difference of every row with the same color rows around it, in the
+/// shadows, and how many samples it comes from, with `offsets` taken off
+/// the rows of their period
+fn row_deviations(file: &RawImage, offsets: &[f64]) -> Vec<(f64, usize)> {
+    let (width, height) = (file.width as usize, file.height as usize);
+    let black = file.black_level as f64;
+    let shadows = black + (file.white_level as f64 - black) * SHADOWS;
+    let reach = 2 * REFERENCE_ROWS;
+    let value =
+        |x: usize, y: usize| file.get_pixel(x as u32, y as u32) as f64 - offsets[y % offsets.len()];
+
+    (0..height)
+        .into_par_iter()
+        .map(|y| {
+            if y < reach || y + reach >= height {
+                return (0.0, 0);
+            }
+
+            let mut sum = 0.0;
+            let mut count = 0;
+            let mut reference = [0.0; 2 * REFERENCE_ROWS];
+            for x in (0..width).step_by(COLUMN_STEP) {
+                let v = value(x, y);
+                if v > shadows {
+                    continue;
+                }
+                for (i, r) in reference.iter_mut().enumerate() {
+                    let dy = 2 * (i % REFERENCE_ROWS + 1);
+                    let ry = if i < REFERENCE_ROWS { y - dy } else { y + dy };
+                    *r = value(x, ry);
+                }
+                // a median, so that the PDAF rows among them don't count
+                reference.sort_unstable_by(f64::total_cmp);
+                let median = (reference[REFERENCE_ROWS - 1] + reference[REFERENCE_ROWS]) / 2.0;
+                if median > shadows {
+                    continue;
+                }
+                sum += v - median;
+                count += 1;
+            }
+            (if count > 0 { sum / count as f64 } else { 0.0 }, count)
+        })
+        .collect()
+}
+
+/// Mean deviation, standard error and number of rows of every row of
+/// `period`
+fn fold(deviations: &[(f64, usize)], period: usize) -> Vec<(f64, f64, usize)> {
+    let mut classes = vec![Vec::new(); period];
+    for (y, &(deviation, count)) in deviations.iter().enumerate() {
+        if count > 0 {
+            classes[y % period].push(deviation);
+        }
+    }
+
+    classes
+        .into_iter()
+        .map(|rows| {
+            let n = rows.len();
+            if n < 2 {
+                return (0.0, f64::INFINITY, n);
+            }
+            let mean = rows.iter().sum::<f64>() / n as f64;
+            let variance = rows.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+            (mean, (variance / n as f64).sqrt(), n)
+        })
+        .collect()
+}
+
+/// Brings the rows compared with each other, the same color ones, to a
+/// median offset of 0: rows are only ever measured against the rows 2 apart,
+/// which leaves the level of every one of those sets open, and most of their
+/// rows aren't PDAF rows
+fn level(offsets: &mut [f64]) {
+    let period = offsets.len();
+    // with an odd period, the rows 2 apart go through both parities
+    let sets = if period.is_multiple_of(2) { 2 } else { 1 };
+    for set in 0..sets {
+        let mut values = offsets
+            .iter()
+            .skip(set)
+            .step_by(sets)
+            .copied()
+            .collect::<Vec<_>>();
+        values.sort_unstable_by(f64::total_cmp);
+        let median = values[values.len() / 2];
+        offsets
+            .iter_mut()
+            .skip(set)
+            .step_by(sets)
+            .for_each(|offset| *offset -= median);
+    }
+}
+
+/// Finds the PDAF rows of `file` from its shadows, None when the frame has
+/// no banding, or no shadows to tell.
+///
+/// Every row is compared with the median of the same color rows around it.
+/// The period of the rows that stand out is the one that explains most of
+/// the differences for the fewest rows, and the rows of that period off by
+/// more than `SIGNIFICANCE` standard errors are the PDAF rows.
+pub fn detect(file: &RawImage) -> Option<Banding> {
+    let deviations = row_deviations(file, &[0.0]);
+    let rows = deviations.iter().filter(|(_, count)| *count > 0).count();
+    if rows < 4 * MAX_PERIOD {
+        debug!(
+            "{}: not enough shadows to look for PDAF banding",
+            file.path.display()
+        );
+        return None;
+    }
+
+    let measured = deviations.iter().filter(|(_, count)| *count > 0);
+    let mean = measured.clone().map(|(d, _)| d).sum::<f64>() / rows as f64;
+    let noise = measured.map(|(d, _)| (d - mean).powi(2)).sum::<f64>() / rows as f64;
+
+    // BIC like: what a period explains, less what its extra rows cost
+    let score = |period: usize| {
+        let explained = fold(&deviations, period)
+            .iter()
+            .map(|&(m, _, n)| n as f64 * (m - mean).powi(2))
+            .sum::<f64>();
+        explained / noise.max(f64::MIN_POSITIVE) - period as f64 * (rows as f64).ln()
+    };
+    let period = (2..=MAX_PERIOD).max_by(|&a, &b| score(a).total_cmp(&score(b)))?;
+    if score(period) <= 0.0 {
+        return None;
+    }
+
+    let mut offsets = vec![0.0; period];
+    let mut deviations = deviations;
+    for pass in 0..PASSES {
+        if pass > 0 {
+            deviations = row_deviations(file, &offsets);
+        }
+        for (offset, (m, se, _)) in offsets.iter_mut().zip(fold(&deviations, period)) {
+            if m.abs() > SIGNIFICANCE * se {
+                *offset += m;
+            }
+        }
+        level(&mut offsets);
+    }
+
+    // what is left on the other rows of the period is below a DN
+    let pdaf_rows = offsets.iter().filter(|o| o.abs() >= 0.5).count();
+    if pdaf_rows == 0 || pdaf_rows == period {
+        return None;
+    }
+    let offsets = offsets.into_iter().map(|o| o as f32).collect::<Vec<_>>();
+
+    info!(
+        "{}: PDAF banding on {} rows every {}, up to {:.1} DN",
+        file.path.display(),
+        pdaf_rows,
+        period,
+        offsets.iter().map(|o| o.abs()).fold(0.0, f32::max)
+    );
+    Some(Banding { offsets })
+}
+
+/// Looks for PDAF banding in every frame, for `RawImage::sample` to take off
+pub fn fix(files: &mut [RawImage]) {
+    info!("looking for PDAF banding");
+    files
+        .par_iter_mut()
+        .for_each(|file| file.banding = detect(file));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::patterns::Shot;
+    use crate::Pixels;
+
+    const WIDTH: u32 = 400;
+    const HEIGHT: u32 = 600;
+    const BLACK: u16 = 512;
+
+    /// A dark frame of read noise around the black level, `planted(y)` DN
+    /// added to every sample of row `y`
+    fn dark_frame(planted: impl Fn(u32) -> f64) -> RawImage<'static> {
+        let exif = crate::exif::fields(
+            format!(
+                "Image Width : {}\nImage Height : {}\nBlack Level : {}\nWhite Level : 16383\n",
+                WIDTH, HEIGHT, BLACK
+            )
+            .as_bytes(),
+        );
+
+        // xorshift, a uniform sum for about 3 DN of noise
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut uniform = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let samples = (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |_| y))
+            .map(|y| {
+                let noise = (0..4).map(|_| uniform()).sum::<f64>() - 2.0;
+                (BLACK as f64 + 5.0 * noise + planted(y)).round() as u16
+            })
+            .collect();
+
+        let shot = Shot {
+            group: 0,
+            offset: (1, 1),
+        };
+        RawImage::new(Path::new("dark.tiff"), exif, shot, Pixels::Owned(samples))
+    }
+
+    #[test]
+    fn finds_planted_rows() {
+        let planted = |y: u32| match y % 12 {
+            3 => 6.0,
+            8 => -4.0,
+            _ => 0.0,
+        };
+        let banding = detect(&dark_frame(planted)).expect("banding");
+
+        assert_eq!(banding.offsets.len(), 12);
+        for y in 0..12 {
+            assert!(
+                (banding.offset(y) as f64 - planted(y)).abs() < 0.5,
+                "row {} is off by {} instead of {}",
+                y,
+                banding.offset(y),
+                planted(y)
+            );
+        }
+    }
+
+    #[test]
+    fn clean_frames_have_no_banding() {
+        assert!(detect(&dark_frame(|_| 0.0)).is_none());
+    }
+}
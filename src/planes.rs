@@ -0,0 +1,98 @@
+use rayon::prelude::*;
+
+use crate::context::MergeContext;
+use crate::{RgbImage16, RggbImage16};
+
+/// The merge while it is being worked on: one plane per channel, in f32 so
+/// that sums of u16 samples stay exact and no pass clips what the next one
+/// could use. Only `encode` brings the values back to u16.
+pub struct Planes<const N: usize = 3> {
+    pub width: u32,
+    pub height: u32,
+    pub channels: [Vec<f32>; N],
+}
+
+impl<const N: usize> Planes<N> {
+    pub fn new(width: u32, height: u32, context: &mut MergeContext) -> Self {
+        let len = width as usize * height as usize;
+        Self {
+            width,
+            height,
+            channels: std::array::from_fn(|_| context.floats(len)),
+        }
+    }
+
+    /// Runs `f` on every value, along with the index of its channel
+    pub fn map(&mut self, f: impl Fn(usize, f32) -> f32 + Sync) {
+        for (c, channel) in self.channels.iter_mut().enumerate() {
+            channel.par_iter_mut().for_each(|v| *v = f(c, *v));
+        }
+    }
+
+    /// Sets every pixel to what `f` gives for its `x`, `y`, row by row in parallel
+    pub fn fill(&mut self, f: impl Fn(u32, u32) -> [f32; N] + Sync) {
+        let width = self.width as usize;
+
+        let mut channels = self
+            .channels
+            .iter_mut()
+            .map(|channel| channel.chunks_mut(width))
+            .collect::<Vec<_>>();
+        let rows = (0..self.height)
+            .map(|_| {
+                channels
+                    .iter_mut()
+                    .map(|rows| rows.next().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        rows.into_par_iter().enumerate().for_each(|(y, mut row)| {
+            for x in 0..width {
+                let values = f(x as u32, y as u32);
+                for (channel, v) in row.iter_mut().zip(values) {
+                    channel[x] = v;
+                }
+            }
+        });
+    }
+
+    /// Drops the first `left` columns and `top` rows, in place
+    pub fn crop(&mut self, left: u32, top: u32) {
+        let (width, height) = (self.width - left, self.height - top);
+        for channel in &mut self.channels {
+            for y in 0..height as usize {
+                let start = (y + top as usize) * self.width as usize + left as usize;
+                channel.copy_within(start..start + width as usize, y * width as usize);
+            }
+            channel.truncate(width as usize * height as usize);
+        }
+        (self.width, self.height) = (width, height);
+    }
+
+    /// Interleaved u16 samples, rounded and clamped
+    fn interleave(&self, context: &mut MergeContext) -> Vec<u16> {
+        let mut samples = context.samples(self.channels[0].len() * N);
+        samples
+            .par_chunks_mut(N)
+            .enumerate()
+            .for_each(|(i, pixel)| {
+                for (sample, channel) in pixel.iter_mut().zip(&self.channels) {
+                    *sample = channel[i].round().clamp(0.0, u16::MAX as f32) as u16;
+                }
+            });
+        samples
+    }
+}
+
+impl Planes {
+    pub fn encode(&self, context: &mut MergeContext) -> RgbImage16 {
+        RgbImage16::from_raw(self.width, self.height, self.interleave(context)).unwrap()
+    }
+}
+
+impl Planes<4> {
+    pub fn encode(&self, context: &mut MergeContext) -> RggbImage16 {
+        RggbImage16::from_raw(self.width, self.height, self.interleave(context)).unwrap()
+    }
+}
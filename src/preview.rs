@@ -0,0 +1,88 @@
+use std::path::Path;
+use std::process::Command;
+
+use log::{info, warn};
+
+use crate::RgbImage16;
+
+/// sRGB transfer function, so that the preview looks right on a color managed
+/// display rather than merely brighter
+fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Small 8 bit sRGB rendition of a merge, meant for a quick look
+/// rather than for editing.
+///
+/// Every channel is stretched to its own maximum, which doubles as a crude
+/// white balance for the raw colors, unless the merge is `balanced` already,
+/// e.g. by --temp, and they are all stretched alike. A `max_size` of 0 keeps
+/// every merged pixel.
+pub fn preview(imgbuf: &RgbImage16, max_size: u32, balanced: bool) -> image::RgbImage {
+    let longest = imgbuf.width().max(imgbuf.height());
+    let small = if max_size == 0 || longest <= max_size {
+        imgbuf.clone()
+    } else {
+        let scale = longest as f32 / max_size as f32;
+        image::imageops::resize(
+            imgbuf,
+            ((imgbuf.width() as f32 / scale) as u32).max(1),
+            ((imgbuf.height() as f32 / scale) as u32).max(1),
+            image::imageops::FilterType::Triangle,
+        )
+    };
+
+    let mut white = [1u16; 3];
+    for px in small.pixels() {
+        for (w, v) in white.iter_mut().zip(px.0) {
+            *w = (*w).max(v);
+        }
+    }
+    if balanced {
+        white = [*white.iter().max().unwrap(); 3];
+    }
+
+    image::ImageBuffer::from_fn(small.width(), small.height(), |x, y| {
+        let px = small.get_pixel(x, y);
+        image::Rgb(std::array::from_fn(|c| {
+            let v = px.0[c] as f32 / white[c] as f32;
+            (srgb_encode(v) * 255.0).round() as u8
+        }))
+    })
+}
+
+/// Opens `path` with the default image viewer of the desktop
+fn open_viewer(path: &Path) -> std::io::Result<std::process::Child> {
+    if cfg!(target_os = "macos") {
+        Command::new("open").arg(path).spawn()
+    } else if cfg!(target_os = "windows") {
+        Command::new("cmd")
+            .args(["/C", "start", ""])
+            .arg(path)
+            .spawn()
+    } else {
+        Command::new("xdg-open").arg(path).spawn()
+    }
+}
+
+/// Writes a preview of the merge to the temporary directory and shows it in
+/// the desktop image viewer, which takes care of panning and zooming; there
+/// is no window of our own
+pub fn show(imgbuf: &RgbImage16, max_size: u32, balanced: bool) {
+    let path = std::env::temp_dir().join(format!("psmsmerge-preview-{}.png", std::process::id()));
+
+    preview(imgbuf, max_size, balanced).save(&path).unwrap();
+    info!("showing {}", path.display());
+
+    if let Err(e) = open_viewer(&path) {
+        warn!(
+            "could not open an image viewer ({}), the preview is in {}",
+            e,
+            path.display()
+        );
+    }
+}
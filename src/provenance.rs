@@ -0,0 +1,338 @@
+use std::fs::File;
+use std::io::Read;
+
+use log::{info, warn};
+use rayon::prelude::*;
+
+use crate::cli::MergeOptions;
+use crate::exif::ExifData;
+use crate::{Merge, Pixels};
+
+/// namespace of the provenance properties in the XMP packet
+const NAMESPACE: &str = "https://github.com/Kezii/PixelShiftMultiShootMerge/ns/provenance/1.0/";
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, fed in any number of pieces
+struct Sha256 {
+    state: [u32; 8],
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+
+        if !self.block.is_empty() {
+            let missing = (64 - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..missing]);
+            data = &data[missing..];
+            if self.block.len() < 64 {
+                return;
+            }
+            Self::compress(&mut self.state, &self.block);
+            self.block.clear();
+        }
+
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            Self::compress(&mut self.state, block);
+        }
+        self.block.extend_from_slice(blocks.remainder());
+    }
+
+    fn hex(mut self) -> String {
+        let bits = self.len * 8;
+        let mut padding = vec![0x80];
+        padding.resize((119 - self.len % 64) as usize % 64 + 1, 0);
+        padding.extend_from_slice(&bits.to_be_bytes());
+        self.update(&padding);
+
+        self.state.iter().map(|v| format!("{:08x}", v)).collect()
+    }
+}
+
+/// SHA-256 of a whole file
+fn hash_file(file: &mut File) -> std::io::Result<String> {
+    let mut sha = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(sha.hex()),
+            n => sha.update(&buffer[..n]),
+        }
+    }
+}
+
+/// `value` escaped for an attribute or an element. XML 1.0 has no room for
+/// the other control characters, even escaped, they become U+FFFD.
+fn xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push_str(&format!("&#x{:x};", c as u32)),
+            '\u{fffe}' | '\u{ffff}' => escaped.push(char::REPLACEMENT_CHARACTER),
+            c if c.is_ascii_control() && c != '\u{7f}' => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A frame as the record names it, and what of it was hashed
+struct Source {
+    path: String,
+    sequence_number: u32,
+    /// `FileSHA256` for raws read from the disk, `RawDataSHA256` of the
+    /// samples for frames that came out of an archive, off the network or
+    /// out of a decoded mosaic
+    hash: (&'static str, String),
+}
+
+fn source(file: &crate::RawImage) -> Source {
+    let (path, hash) = match &file.pixels {
+        Pixels::Mapped(_) => {
+            let path = std::fs::canonicalize(&file.path).unwrap_or_else(|_| file.path.clone());
+            let hash = File::open(&file.path)
+                .and_then(|mut f| hash_file(&mut f))
+                .unwrap_or_else(|e| {
+                    warn!("can't hash {}: {}", file.path.display(), e);
+                    String::new()
+                });
+            (path, ("FileSHA256", hash))
+        }
+        Pixels::Owned(samples) => {
+            let mut sha = Sha256::new();
+            for chunk in samples.chunks(1 << 16) {
+                let bytes = chunk
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect::<Vec<_>>();
+                sha.update(&bytes);
+            }
+            (file.path.clone(), ("RawDataSHA256", sha.hex()))
+        }
+    };
+
+    Source {
+        path: path.to_string_lossy().into_owned(),
+        sequence_number: file.sequence_number,
+        hash,
+    }
+}
+
+/// How the pixels were made, in a word
+fn mode(options: &MergeOptions) -> String {
+    use clap::ValueEnum;
+
+    if options.fast {
+        "fast".to_string()
+    } else {
+        let reconstruct = options.reconstruct.to_possible_value().unwrap();
+        reconstruct.get_name().to_string()
+    }
+}
+
+/// XMP packet recording where `merge` came from: the version of the tool,
+/// its arguments, the merge mode and the camera, and a hash of every frame,
+/// so that an archived merge can be traced back to its sources and settings
+pub fn xmp(merge: &Merge, options: &MergeOptions) -> String {
+    use clap::ValueEnum;
+
+    info!(
+        "hashing {} frames for the provenance record",
+        merge.files.len()
+    );
+    let mut sources = merge.files.par_iter().map(source).collect::<Vec<_>>();
+    sources.sort_by_key(|source| source.sequence_number);
+    let exif = |field: fn(&ExifData) -> &Option<String>| {
+        merge
+            .files
+            .iter()
+            .find_map(|file| field(&file.exif).clone())
+    };
+
+    let mut properties = vec![
+        (
+            "Software",
+            concat!("psmsmerge ", env!("CARGO_PKG_VERSION")).to_string(),
+        ),
+        ("ShiftPattern", merge.pattern.name.clone()),
+        ("Mode", mode(options)),
+        (
+            "Green",
+            options
+                .green
+                .to_possible_value()
+                .unwrap()
+                .get_name()
+                .to_string(),
+        ),
+    ];
+    if let Some(model) = exif(|exif| &exif.model) {
+        properties.push(("CameraModel", model));
+    }
+    if let Some(serial) = exif(|exif| &exif.serial_number) {
+        properties.push(("CameraSerialNumber", serial));
+    }
+
+    let mut packet = String::new();
+    packet += "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n";
+    packet += "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n";
+    packet += " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n";
+    packet += &format!(
+        "  <rdf:Description rdf:about=\"\" xmlns:psms=\"{}\">\n",
+        NAMESPACE
+    );
+    for (name, value) in properties {
+        packet += &format!("   <psms:{0}>{1}</psms:{0}>\n", name, xml(&value));
+    }
+
+    packet += "   <psms:Arguments>\n    <rdf:Seq>\n";
+    for arg in std::env::args_os() {
+        packet += &format!("     <rdf:li>{}</rdf:li>\n", xml(&arg.to_string_lossy()));
+    }
+    packet += "    </rdf:Seq>\n   </psms:Arguments>\n";
+
+    packet += "   <psms:Sources>\n    <rdf:Seq>\n";
+    for source in sources {
+        packet += "     <rdf:li rdf:parseType=\"Resource\">\n";
+        packet += &format!("      <psms:Path>{}</psms:Path>\n", xml(&source.path));
+        packet += &format!(
+            "      <psms:SequenceNumber>{}</psms:SequenceNumber>\n",
+            source.sequence_number
+        );
+        packet += &format!(
+            "      <psms:{0}>{1}</psms:{0}>\n",
+            source.hash.0, source.hash.1
+        );
+        packet += "     </rdf:li>\n";
+    }
+    packet += "    </rdf:Seq>\n   </psms:Sources>\n";
+
+    packet += "  </rdf:Description>\n </rdf:RDF>\n</x:xmpmeta>\n";
+    packet += "<?xpacket end=\"w\"?>";
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(pieces: &[&[u8]]) -> String {
+        let mut sha = Sha256::new();
+        for piece in pieces {
+            sha.update(piece);
+        }
+        sha.hex()
+    }
+
+    /// the examples of FIPS 180-4
+    #[test]
+    fn known_answers() {
+        assert_eq!(
+            sha256(&[b""]),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(&[b"abc"]),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"]),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // a million a, in pieces straddling the blocks every which way
+        let a = vec![b'a'; 1_000_000];
+        let mut pieces = Vec::new();
+        let mut rest = &a[..];
+        for len in [1, 63, 64, 65, 127, 3, 4096, 55, 56, 57].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (piece, tail) = rest.split_at((*len).min(rest.len()));
+            pieces.push(piece);
+            rest = tail;
+        }
+        assert_eq!(
+            sha256(&pieces),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn xml_escapes() {
+        assert_eq!(xml("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+        assert_eq!(xml("tab\tline\n"), "tab&#x9;line&#xa;");
+        assert_eq!(xml("bell\u{7}nul\0"), "bell\u{fffd}nul\u{fffd}");
+        assert_eq!(xml("DSC0001 é.ARW"), "DSC0001 é.ARW");
+    }
+}
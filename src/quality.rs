@@ -0,0 +1,346 @@
+use log::info;
+use rayon::prelude::*;
+
+use crate::calibration::Calibration;
+use crate::demosaic::demosaic;
+use crate::{downscale, RawImage, RgbImage16};
+
+/// the report splits the image in a REGIONS x REGIONS grid
+const REGIONS: u32 = 4;
+
+const SSIM_BLOCK: u32 = 8;
+
+/// side of the windows searched for a slanted edge
+const EDGE_ROI: u32 = 48;
+
+/// ESF oversampling, in bins per pixel
+const ESF_BINS_PER_PIXEL: f32 = 4.0;
+const ESF_RADIUS: f32 = 8.0;
+
+/// Brightness of a pixel, both images being compared are scaled the same way
+fn luma(imgbuf: &RgbImage16, x: u32, y: u32) -> f32 {
+    imgbuf
+        .get_pixel(x, y)
+        .0
+        .iter()
+        .map(|&v| v as f32)
+        .sum::<f32>()
+        / 3.0
+}
+
+struct RegionScore {
+    psnr: f32,
+    ssim: f32,
+}
+
+fn compare_region(a: &RgbImage16, b: &RgbImage16, x0: u32, y0: u32, w: u32, h: u32) -> RegionScore {
+    let peak = u16::MAX as f32;
+    let c1 = (0.01 * peak).powi(2);
+    let c2 = (0.03 * peak).powi(2);
+
+    let mut squared_error = 0.0f64;
+    let mut ssim_sum = 0.0f64;
+    let mut blocks = 0u32;
+
+    for by in (y0..y0 + h).step_by(SSIM_BLOCK as usize) {
+        for bx in (x0..x0 + w).step_by(SSIM_BLOCK as usize) {
+            let xs = bx..(bx + SSIM_BLOCK).min(x0 + w);
+            let ys = by..(by + SSIM_BLOCK).min(y0 + h);
+
+            let samples = ys
+                .flat_map(|y| xs.clone().map(move |x| (x, y)))
+                .map(|(x, y)| (luma(a, x, y), luma(b, x, y)))
+                .collect::<Vec<_>>();
+
+            let n = samples.len() as f32;
+            let mean_a = samples.iter().map(|s| s.0).sum::<f32>() / n;
+            let mean_b = samples.iter().map(|s| s.1).sum::<f32>() / n;
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for &(va, vb) in &samples {
+                var_a += (va - mean_a).powi(2) / n;
+                var_b += (vb - mean_b).powi(2) / n;
+                cov += (va - mean_a) * (vb - mean_b) / n;
+                squared_error += ((va - vb) as f64).powi(2);
+            }
+
+            ssim_sum += (((2.0 * mean_a * mean_b + c1) * (2.0 * cov + c2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + c1) * (var_a + var_b + c2)))
+                as f64;
+            blocks += 1;
+        }
+    }
+
+    let mse = squared_error / (w * h) as f64;
+
+    RegionScore {
+        psnr: (10.0 * ((peak as f64).powi(2) / mse.max(1e-9)).log10()) as f32,
+        ssim: (ssim_sum / blocks.max(1) as f64) as f32,
+    }
+}
+
+/// Straight edge fitted as `x = slope * y + offset` inside a window
+struct EdgeFit {
+    slope: f32,
+    offset: f32,
+    contrast: f32,
+}
+
+/// Looks for a single, slightly slanted, near vertical edge in a `size` wide
+/// window. `sample` reads the window, transpose it to look for horizontal edges
+fn fit_edge(sample: &dyn Fn(u32, u32) -> f32, size: u32) -> Option<EdgeFit> {
+    let mut centroids = Vec::with_capacity(size as usize);
+
+    for y in 0..size {
+        let (mut weight, mut moment) = (0.0, 0.0);
+        for x in 1..size {
+            let d = (sample(x, y) - sample(x - 1, y)).abs();
+            weight += d;
+            moment += d * (x as f32 - 0.5);
+        }
+        if weight <= 0.0 {
+            return None;
+        }
+        centroids.push((y as f32, moment / weight));
+    }
+
+    // least squares line through the per row edge positions
+    let n = centroids.len() as f32;
+    let mean_y = centroids.iter().map(|c| c.0).sum::<f32>() / n;
+    let mean_x = centroids.iter().map(|c| c.1).sum::<f32>() / n;
+    let var_y = centroids
+        .iter()
+        .map(|c| (c.0 - mean_y).powi(2))
+        .sum::<f32>();
+    let cov = centroids
+        .iter()
+        .map(|c| (c.0 - mean_y) * (c.1 - mean_x))
+        .sum::<f32>();
+    let slope = cov / var_y;
+    let offset = mean_x - slope * mean_y;
+
+    let residual = (centroids
+        .iter()
+        .map(|c| (c.1 - (slope * c.0 + offset)).powi(2))
+        .sum::<f32>()
+        / n)
+        .sqrt();
+
+    // a few degrees of slant are needed to oversample the edge, too much of it
+    // and the horizontal profile isn't a good estimate anymore
+    let margin = ESF_RADIUS;
+    let inside = |y: f32| {
+        let x = slope * y + offset;
+        x > margin && x < size as f32 - margin
+    };
+    if !(0.03..0.35).contains(&slope.abs())
+        || residual > 0.5
+        || !inside(0.0)
+        || !inside(size as f32)
+    {
+        return None;
+    }
+
+    let side = |right: bool| {
+        let mut sum = 0.0;
+        for y in 0..size {
+            let edge = slope * y as f32 + offset;
+            let x = if right { edge + margin } else { edge - margin };
+            sum += sample(x.clamp(0.0, size as f32 - 1.0) as u32, y);
+        }
+        sum / size as f32
+    };
+
+    Some(EdgeFit {
+        slope,
+        offset,
+        contrast: (side(true) - side(false)).abs(),
+    })
+}
+
+/// Spatial frequency, in cycles per pixel, at which the contrast of the edge
+/// drops to half
+fn mtf50(sample: &dyn Fn(u32, u32) -> f32, size: u32, edge: &EdgeFit) -> Option<f32> {
+    let bins = (2.0 * ESF_RADIUS * ESF_BINS_PER_PIXEL) as usize;
+    let mut sum = vec![0.0f32; bins];
+    let mut count = vec![0u32; bins];
+
+    for y in 0..size {
+        let center = edge.slope * y as f32 + edge.offset;
+        for x in 0..size {
+            // distance along the normal of the edge
+            let d = (x as f32 - center) / (1.0 + edge.slope.powi(2)).sqrt();
+            let bin = ((d + ESF_RADIUS) * ESF_BINS_PER_PIXEL).floor();
+            if bin >= 0.0 && (bin as usize) < bins {
+                sum[bin as usize] += sample(x, y);
+                count[bin as usize] += 1;
+            }
+        }
+    }
+
+    if count.contains(&0) {
+        return None;
+    }
+
+    let esf = sum
+        .iter()
+        .zip(&count)
+        .map(|(s, &c)| s / c as f32)
+        .collect::<Vec<_>>();
+
+    let n = esf.len() - 1;
+    let lsf = (0..n)
+        .map(|i| {
+            let hamming =
+                0.54 - 0.46 * (2.0 * std::f32::consts::PI * i as f32 / (n - 1) as f32).cos();
+            (esf[i + 1] - esf[i]) * hamming
+        })
+        .collect::<Vec<_>>();
+
+    let mtf = (0..n / 2)
+        .map(|k| {
+            let (mut re, mut im) = (0.0f32, 0.0f32);
+            for (i, v) in lsf.iter().enumerate() {
+                let phase = -2.0 * std::f32::consts::PI * (k * i) as f32 / n as f32;
+                re += v * phase.cos();
+                im += v * phase.sin();
+            }
+            (re * re + im * im).sqrt()
+        })
+        .collect::<Vec<_>>();
+
+    if mtf[0] <= 0.0 {
+        return None;
+    }
+
+    let frequency = |k: f32| k * ESF_BINS_PER_PIXEL / n as f32;
+
+    for k in 1..mtf.len() {
+        let (prev, cur) = (mtf[k - 1] / mtf[0], mtf[k] / mtf[0]);
+        if cur < 0.5 {
+            let t = (prev - 0.5) / (prev - cur);
+            return Some(frequency(k as f32 - 1.0 + t));
+        }
+    }
+
+    None
+}
+
+/// Window with the strongest clean slanted edge of the image, if any
+fn find_edge(imgbuf: &RgbImage16) -> Option<(u32, u32, bool, EdgeFit)> {
+    let cols = imgbuf.width() / EDGE_ROI;
+    let rows = imgbuf.height() / EDGE_ROI;
+
+    (0..cols * rows)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let (x0, y0) = ((i % cols) * EDGE_ROI, (i / cols) * EDGE_ROI);
+            [false, true].into_iter().filter_map(move |transposed| {
+                let sample = roi_sampler(imgbuf, x0, y0, 1, transposed);
+                fit_edge(&sample, EDGE_ROI).map(|fit| (x0, y0, transposed, fit))
+            })
+        })
+        .max_by(|a, b| a.3.contrast.total_cmp(&b.3.contrast))
+}
+
+fn roi_sampler(
+    imgbuf: &RgbImage16,
+    x0: u32,
+    y0: u32,
+    scale: u32,
+    transposed: bool,
+) -> impl Fn(u32, u32) -> f32 + '_ {
+    move |x, y| {
+        let (x, y) = if transposed { (y, x) } else { (x, y) };
+        luma(imgbuf, x0 * scale + x, y0 * scale + y)
+    }
+}
+
+/// Compares the merge with a plain demosaic of the first frame of the
+/// sequence, and reports whether pixel shift was worth it for this scene
+pub fn report(
+    files: &[RawImage],
+    merged: &RgbImage16,
+    calibration: Option<&Calibration>,
+    channel_samples: [u32; 3],
+) {
+    let frame = files
+        .iter()
+        .find(|file| file.sequence_number == 1)
+        .unwrap_or(&files[0]);
+
+    info!(
+        "quality report against a demosaic of {}",
+        frame.path.display()
+    );
+    let single = demosaic(frame, calibration, channel_samples);
+
+    // 16 shots merges are compared at the sensor resolution
+    let scale = merged.width() / single.width();
+    let merged_native = if scale > 1 {
+        downscale(merged, scale)
+    } else {
+        merged.clone()
+    };
+
+    let (w, h) = (single.width() / REGIONS, single.height() / REGIONS);
+    let scores = (0..REGIONS * REGIONS)
+        .into_par_iter()
+        .map(|i| {
+            let (x0, y0) = ((i % REGIONS) * w, (i / REGIONS) * h);
+            compare_region(&single, &merged_native, x0, y0, w, h)
+        })
+        .collect::<Vec<_>>();
+
+    info!("per region PSNR (dB) / SSIM:");
+    for row in scores.chunks(REGIONS as usize) {
+        info!(
+            "  {}",
+            row.iter()
+                .map(|s| format!("{:5.1} / {:.3}", s.psnr, s.ssim))
+                .collect::<Vec<_>>()
+                .join("   ")
+        );
+    }
+
+    let worst = scores.iter().map(|s| s.ssim).fold(f32::MAX, f32::min);
+    if worst < 0.8 {
+        info!(
+            "lowest region SSIM is {:.3}, something likely moved during the sequence",
+            worst
+        );
+    }
+
+    let gain = find_edge(&single).and_then(|(x0, y0, transposed, fit)| {
+        let single_mtf = mtf50(&roi_sampler(&single, x0, y0, 1, transposed), EDGE_ROI, &fit)?;
+
+        let merged_fit = EdgeFit {
+            slope: fit.slope,
+            offset: fit.offset * scale as f32,
+            contrast: fit.contrast,
+        };
+        let merged_mtf = mtf50(
+            &roi_sampler(merged, x0, y0, scale, transposed),
+            EDGE_ROI * scale,
+            &merged_fit,
+        )? * scale as f32;
+
+        info!(
+            "slanted edge at {},{}: MTF50 {:.3} cycles/pixel single frame, {:.3} merged",
+            x0, y0, single_mtf, merged_mtf
+        );
+
+        Some(merged_mtf / single_mtf)
+    });
+
+    match gain {
+        Some(gain) => {
+            info!("estimated resolution gain: {:.2}x", gain);
+            if gain > 1.1 {
+                info!("pixel shift delivered a resolution benefit for this scene");
+            } else {
+                info!("pixel shift delivered no measurable resolution benefit for this scene");
+            }
+        }
+        None => info!("no slanted edge target found, resolution gain not measured"),
+    }
+}
@@ -0,0 +1,274 @@
+use log::info;
+
+use crate::calibration::Calibration;
+use crate::context::MergeContext;
+use crate::patterns::ShiftPattern;
+use crate::planes::Planes;
+use crate::{bayer_pattern, Color, GreenMode, RawImage};
+
+/// How the output pixels are made from the groups of a multi group pattern
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Reconstruct {
+    /// every output pixel is the 4 shots merge of the group the pattern puts
+    /// there
+    #[default]
+    Interleave,
+    /// experimental: least squares fit of the output pixels to every sample
+    /// covering them, see `mlr`
+    Mlr,
+}
+
+/// weight of the differences between neighbouring output pixels against the
+/// fit to the samples, what keeps the underdetermined fit from ringing
+const SMOOTHNESS: f64 = 0.02;
+
+/// keeps the normal equations invertible whatever the samples
+const RIDGE: f64 = 1e-9;
+
+/// Where a sample used for the output pixels of a sensor pixel comes from:
+/// its frame, and the sensor pixel, -1 or 0 from it on each axis, whose
+/// sample it is in that frame
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    frame: usize,
+    dx: i32,
+    dy: i32,
+}
+
+/// The least squares solution for the output pixels of one sensor pixel of
+/// a given CFA parity, in one channel: a weight per measurement for each of
+/// its scale x scale output pixels
+#[derive(Debug)]
+struct Operator {
+    measurements: Vec<Measurement>,
+    /// by output pixel in the sensor pixel, row by row
+    weights: Vec<Vec<f32>>,
+}
+
+/// Solves `a x = b` for the `m` columns of `b`, `a` being `n` x `n`, by
+/// Gauss-Jordan elimination with partial pivoting
+fn solve(mut a: Vec<f64>, mut b: Vec<f64>, n: usize, m: usize) -> Vec<f64> {
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i * n + col].abs().total_cmp(&a[j * n + col].abs()))
+            .unwrap();
+        for k in 0..n {
+            a.swap(col * n + k, pivot * n + k);
+        }
+        for k in 0..m {
+            b.swap(col * m + k, pivot * m + k);
+        }
+
+        let p = a[col * n + col];
+        for k in 0..n {
+            a[col * n + k] /= p;
+        }
+        for k in 0..m {
+            b[col * m + k] /= p;
+        }
+
+        for row in (0..n).filter(|&row| row != col) {
+            let f = a[row * n + col];
+            if f == 0.0 {
+                continue;
+            }
+            for k in 0..n {
+                a[row * n + k] -= f * a[col * n + k];
+            }
+            for k in 0..m {
+                b[row * m + k] -= f * b[col * m + k];
+            }
+        }
+    }
+    b
+}
+
+/// Fits the output pixels of a sensor pixel at CFA parity `parity` in
+/// `channel`, modelling every sample as the mean of the output pixels its
+/// sensor pixel covers
+fn operator(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    parity: (u32, u32),
+    channel: Color,
+) -> Operator {
+    let s = pattern.scale as i32;
+    // all the samples overlapping the sensor pixel lie in this window of
+    // output pixels, starting s - 1 before it
+    let n = (3 * s - 2) as usize;
+    let unknowns = n * n;
+
+    let mut measurements = Vec::new();
+    let mut rows = Vec::new();
+    for (frame, file) in files.iter().enumerate() {
+        let (gx, gy) = pattern.groups[file.group as usize];
+        let (gx, gy) = (gx as i32, gy as i32);
+        let (oy, ox) = (file.offset.0 as i32, file.offset.1 as i32);
+
+        for dy in -1..=0 {
+            for dx in -1..=0 {
+                // the sensor pixel before only reaches in for groups shifted
+                // past it
+                if (dx < 0 && gx == 0) || (dy < 0 && gy == 0) {
+                    continue;
+                }
+                let sx = (parity.0 as i32 + dx - ox).rem_euclid(2) as u32;
+                let sy = (parity.1 as i32 + dy - oy).rem_euclid(2) as u32;
+                if bayer_pattern(sx + file.cfa_phase.0, sy + file.cfa_phase.1) != channel {
+                    continue;
+                }
+
+                let left = (s * dx + gx + s - 1) as usize;
+                let top = (s * dy + gy + s - 1) as usize;
+                let mut row = vec![0.0; unknowns];
+                for y in top..top + s as usize {
+                    for x in left..left + s as usize {
+                        row[y * n + x] = 1.0 / (s * s) as f64;
+                    }
+                }
+
+                measurements.push(Measurement { frame, dx, dy });
+                rows.push(row);
+            }
+        }
+    }
+
+    // normal equations, the differences of the neighbours on top
+    let m = measurements.len();
+    let mut ata = vec![0.0; unknowns * unknowns];
+    let mut at = vec![0.0; unknowns * m];
+    for (j, row) in rows.iter().enumerate() {
+        for a in 0..unknowns {
+            at[a * m + j] = row[a];
+            for b in 0..unknowns {
+                ata[a * unknowns + b] += row[a] * row[b];
+            }
+        }
+    }
+    for y in 0..n {
+        for x in 0..n {
+            let i = y * n + x;
+            ata[i * unknowns + i] += RIDGE;
+            for j in [(x + 1 < n).then(|| i + 1), (y + 1 < n).then(|| i + n)]
+                .into_iter()
+                .flatten()
+            {
+                ata[i * unknowns + i] += SMOOTHNESS;
+                ata[j * unknowns + j] += SMOOTHNESS;
+                ata[i * unknowns + j] -= SMOOTHNESS;
+                ata[j * unknowns + i] -= SMOOTHNESS;
+            }
+        }
+    }
+
+    let solution = solve(ata, at, unknowns, m);
+
+    let first = (s - 1) as usize;
+    let weights = (first..first + s as usize)
+        .flat_map(|y| (first..first + s as usize).map(move |x| y * n + x))
+        .map(|i| {
+            solution[i * m..(i + 1) * m]
+                .iter()
+                .map(|&w| w as f32)
+                .collect()
+        })
+        .collect();
+
+    Operator {
+        measurements,
+        weights,
+    }
+}
+
+/// Sample of `measurement` for the sensor pixel `x`, `y`, the frame edges
+/// repeated past them
+fn sample(
+    files: &[RawImage],
+    measurement: &Measurement,
+    x: u32,
+    y: u32,
+    calibration: Option<&Calibration>,
+) -> f32 {
+    let file = &files[measurement.frame];
+    let sx =
+        (x as i64 + measurement.dx as i64 - file.offset.1 as i64).clamp(0, file.width as i64 - 1);
+    let sy =
+        (y as i64 + measurement.dy as i64 - file.offset.0 as i64).clamp(0, file.height as i64 - 1);
+    file.sample(sx as u32, sy as u32, calibration) - file.black_level as f32
+}
+
+/// Experimental reconstruction of multi group patterns, `--reconstruct mlr`.
+///
+/// Instead of taking every output pixel from one group, the output pixels
+/// under each sensor pixel are fitted to all the samples of all the frames
+/// that cover them, each sample being the mean of the output pixels under
+/// it, with a little smoothness to settle what the samples leave open. The
+/// geometry only depends on the CFA parity of the sensor pixel, so the fit
+/// is solved once up front for every parity and channel, and each output
+/// pixel only costs a weighted sum of samples.
+///
+/// The output pixels are half an output pixel up and left of those of the
+/// interleave, which stand for all of the samples they come from.
+pub fn mlr(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    green: GreenMode,
+    calibration: Option<&Calibration>,
+    context: &mut MergeContext,
+) -> Planes {
+    let scale = pattern.scale;
+    info!("fitting {} frames, scale {}", files.len(), scale);
+
+    let operators = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|parity| {
+        [Color::Red, Color::Green, Color::Blue]
+            .map(|channel| operator(files, pattern, parity, channel))
+    });
+
+    let black = files[0].black_level as f32;
+    let channel_samples = green.channel_samples().map(|n| n as f32);
+
+    let mut planes = Planes::new(files[0].width * scale, files[0].height * scale, context);
+    planes.fill(|x, y| {
+        let (sx, sy) = (x / scale, y / scale);
+        let parity = ((sy % 2) * 2 + sx % 2) as usize;
+        let k = ((y % scale) * scale + x % scale) as usize;
+
+        std::array::from_fn(|c| {
+            let operator = &operators[parity][c];
+            let value = operator
+                .measurements
+                .iter()
+                .zip(&operator.weights[k])
+                .map(|(measurement, &w)| w * sample(files, measurement, sx, sy, calibration))
+                .sum::<f32>();
+            (value + black) * channel_samples[c]
+        })
+    });
+
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solve_inverts_a_known_system() {
+        // a zero first pivot, so that the rows have to be swapped
+        let a = vec![0.0, 2.0, 1.0, 1.0, 1.0, 0.0, 3.0, 0.0, 4.0];
+        let x = [[1.0, -2.0], [0.5, 4.0], [-3.0, 0.25]];
+        let b = (0..6)
+            .map(|i| (0..3).map(|k| a[i / 2 * 3 + k] * x[k][i % 2]).sum())
+            .collect();
+
+        let solved = solve(a, b, 3, 2);
+        for (got, want) in solved.iter().zip(x.as_flattened()) {
+            assert!(
+                (got - want).abs() < 1e-12,
+                "{:?} instead of {:?}",
+                solved,
+                x
+            );
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use log::{debug, warn};
+
+use crate::exif::{read_exif_head, ExifData};
+use crate::failure::fail;
+use crate::{ifd, Pixels};
+
+/// the metadata is read through a cache of blocks this big
+const BLOCK: u64 = 64 * 1024;
+
+/// `https://`, `http://` or `s3://` inputs, as opposed to local files
+pub fn is_remote(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    ["https://", "http://", "s3://"]
+        .iter()
+        .any(|scheme| path.starts_with(scheme))
+}
+
+/// A value of a curl config file, quoted
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Percent encodes an S3 key, keeping its slashes
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The curl config for `path`: its URL, and for S3 the request signing with
+/// the usual AWS environment variables. Passed on stdin so that credentials
+/// never show up in the process list.
+fn curl_config(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let Some(object) = path.strip_prefix("s3://") else {
+        return format!("url = {}\n", quoted(&path));
+    };
+
+    let (bucket, key) = object
+        .split_once('/')
+        .unwrap_or_else(|| fail!(Usage, "{} has no key after the bucket", path));
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let region = env("AWS_REGION")
+        .or_else(|| env("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|| "us-east-1".to_string());
+
+    // other S3 implementations, e.g. MinIO, are addressed by path
+    let url = match env("AWS_ENDPOINT_URL") {
+        Some(endpoint) => format!(
+            "{}/{}/{}",
+            endpoint.trim_end_matches('/'),
+            bucket,
+            encode_key(key)
+        ),
+        None => format!(
+            "https://{}.s3.{}.amazonaws.com/{}",
+            bucket,
+            region,
+            encode_key(key)
+        ),
+    };
+
+    let mut config = format!("url = {}\n", quoted(&url));
+    match (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+        (Some(id), Some(secret)) => {
+            config += &format!(
+                "aws-sigv4 = {}\n",
+                quoted(&format!("aws:amz:{}:s3", region))
+            );
+            config += &format!("user = {}\n", quoted(&format!("{}:{}", id, secret)));
+            if let Some(token) = env("AWS_SESSION_TOKEN") {
+                config += &format!(
+                    "header = {}\n",
+                    quoted(&format!("x-amz-security-token: {}", token))
+                );
+            }
+        }
+        _ => debug!(
+            "no AWS credentials in the environment, {} is read unsigned",
+            path
+        ),
+    }
+    config
+}
+
+/// A raw on a web server or in an S3 bucket, of which only the byte ranges
+/// asked for are downloaded, with curl
+pub struct RemoteFile {
+    config: String,
+    name: String,
+    position: u64,
+    blocks: HashMap<u64, Vec<u8>>,
+}
+
+impl RemoteFile {
+    pub fn open(path: &Path) -> Self {
+        Self {
+            config: curl_config(path),
+            name: path.to_string_lossy().into_owned(),
+            position: 0,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// `len` bytes from `start`, fewer past the end of the file
+    pub fn range(&self, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut child = Command::new("curl")
+            .args(["-sSfL", "-K", "-", "-r"])
+            .arg(format!("{}-{}", start, start + len - 1))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(self.config.as_bytes())?;
+        let output = child.wait_with_output()?;
+
+        if !output.status.success() {
+            // 416, the range starts past the end
+            if output.status.code() == Some(22) && start > 0 {
+                return Ok(Vec::new());
+            }
+            return Err(io::Error::other(format!(
+                "curl failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let mut bytes = output.stdout;
+        if bytes.len() as u64 > len {
+            warn!(
+                "{} was downloaded whole, the server doesn't do ranged requests",
+                self.name
+            );
+            bytes.drain(..(start as usize).min(bytes.len()));
+            bytes.truncate(len as usize);
+        }
+        Ok(bytes)
+    }
+
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        if !self.blocks.contains_key(&index) {
+            let block = self.range(index * BLOCK, BLOCK)?;
+            self.blocks.insert(index, block);
+        }
+        Ok(&self.blocks[&index])
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        let block = self.block(position / BLOCK)?;
+        let start = ((position % BLOCK) as usize).min(block.len());
+        let len = buf.len().min(block.len() - start);
+        buf[..len].copy_from_slice(&block[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => {
+                self.position.checked_add_signed(delta).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "seek before the start")
+                })?
+            }
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the length of remote files is not known",
+                ))
+            }
+        };
+        Ok(self.position)
+    }
+}
+
+/// Reads the metadata and the raw strips of a remote frame, and nothing else
+/// of it: not the previews, not the maker notes past the strips
+pub fn read(path: &Path) -> (ExifData, Pixels) {
+    let fail = |e: io::Error| -> ! { fail!(Io, "can't read {}: {}", path.display(), e) };
+    let mut file = RemoteFile::open(path);
+
+    let raw = ifd::find_raw_from(path, &mut file);
+    let head = match &raw {
+        Some(raw) => file.range(0, raw.strip_offset as u64),
+        // no telling where the strips are, all of it is needed
+        None => file.range(0, u32::MAX as u64),
+    }
+    .unwrap_or_else(|e| fail(e));
+
+    let exif = read_exif_head(path, &head, raw);
+    let len = exif
+        .strip_byte_count
+        .map(u64::from)
+        .unwrap_or(exif.width as u64 * exif.height as u64 * 2);
+
+    let strips = if (exif.offset as usize) < head.len() {
+        head[exif.offset as usize..].to_vec()
+    } else {
+        file.range(exif.offset as u64, len)
+            .unwrap_or_else(|e| fail(e))
+    };
+
+    debug!(
+        "{}: {} bytes of metadata and {} of strips downloaded",
+        path.display(),
+        head.len(),
+        strips.len()
+    );
+
+    (exif, Pixels::copy(&strips, 0))
+}
@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use std::ffi::OsStr;
+
+use log::warn;
+
+use crate::exif::{ExifData, SEQUENCE_SETTINGS};
+use crate::failure::fail;
+
+/// Where the sequence numbers of the frames come from
+#[derive(Debug, Clone, Copy)]
+pub enum Numbering<'a> {
+    /// their metadata, checked against their names and timestamps
+    Metadata,
+    /// the order of their file names, --trust-filename-order
+    FileNames,
+    /// one for every file in turn, --sequence-numbers
+    Given(&'a [u32]),
+}
+
+impl<'a> Numbering<'a> {
+    pub fn new(trust_filename_order: bool, sequence_numbers: &'a [u32]) -> Self {
+        if !sequence_numbers.is_empty() {
+            Numbering::Given(sequence_numbers)
+        } else if trust_filename_order {
+            Numbering::FileNames
+        } else {
+            Numbering::Metadata
+        }
+    }
+}
+
+fn file_name(path: &Path) -> &OsStr {
+    path.file_name().unwrap_or(path.as_os_str())
+}
+
+/// Indices of `paths` sorted by file name
+fn by_file_name(paths: &[PathBuf]) -> Vec<usize> {
+    let mut order = (0..paths.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| file_name(&paths[i]));
+    order
+}
+
+/// Makes sure the frames belong to a single burst, and warns when their
+/// names or timestamps tell a different story than their sequence numbers.
+/// Files get renamed on import all the time, the metadata is what counts.
+pub fn check_order(paths: &[PathBuf], exifs: &[ExifData]) {
+    let mut bursts = exifs
+        .iter()
+        .filter_map(|exif| exif.burst_id.as_deref())
+        .collect::<Vec<_>>();
+    bursts.sort();
+    bursts.dedup();
+
+    if bursts.len() > 1 {
+        fail!(
+            MissingFrames,
+            "the files come from {} different bursts ({}), merge them separately",
+            bursts.len(),
+            bursts.join(", ")
+        );
+    }
+
+    let mut by_sequence = (0..paths.len()).collect::<Vec<_>>();
+    by_sequence.sort_by_key(|&i| exifs[i].sequence_number);
+
+    for pair in by_sequence.windows(2) {
+        let (a, b) = (&exifs[pair[0]], &exifs[pair[1]]);
+        if let (Some(time_a), Some(time_b)) = (&a.date_time, &b.date_time) {
+            // exiftool dates are "YYYY:MM:DD HH:MM:SS", they sort as strings
+            if time_b < time_a {
+                warn!(
+                    "{} is sequence number {} but was shot before {}, sequence number {}",
+                    paths[pair[1]].display(),
+                    b.sequence_number,
+                    paths[pair[0]].display(),
+                    a.sequence_number
+                );
+            }
+        }
+    }
+
+    if let Some((&a, &b)) = by_file_name(paths)
+        .iter()
+        .zip(&by_sequence)
+        .find(|(a, b)| a != b)
+    {
+        warn!(
+            "file names are not in shooting order, {} sorts where sequence number {} ({}) \
+             should be; going by the metadata, pass --trust-filename-order to go by the names",
+            file_name(&paths[a]).to_string_lossy(),
+            exifs[b].sequence_number,
+            file_name(&paths[b]).to_string_lossy()
+        );
+    }
+}
+
+/// Stops on frames shot with a different shutter than the others, and warns
+/// about any other capture setting that changed within the sequence: either
+/// way the frames don't line up the way the shift pattern says.
+pub fn check_settings(paths: &[PathBuf], exifs: &[ExifData]) {
+    for setting in SEQUENCE_SETTINGS {
+        let values = paths
+            .iter()
+            .zip(exifs)
+            .filter_map(|(path, exif)| {
+                let (_, value) = exif.settings.iter().find(|(key, _)| key == setting)?;
+                Some((path, value))
+            })
+            .collect::<Vec<_>>();
+
+        let Some(&(first_path, first)) = values.first() else {
+            continue;
+        };
+        let Some(&(path, value)) = values.iter().find(|(_, value)| *value != first) else {
+            continue;
+        };
+
+        if setting == "Shutter Type" {
+            fail!(
+                Metadata,
+                "{} was shot with the {} shutter but {} with the {} one: pixel shift needs every \
+                 frame shot with the electronic shutter, a shutter that moves the camera \
+                 between shots misaligns the frames and shows up as artifacts",
+                first_path.display(),
+                first,
+                path.display(),
+                value
+            );
+        }
+
+        warn!(
+            "{} was shot with {} {} but {} with {}, the frames may not line up",
+            first_path.display(),
+            setting,
+            first,
+            path.display(),
+            value
+        );
+    }
+}
+
+/// Index of the only value of `values` unlike all the others, which agree
+fn odd_one_out<T: PartialEq>(values: &[T]) -> Option<usize> {
+    let odd =
+        (0..values.len()).find(|&i| values.iter().filter(|v| **v == values[i]).count() == 1)?;
+    let mut others = values.iter().enumerate().filter(|&(i, _)| i != odd);
+    let (_, first) = others.next()?;
+    others.all(|(_, v)| v == first).then_some(odd)
+}
+
+/// The noise reduction dark frame some cameras shoot after a long exposure
+/// burst, when its metadata tells it apart from the frames of the sequence:
+/// no sequence number, another burst, or another exposure time. Whatever
+/// stands out has to point at the same, single frame.
+pub fn find_dark_frame(exifs: &[ExifData]) -> Option<usize> {
+    let unnumbered = exifs
+        .iter()
+        .map(|exif| exif.sequence_number == 0)
+        .collect::<Vec<_>>();
+    let bursts = exifs
+        .iter()
+        .map(|exif| exif.burst_id.as_deref())
+        .collect::<Vec<_>>();
+    // frames missing it only tell that their metadata is incomplete
+    let exposures = exifs
+        .iter()
+        .map(|exif| exif.exposure_time)
+        .collect::<Option<Vec<_>>>();
+
+    let mut candidates = [
+        odd_one_out(&unnumbered),
+        odd_one_out(&bursts),
+        exposures.and_then(|exposures| odd_one_out(&exposures)),
+    ]
+    .into_iter()
+    .flatten();
+
+    let first = candidates.next()?;
+    candidates.all(|i| i == first).then_some(first)
+}
+
+/// Numbers the frames by file name instead of their metadata, for files whose
+/// sequence numbers got lost or mangled
+pub fn number_by_file_name(paths: &[PathBuf], exifs: &mut [ExifData]) {
+    for (n, i) in by_file_name(paths).into_iter().enumerate() {
+        exifs[i].sequence_number = n as u32 + 1;
+    }
+}
+
+/// Numbers the frames with the numbers given for them, in the order of
+/// `paths`, for frames whose metadata has none
+pub fn number_given(paths: &[PathBuf], exifs: &mut [ExifData], numbers: &[u32]) {
+    if numbers.len() != paths.len() {
+        fail!(
+            Usage,
+            "{} sequence numbers for {} files, --sequence-numbers takes one per file",
+            numbers.len(),
+            paths.len()
+        );
+    }
+    for (exif, &number) in exifs.iter_mut().zip(numbers) {
+        exif.sequence_number = number;
+    }
+}
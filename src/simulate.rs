@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use rayon::prelude::*;
+use tiff::encoder::TiffEncoder;
+use tiff::tags::Tag;
+
+use crate::cli::SimulateArgs;
+use crate::failure::fail;
+use crate::patterns::{registry, ShiftPattern, Shot};
+use crate::{bayer_pattern, Color};
+
+/// levels of the simulated sensor, those of a 14 bit Sony body
+const BLACK_LEVEL: u32 = 512;
+const WHITE_LEVEL: u32 = 16383;
+
+/// Camera Model Name of the simulated frames
+const MODEL: &str = "psmsmerge simulate";
+
+// TIFF/EP tags, exiftool reads ImageNumber as "Image Number"
+const CFA_REPEAT_PATTERN_DIM: Tag = Tag::Unknown(33421);
+const CFA_PATTERN: Tag = Tag::Unknown(33422);
+const IMAGE_NUMBER: Tag = Tag::Unknown(37393);
+const BLACK_LEVEL_TAG: Tag = Tag::Unknown(50714);
+const WHITE_LEVEL_TAG: Tag = Tag::Unknown(50717);
+const PHOTOMETRIC_CFA: u16 = 32803;
+
+/// SplitMix64, plenty for noise and reproducible from a seed
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64, stream: u64) -> Self {
+        let mut random = Random(seed ^ stream.wrapping_mul(0x9e3779b97f4a7c15));
+        random.next();
+        random
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn uniform(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, by Box-Muller
+    fn normal(&mut self) -> f64 {
+        let u = 1.0 - self.uniform();
+        let v = self.uniform();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
+
+/// The full color image the sequence is shot of, linear, from 0 to 1
+struct Scene {
+    width: u32,
+    height: u32,
+    pixels: Vec<[f32; 3]>,
+}
+
+impl Scene {
+    fn load(path: &Path, linear: bool) -> Self {
+        info!("loading {}", path.display());
+        let image = image::open(path)
+            .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e))
+            .into_rgb32f();
+
+        // sRGB decoding, images are almost always encoded for display
+        let decode = |v: f32| {
+            if linear {
+                v
+            } else if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        };
+
+        Scene {
+            width: image.width(),
+            height: image.height(),
+            pixels: image
+                .pixels()
+                .map(|px| px.0.map(|v| decode(v.clamp(0.0, 1.0))))
+                .collect(),
+        }
+    }
+
+    /// Bilinear sample of `channel` at `x`, `y`, in pixels from the top left
+    /// one, the edges repeated past them
+    fn sample(&self, x: f64, y: f64, channel: usize) -> f64 {
+        let x = x.clamp(0.0, (self.width - 1) as f64);
+        let y = y.clamp(0.0, (self.height - 1) as f64);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+        let at = |x: u32, y: u32| self.pixels[(y * self.width + x) as usize][channel] as f64;
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+/// How a frame sees the scene: where its pattern puts it, and how far it is
+/// off that
+struct Frame {
+    sequence_number: u32,
+    shot: Shot,
+    /// misalignment, in sensor pixels
+    shake: (f64, f64),
+    /// how far the moving patch has gone, in scene pixels
+    motion: f64,
+}
+
+/// Value of the sensor pixel `x`, `y` of `frame`, from 0 to 1: the mean of
+/// the scale x scale scene pixels it covers, in its CFA color
+fn expose(scene: &Scene, pattern: &ShiftPattern, frame: &Frame, x: u32, y: u32) -> f64 {
+    let scale = pattern.scale as f64;
+    let (gx, gy) = pattern.groups[frame.shot.group as usize];
+    let (oy, ox) = frame.shot.offset;
+    let left = scale * (x as f64 + ox as f64 + frame.shake.0) + gx as f64;
+    let top = scale * (y as f64 + oy as f64 + frame.shake.1) + gy as f64;
+
+    let channel = match bayer_pattern(x, y) {
+        Color::Red => 0,
+        Color::Green => 1,
+        Color::Blue => 2,
+    };
+
+    // the middle ninth of the scene moves to the right, the rest stays
+    let (w, h) = (scene.width as f64, scene.height as f64);
+    let moving = |sx: f64, sy: f64| {
+        (w / 3.0..2.0 * w / 3.0).contains(&sx) && (h / 3.0..2.0 * h / 3.0).contains(&sy)
+    };
+
+    let n = pattern.scale;
+    let mut sum = 0.0;
+    for j in 0..n {
+        for i in 0..n {
+            let (sx, sy) = (left + i as f64, top + j as f64);
+            sum += if frame.motion != 0.0 && moving(sx - frame.motion, sy) {
+                scene.sample(sx - frame.motion, sy, channel)
+            } else {
+                scene.sample(sx, sy, channel)
+            };
+        }
+    }
+    sum / (n * n) as f64
+}
+
+fn write_frame(
+    path: &Path,
+    samples: &[u16],
+    width: u32,
+    height: u32,
+    frame: &Frame,
+    description: &str,
+) -> tiff::TiffResult<()> {
+    let mut tiff = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let mut encoder = tiff.new_directory()?;
+
+    let offset = u32::try_from(encoder.write_data(samples)?)?;
+    encoder.write_tag(Tag::NewSubfileType, 0u32)?;
+    encoder.write_tag(Tag::ImageWidth, width)?;
+    encoder.write_tag(Tag::ImageLength, height)?;
+    encoder.write_tag(Tag::BitsPerSample, 16u16)?;
+    encoder.write_tag(Tag::Compression, 1u16)?;
+    encoder.write_tag(Tag::PhotometricInterpretation, PHOTOMETRIC_CFA)?;
+    encoder.write_tag(Tag::SamplesPerPixel, 1u16)?;
+    encoder.write_tag(Tag::RowsPerStrip, height)?;
+    encoder.write_tag(Tag::StripOffsets, offset)?;
+    encoder.write_tag(Tag::StripByteCounts, u32::try_from(samples.len() * 2)?)?;
+    encoder.write_tag(Tag::PlanarConfiguration, 1u16)?;
+    encoder.write_tag(Tag::Model, MODEL)?;
+    encoder.write_tag(
+        Tag::Software,
+        concat!("psmsmerge ", env!("CARGO_PKG_VERSION")),
+    )?;
+    encoder.write_tag(Tag::ImageDescription, description)?;
+    encoder.write_tag(CFA_REPEAT_PATTERN_DIM, &[2u16, 2][..])?;
+    encoder.write_tag(CFA_PATTERN, &[0u8, 1, 1, 2][..])?;
+    encoder.write_tag(IMAGE_NUMBER, frame.sequence_number)?;
+    encoder.write_tag(BLACK_LEVEL_TAG, BLACK_LEVEL)?;
+    encoder.write_tag(WHITE_LEVEL_TAG, WHITE_LEVEL)?;
+
+    encoder.finish()
+}
+
+/// Shoots a pixel shift sequence of a full color image: every frame samples
+/// the image through an RGGB mosaic, at the shift its shot of the pattern
+/// taking as many frames puts it, with noise, misalignment and a moving
+/// patch on top if asked. Each sensor pixel covers pattern scale x scale
+/// pixels of the image, so the merge of the frames is the image back when
+/// nothing went wrong, and shows what does when something did.
+pub fn run(args: &SimulateArgs) {
+    let pattern = registry().find(None, args.shots).unwrap_or_else(|| {
+        fail!(
+            Usage,
+            "no shift pattern takes {} frames (known frame counts: {:?})",
+            args.shots,
+            registry().frame_counts()
+        )
+    });
+    info!("simulating the {} shift pattern", pattern.name);
+
+    let scene = Scene::load(&args.input, args.linear);
+    let (width, height) = (scene.width / pattern.scale, scene.height / pattern.scale);
+    if width < 4 || height < 4 {
+        fail!(
+            Usage,
+            "{} is too small for a {} sequence",
+            args.input.display(),
+            pattern.name
+        );
+    }
+
+    std::fs::create_dir_all(&args.output_dir)
+        .unwrap_or_else(|e| fail!(Io, "can't create {}: {}", args.output_dir.display(), e));
+
+    let mut shake = Random::new(args.seed, 0);
+    let frames = (1..=pattern.frames() as u32)
+        .map(|sequence_number| Frame {
+            sequence_number,
+            shot: pattern.shot(sequence_number),
+            shake: match args.misalign {
+                0.0 => (0.0, 0.0),
+                misalign => (
+                    (2.0 * shake.uniform() - 1.0) * misalign,
+                    (2.0 * shake.uniform() - 1.0) * misalign,
+                ),
+            },
+            motion: args.motion * (sequence_number - 1) as f64,
+        })
+        .collect::<Vec<_>>();
+
+    let range = (WHITE_LEVEL - BLACK_LEVEL) as f64;
+    let paths = frames
+        .par_iter()
+        .map(|frame| {
+            let samples = (0..height)
+                .into_par_iter()
+                .flat_map_iter(|y| {
+                    let stream = ((frame.sequence_number as u64) << 32) | (y as u64 + 1);
+                    let mut noise = Random::new(args.seed, stream);
+                    (0..width)
+                        .map(|x| {
+                            let v = BLACK_LEVEL as f64
+                                + expose(&scene, pattern, frame, x, y) * range
+                                + noise.normal() * args.noise;
+                            v.round().clamp(0.0, WHITE_LEVEL as f64) as u16
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            let description = format!(
+                "simulated from {}\npattern={}\nshot={}\nshake={:.3},{:.3}\nnoise={}\nseed={}",
+                args.input.display(),
+                pattern.name,
+                frame.sequence_number,
+                frame.shake.0,
+                frame.shake.1,
+                args.noise,
+                args.seed
+            );
+
+            let path = args
+                .output_dir
+                .join(format!("SIM{:04}.tiff", frame.sequence_number));
+            write_frame(&path, &samples, width, height, frame, &description)
+                .unwrap_or_else(|e| fail!(Io, "can't write {}: {}", path.display(), e));
+            path
+        })
+        .collect::<Vec<PathBuf>>();
+
+    for (frame, path) in frames.iter().zip(&paths) {
+        info!(
+            "{}: group {}, offset {:?}, off by {:.3}, {:.3}",
+            path.display(),
+            frame.shot.group,
+            frame.shot.offset,
+            frame.shake.0,
+            frame.shake.1
+        );
+    }
+    info!(
+        "{} frames of {}x{} in {}",
+        paths.len(),
+        width,
+        height,
+        args.output_dir.display()
+    );
+}
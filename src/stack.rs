@@ -0,0 +1,190 @@
+use std::path::Path;
+
+use log::info;
+use rayon::prelude::*;
+
+use crate::cli::{MergeOptions, StackArgs};
+use crate::context::MergeContext;
+use crate::failure::fail;
+use crate::output::{self, Metadata};
+use crate::{is_raw, process, should_write, RgbImage16};
+
+/// the focus measure is averaged over this radius, so that the blend follows
+/// objects rather than single noisy pixels
+const SHARPNESS_RADIUS: usize = 4;
+
+/// Merged image given as is, or a directory with a sequence to merge first
+fn load(path: &Path, args: &MergeOptions, context: &mut MergeContext) -> RgbImage16 {
+    if !path.is_dir() {
+        info!("loading {}", path.display());
+        return image::open(path)
+            .unwrap_or_else(|e| fail!(Io, "can't read {}: {}", path.display(), e))
+            .into_rgb16();
+    }
+
+    let mut raws = std::fs::read_dir(path)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_raw(path))
+        .collect::<Vec<_>>();
+    raws.sort();
+
+    info!("merging {} raws from {}", raws.len(), path.display());
+    process(&raws, args, context).imgbuf
+}
+
+/// Separable box blur of a `width` wide plane, clamped at the borders
+pub fn box_blur(plane: &[f32], width: usize, radius: usize) -> Vec<f32> {
+    let height = plane.len() / width;
+    let window = (2 * radius + 1) as f32;
+
+    let mut horizontal = vec![0.0; plane.len()];
+    horizontal
+        .par_chunks_mut(width)
+        .zip(plane.par_chunks(width))
+        .for_each(|(out, row)| {
+            for (x, out) in out.iter_mut().enumerate() {
+                let sum = (x as isize - radius as isize..=(x + radius) as isize)
+                    .map(|sx| row[sx.clamp(0, width as isize - 1) as usize])
+                    .sum::<f32>();
+                *out = sum / window;
+            }
+        });
+
+    let mut blurred = vec![0.0; plane.len()];
+    blurred
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, out)| {
+            for (x, out) in out.iter_mut().enumerate() {
+                let sum = (y as isize - radius as isize..=(y + radius) as isize)
+                    .map(|sy| horizontal[sy.clamp(0, height as isize - 1) as usize * width + x])
+                    .sum::<f32>();
+                *out = sum / window;
+            }
+        });
+
+    blurred
+}
+
+/// Local contrast of the image, the absolute laplacian of its brightness
+/// averaged around every pixel
+fn sharpness(imgbuf: &RgbImage16) -> Vec<f32> {
+    let (w, h) = (imgbuf.width() as usize, imgbuf.height() as usize);
+
+    let luma = imgbuf
+        .pixels()
+        .map(|px| px.0.iter().map(|&v| v as f32).sum::<f32>())
+        .collect::<Vec<_>>();
+
+    let mut laplacian = vec![0.0; luma.len()];
+    laplacian
+        .par_chunks_mut(w)
+        .enumerate()
+        .for_each(|(y, out)| {
+            for (x, out) in out.iter_mut().enumerate() {
+                let at = |dx: isize, dy: isize| {
+                    let sx = (x as isize + dx).clamp(0, w as isize - 1) as usize;
+                    let sy = (y as isize + dy).clamp(0, h as isize - 1) as usize;
+                    luma[sy * w + sx]
+                };
+                *out = (4.0 * at(0, 0) - at(-1, 0) - at(1, 0) - at(0, -1) - at(0, 1)).abs();
+            }
+        });
+
+    box_blur(&laplacian, w, SHARPNESS_RADIUS)
+}
+
+/// Blends the inputs with weights following their local sharpness, one input
+/// at a time so that only the accumulators stay in memory.
+///
+/// The inputs are expected to be aligned already, as shot from a tripod or a
+/// focus rail.
+pub fn run(args: &StackArgs) {
+    let inputs = &args.inputs;
+    if args.merge.rggb_out {
+        fail!(
+            Usage,
+            "--rggb-out doesn't work with stack, the blend is made in RGB"
+        );
+    }
+
+    if !should_write(Path::new(&args.output_file), &args.merge, &args.output) {
+        return;
+    }
+
+    let now = std::time::Instant::now();
+
+    let mut dimensions = None;
+    let mut color = Vec::<f32>::new();
+    let mut weight = Vec::<f32>::new();
+    let mut context = MergeContext::default();
+
+    for input in inputs {
+        let imgbuf = load(input, &args.merge, &mut context);
+
+        match dimensions {
+            None => {
+                dimensions = Some(imgbuf.dimensions());
+                color = vec![0.0; imgbuf.as_raw().len()];
+                weight = vec![0.0; imgbuf.as_raw().len() / 3];
+            }
+            Some(dimensions) if dimensions != imgbuf.dimensions() => {
+                fail!(
+                    Usage,
+                    "{} is {}x{}, the other inputs are {}x{}",
+                    input.display(),
+                    imgbuf.width(),
+                    imgbuf.height(),
+                    dimensions.0,
+                    dimensions.1
+                );
+            }
+            Some(_) => (),
+        }
+
+        info!("blending {}", input.display());
+        let sharpness = sharpness(&imgbuf);
+
+        color
+            .par_chunks_mut(3)
+            .zip(weight.par_iter_mut())
+            .zip(imgbuf.as_raw().par_chunks(3).zip(sharpness.par_iter()))
+            .for_each(|((color, weight), (px, sharpness))| {
+                // squared, so that the sharpest input clearly wins, and never
+                // zero, so that flat areas are simply averaged
+                let w = sharpness * sharpness + 1e-3;
+                for (c, &v) in color.iter_mut().zip(px) {
+                    *c += w * v as f32;
+                }
+                *weight += w;
+            });
+        context.recycle_samples(imgbuf.into_raw());
+    }
+
+    let (width, height) = dimensions.unwrap();
+    let stacked = RgbImage16::from_raw(
+        width,
+        height,
+        color
+            .par_chunks(3)
+            .zip(weight.par_iter())
+            .flat_map_iter(|(color, weight)| color.iter().map(move |c| (c / weight).round() as u16))
+            .collect(),
+    )
+    .unwrap();
+
+    let mut metadata = Metadata::default();
+    metadata.describe("focus_stack", inputs.len());
+
+    info!("saving");
+    output::write(
+        &stacked,
+        Path::new(&args.output_file),
+        &metadata,
+        &args.output,
+    );
+
+    info!("done in {:?}", now.elapsed());
+}
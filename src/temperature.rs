@@ -0,0 +1,133 @@
+use log::info;
+
+use crate::cameras;
+use crate::failure::fail;
+use crate::planes::Planes;
+use crate::RawImage;
+
+/// tint units per unit of distance from the Planckian locus in CIE 1960 uv,
+/// the scale raw processors use
+const TINT_SCALE: f64 = 3000.0;
+
+/// --temp, in kelvin, within the range the locus approximation holds
+pub fn parse_temperature(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(kelvin) if (1667.0..=25000.0).contains(&kelvin) => Ok(kelvin),
+        _ => Err(format!(
+            "{:?} is not a color temperature between 1667 and 25000 K",
+            value
+        )),
+    }
+}
+
+/// --tint, green to magenta
+pub fn parse_tint(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(tint) if (-150.0..=150.0).contains(&tint) => Ok(tint),
+        _ => Err(format!("{:?} is not a tint between -150 and 150", value)),
+    }
+}
+
+/// CIE 1931 xy of a black body at `kelvin`, Kim et al.'s cubic fit of the
+/// Planckian locus
+fn planckian_xy(kelvin: f64) -> (f64, f64) {
+    let t = kelvin;
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x.powi(3) - 5.87338670 * x.powi(2) + 3.75112997 * x - 0.37001483
+    };
+    (x, y)
+}
+
+fn xy_to_uv((x, y): (f64, f64)) -> (f64, f64) {
+    let d = -2.0 * x + 12.0 * y + 3.0;
+    (4.0 * x / d, 6.0 * y / d)
+}
+
+fn uv_to_xy((u, v): (f64, f64)) -> (f64, f64) {
+    let d = 2.0 * u - 8.0 * v + 4.0;
+    (3.0 * u / d, 2.0 * v / d)
+}
+
+/// xy of the light at `kelvin`, moved off the locus by `tint`: positive
+/// tints are greener lights, which the white balance turns magenta
+pub fn white_xy(kelvin: f64, tint: f64) -> (f64, f64) {
+    let (u, v) = xy_to_uv(planckian_xy(kelvin));
+
+    // normal of the locus, pointing to the greens
+    let (u0, v0) = xy_to_uv(planckian_xy(kelvin - 1.0));
+    let (u1, v1) = xy_to_uv(planckian_xy(kelvin + 1.0));
+    let (du, dv) = (u1 - u0, v1 - v0);
+    let length = (du * du + dv * dv).sqrt();
+    let (mut nu, mut nv) = (-dv / length, du / length);
+    if nv < 0.0 {
+        (nu, nv) = (-nu, -nv);
+    }
+
+    let offset = tint / TINT_SCALE;
+    uv_to_xy((u + nu * offset, v + nv * offset))
+}
+
+/// XYZ to camera RGB matrix of `frame`: the D65 one of a DNG, or the one of
+/// the camera database
+fn color_matrix(frame: &RawImage) -> [[f64; 3]; 3] {
+    let matrix = frame.exif.color_matrix.or_else(|| {
+        cameras::lookup(frame.exif.model.as_deref())
+            .and_then(|camera| camera.color_matrix)
+            .map(|matrix| matrix.map(|v| v as f64 / 10000.0))
+    });
+
+    let Some(matrix) = matrix else {
+        fail!(
+            UnsupportedCamera,
+            "no color matrix is known for {}, --temp can't tell its white balance",
+            frame.exif.model.as_deref().unwrap_or("an unknown camera")
+        );
+    };
+    std::array::from_fn(|row| std::array::from_fn(|col| matrix[row * 3 + col]))
+}
+
+/// Channel multipliers, green kept at 1, that make a light of `kelvin` and
+/// `tint` neutral in the raw colors of `frame`
+pub fn multipliers(frame: &RawImage, kelvin: f64, tint: f64) -> [f64; 3] {
+    let (x, y) = white_xy(kelvin, tint);
+    let xyz = [x / y, 1.0, (1.0 - x - y) / y];
+
+    let matrix = color_matrix(frame);
+    let camera = matrix.map(|row| row.iter().zip(xyz).map(|(m, v)| m * v).sum::<f64>());
+    if camera.iter().any(|&v| v <= 0.0) {
+        fail!(
+            Usage,
+            "{} K, tint {} is out of the gamut of the camera",
+            kelvin,
+            tint
+        );
+    }
+
+    let multipliers = camera.map(|v| camera[1] / v);
+    info!(
+        "{} K, tint {}: multipliers {:.3} {:.3} {:.3}",
+        kelvin, tint, multipliers[0], multipliers[1], multipliers[2]
+    );
+    multipliers
+}
+
+/// Scales every channel of `planes` by its multiplier, above the black level
+pub fn apply<const N: usize>(
+    planes: &mut Planes<N>,
+    frame: &RawImage,
+    multipliers: [f64; N],
+    channel_samples: [u32; N],
+) {
+    let black = channel_samples.map(|n| (frame.black_level * n) as f32);
+    let multipliers = multipliers.map(|m| m as f32);
+    planes.map(|c, v| (v - black[c]).max(0.0) * multipliers[c] + black[c]);
+}
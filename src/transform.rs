@@ -0,0 +1,55 @@
+use image::{imageops, ImageBuffer, Pixel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rotation {
+    #[value(name = "90")]
+    Cw90,
+    #[value(name = "180")]
+    Cw180,
+    #[value(name = "270")]
+    Cw270,
+}
+
+impl Rotation {
+    pub fn degrees(self) -> u32 {
+        match self {
+            Rotation::Cw90 => 90,
+            Rotation::Cw180 => 180,
+            Rotation::Cw270 => 270,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Flip {
+    /// mirror left to right
+    H,
+    /// mirror top to bottom
+    V,
+}
+
+/// Rotates clockwise then flips the merged image, both are exact pixel moves
+pub fn apply<P: Pixel + 'static>(
+    imgbuf: ImageBuffer<P, Vec<P::Subpixel>>,
+    rotation: Option<Rotation>,
+    flip: Option<Flip>,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let mut imgbuf = match rotation {
+        Some(Rotation::Cw90) => imageops::rotate90(&imgbuf),
+        Some(Rotation::Cw180) => {
+            let mut imgbuf = imgbuf;
+            imageops::rotate180_in_place(&mut imgbuf);
+            imgbuf
+        }
+        Some(Rotation::Cw270) => imageops::rotate270(&imgbuf),
+        None => imgbuf,
+    };
+
+    match flip {
+        Some(Flip::H) => imageops::flip_horizontal_in_place(&mut imgbuf),
+        Some(Flip::V) => imageops::flip_vertical_in_place(&mut imgbuf),
+        None => (),
+    }
+
+    imgbuf
+}
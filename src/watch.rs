@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::cli::WatchArgs;
+use crate::context::MergeContext;
+use crate::exif::{self, read_exif, ExifData};
+use crate::failure::fail;
+use crate::patterns::registry;
+use crate::preview::preview;
+use crate::{is_raw, process, save, should_write};
+
+/// the directory is listed rather than watched for events: that works the
+/// same on network shares and card readers, where file system events are
+/// unreliable or missing, and a file has to be seen twice at the same size
+/// anyway to know the camera is done writing it
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const PREVIEW_SIZE: u32 = 2048;
+
+fn list_raws(dir: &Path) -> Vec<(PathBuf, u64)> {
+    let mut raws = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| fail!(Io, "can't list {}: {}", dir.display(), e))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_raw(&entry.path()))
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?.len())))
+        .collect::<Vec<_>>();
+
+    raws.sort();
+    raws
+}
+
+/// `stem` with `suffix` appended, whatever the encoding of the name
+fn with_suffix(stem: &OsStr, suffix: &str) -> OsString {
+    let mut name = stem.to_os_string();
+    name.push(suffix);
+    name
+}
+
+/// The frames of the burst being shot, in the order they arrived
+#[derive(Default)]
+struct Burst {
+    frames: Vec<(PathBuf, u32)>,
+    /// Pixel Shift Group ID of its frames, when the camera writes one
+    id: Option<String>,
+    /// Pixel Shift Interval of its frames
+    interval: Option<Duration>,
+}
+
+impl Burst {
+    /// Whether `exif` is a frame of another burst: its group ID isn't this
+    /// one's, or without IDs, its sequence number started over
+    fn is_new(&self, exif: &ExifData) -> bool {
+        match (&self.id, &exif.burst_id) {
+            (Some(id), Some(other)) => id != other,
+            _ => self
+                .frames
+                .last()
+                .is_some_and(|&(_, last)| exif.sequence_number <= last),
+        }
+    }
+}
+
+/// Pixel Shift Interval of a frame, the time the camera waits between shots
+fn interval(exif: &ExifData) -> Option<Duration> {
+    let (_, value) = exif
+        .settings
+        .iter()
+        .find(|(name, _)| name == "Pixel Shift Interval")?;
+    exif::seconds(value).map(Duration::from_secs_f64)
+}
+
+/// Merges a complete burst, in the buffers the previous ones left in `context`
+fn flush(burst: &mut Burst, args: &WatchArgs, context: &mut MergeContext) {
+    let paths = std::mem::take(burst)
+        .frames
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+
+    if !registry().frame_counts().contains(&paths.len()) {
+        warn!("skipping incomplete burst of {} frames", paths.len());
+        return;
+    }
+
+    let stem = paths[0].file_stem().unwrap().to_os_string();
+    let out_dir = args.output_dir.as_path();
+    let output = out_dir.join(with_suffix(&stem, ".tiff"));
+    let now = Instant::now();
+
+    // a broken burst must not end the shooting session
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        if !should_write(&output, &args.merge, &args.output) {
+            return;
+        }
+
+        let merge = process(&paths, &args.merge, context);
+
+        save(&merge, &output, &args.merge, &args.output);
+        preview(&merge.imgbuf, PREVIEW_SIZE, merge.white_balance.is_some())
+            .save(out_dir.join(with_suffix(&stem, "_preview.jpg")))
+            .unwrap();
+        context.recycle(merge);
+    }));
+
+    match result {
+        Ok(()) => info!(
+            "merged burst {} in {:?}",
+            stem.to_string_lossy(),
+            now.elapsed()
+        ),
+        Err(_) => warn!("failed to merge burst {}", stem.to_string_lossy()),
+    }
+}
+
+/// Polls `dir` for new raw files, groups them into bursts by Pixel Shift
+/// Group ID, or by sequence number for cameras without one, and merges each
+/// burst into the output directory once it is complete: once the next one
+/// starts, once it has the frames of the largest shift pattern, or once no
+/// frame came for `--settle` seconds plus the Pixel Shift Interval, since
+/// the first 4 frames of a 16 shots burst look exactly like a 4 shots one.
+pub fn run(args: &WatchArgs) {
+    let dir = args.dir.as_path();
+    std::fs::create_dir_all(&args.output_dir)
+        .unwrap_or_else(|e| fail!(Io, "can't create {}: {}", args.output_dir.display(), e));
+
+    // files already there belong to a previous session
+    let mut seen = list_raws(dir)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect::<HashSet<_>>();
+
+    // size seen at the previous poll, a file is complete once it stops growing
+    let mut sizes = HashMap::<PathBuf, u64>::new();
+    let mut burst = Burst::default();
+    let settle = Duration::from_secs_f64(args.settle.max(0.0));
+    let mut last_arrival = Instant::now();
+    let mut context = MergeContext::default();
+
+    info!("watching {}", dir.display());
+
+    loop {
+        for (path, size) in list_raws(dir) {
+            if seen.contains(&path) {
+                continue;
+            }
+
+            if size == 0 || sizes.insert(path.clone(), size) != Some(size) {
+                continue;
+            }
+
+            sizes.remove(&path);
+            seen.insert(path.clone());
+
+            // nor must a frame whose metadata can't be read, it is left out
+            let Ok(exif) = std::panic::catch_unwind(|| read_exif(&path)) else {
+                warn!("skipping {}, its metadata can't be read", path.display());
+                continue;
+            };
+            info!(
+                "{}: sequence number {}",
+                path.display(),
+                exif.sequence_number
+            );
+
+            if burst.is_new(&exif) {
+                flush(&mut burst, args, &mut context);
+            }
+
+            burst.id = exif.burst_id.clone();
+            burst.interval = interval(&exif);
+            burst.frames.push((path, exif.sequence_number));
+            last_arrival = Instant::now();
+
+            if registry().frame_counts().last() == Some(&burst.frames.len()) {
+                flush(&mut burst, args, &mut context);
+            }
+        }
+
+        let wait = settle + burst.interval.unwrap_or_default();
+        if registry().frame_counts().contains(&burst.frames.len()) && last_arrival.elapsed() > wait
+        {
+            flush(&mut burst, args, &mut context);
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
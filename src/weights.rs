@@ -0,0 +1,68 @@
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::motion::MotionMask;
+use crate::patterns::ShiftPattern;
+use crate::RawImage;
+
+/// Fraction of the frames that cleanly contributed to each pixel of a merge,
+/// 1.0 when all 4 of its group did. Clipped samples are only counted out
+/// here, the merge still takes them as they are.
+pub type WeightMap = image::ImageBuffer<image::Luma<f32>, Vec<f32>>;
+
+/// `photo.tiff` gets its weights in `photo.weights.tiff`
+pub fn path_for(output: &Path) -> PathBuf {
+    let mut name = output.file_stem().unwrap().to_os_string();
+    name.push(".weights.tiff");
+    output.with_file_name(name)
+}
+
+/// Whether `file` has a usable sample for the 4 shots merge pixel `x`, `y`:
+/// inside the frame once shifted, and not clipped
+fn contributes(file: &RawImage, x: u32, y: u32) -> bool {
+    let offset = file.inter_group_offsets();
+
+    let (Some(fx), Some(fy)) = (x.checked_sub(offset.1), y.checked_sub(offset.0)) else {
+        return false;
+    };
+
+    if fx >= file.width || fy >= file.height {
+        return false;
+    }
+
+    (file.get_pixel(fx, fy) as u32) < file.white_level
+}
+
+/// Weights of every merged pixel, before the border is cropped. Pixels the
+/// `mask` takes from the first frame alone weigh 1, the weight of the merge
+/// fading into it along a feathered edge: nothing was rejected there.
+pub fn contributions(
+    files: &[RawImage],
+    pattern: &ShiftPattern,
+    mask: Option<&MotionMask>,
+) -> WeightMap {
+    let groups = files
+        .chunk_by(|a, b| a.group == b.group)
+        .collect::<Vec<_>>();
+    // groups are interleaved like `merge` does
+    let grid = pattern.grid();
+    let scale = pattern.scale;
+
+    let mut weights = WeightMap::new(files[0].width * scale, files[0].height * scale);
+
+    weights
+        .par_enumerate_pixels_mut()
+        .for_each(|(x, y, pixel)| {
+            let group = groups[grid[((y % scale) * scale + x % scale) as usize]];
+            let count = group
+                .iter()
+                .filter(|file| contributes(file, x / scale, y / scale))
+                .count();
+            let weight = count as f32 / group.len() as f32;
+            let masked = mask.map_or(0.0, |mask| mask.weight(x, y));
+            pixel.0[0] = weight * (1.0 - masked) + masked;
+        });
+
+    weights
+}
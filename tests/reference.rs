@@ -0,0 +1,364 @@
+//! Merges of reference pixel shift sequences checked against golden hashes of
+//! their pixels, so that a refactor can't change the merge math unnoticed.
+//!
+//! The `simulated_` cases shoot their sequences with `simulate`, from an
+//! image drawn here, and hand the frames over as decoded mosaics: they need
+//! nothing else and always run. The others merge real raws, which need the
+//! sequences and exiftool, so they only run when asked:
+//!
+//! ```text
+//! PSMS_SAMPLES=~/samples cargo test --release -- --ignored
+//! ```
+//!
+//! `PSMS_SAMPLES` holds `4shots` and `16shots`, each a directory of raws or
+//! a ZIP of them. `PSMS_SAMPLES_URL` downloads `4shots.zip` and `16shots.zip`
+//! from a server of your own instead, once, into the target directory; no
+//! samples are published anywhere yet. With either set, a sequence that
+//! isn't there fails its cases, without them they are skipped.
+//!
+//! `PSMS_BLESS=1` records the outputs as the golden ones in `golden.txt`, for
+//! changes meant to alter them.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+
+const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden.txt");
+
+/// golden.txt is rewritten by every blessed case, one at a time
+static BLESS: Mutex<()> = Mutex::new(());
+
+/// simulated sequences, shot once per run by the first case needing them
+static SIMULATED: Mutex<Option<HashMap<usize, PathBuf>>> = Mutex::new(None);
+
+/// Where the sequence `name` is, downloading it first if needed; None when
+/// no samples were asked for
+fn sequence(name: &str) -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("PSMS_SAMPLES") {
+        let dir = PathBuf::from(dir);
+        let found = [dir.join(name), dir.join(format!("{}.zip", name))]
+            .into_iter()
+            .find(|path| path.exists());
+        return Some(found.unwrap_or_else(|| {
+            panic!("PSMS_SAMPLES has no {} sequence in {}", name, dir.display())
+        }));
+    }
+
+    let url = std::env::var("PSMS_SAMPLES_URL").ok()?;
+    let cache = Path::new(env!("CARGO_TARGET_TMPDIR")).join("samples");
+    let path = cache.join(format!("{}.zip", name));
+    if path.exists() {
+        return Some(path);
+    }
+
+    std::fs::create_dir_all(&cache).unwrap();
+    let partial = path.with_extension("zip.part");
+    let status = Command::new("curl")
+        .args(["-sSfL", "-o"])
+        .arg(&partial)
+        .arg(format!("{}/{}.zip", url.trim_end_matches('/'), name))
+        .status()
+        .expect("curl is needed to download the samples");
+    if !status.success() {
+        let _ = std::fs::remove_file(&partial);
+        panic!("can't download {} from {}", name, url);
+    }
+    std::fs::rename(&partial, &path).unwrap();
+    Some(path)
+}
+
+/// The image the simulated sequences are shot of: gradients, rings finer
+/// than the sensor can tell apart and a few sharp edges, linear already
+fn scene(path: &Path) {
+    let (width, height) = (192u32, 144u32);
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let (fx, fy) = (x as f32 / width as f32, y as f32 / height as f32);
+        let (dx, dy) = (x as f32 - 96.0, y as f32 - 72.0);
+        let rings = 0.5 + 0.5 * ((dx * dx + dy * dy) / 40.0).sin();
+        let edge = if x > 40 + y / 3 && y > 20 { 1.0 } else { 0.3 };
+        let channels = [fx * edge, rings * edge, fy * (1.0 - rings * 0.5)];
+        image::Rgb(channels.map(|v| (v * 230.0 + 10.0) as u8))
+    });
+    image.save(path).unwrap();
+}
+
+/// Samples of a frame `simulate` wrote, a single strip of 16 bit little
+/// endian samples, and its tags that matter here
+fn read_frame(path: &Path) -> (u32, u32, Vec<u8>, HashMap<u16, u32>) {
+    let bytes = std::fs::read(path).unwrap();
+    assert_eq!(
+        &bytes[..4],
+        b"II*\0",
+        "{} isn't what simulate writes",
+        path.display()
+    );
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+    let ifd = u32_at(4) as usize;
+    let tags = (0..u16_at(ifd) as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .map(|entry| {
+            // SHORT values sit in the first half of the value field
+            let value = match u16_at(entry + 2) {
+                3 => u16_at(entry + 8) as u32,
+                _ => u32_at(entry + 8),
+            };
+            (u16_at(entry), value)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let (width, height) = (tags[&256], tags[&257]);
+    let start = tags[&273] as usize;
+    let samples = bytes[start..start + (width * height * 2) as usize].to_vec();
+    (width, height, samples, tags)
+}
+
+/// A `shots` frames sequence shot by `simulate`, every frame rewritten as a
+/// PGM with the metadata of its tags in a sidecar, the way decoded mosaics
+/// are merged without exiftool
+fn simulated(shots: usize) -> PathBuf {
+    let mut simulated = SIMULATED.lock().unwrap();
+    let simulated = simulated.get_or_insert_with(HashMap::new);
+    if let Some(dir) = simulated.get(&shots) {
+        return dir.clone();
+    }
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("simulated-{}", shots));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let scene_path = dir.join("scene.png");
+    scene(&scene_path);
+
+    let frames = dir.join("frames");
+    let status = Command::new(env!("CARGO_BIN_EXE_psmsmerge"))
+        .args(["-q", "simulate", "--linear", "--noise", "4", "--shots"])
+        .arg(shots.to_string())
+        .arg(&scene_path)
+        .arg("-o")
+        .arg(&frames)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "simulating {} shots failed with {}",
+        shots,
+        status
+    );
+
+    for entry in std::fs::read_dir(&frames).unwrap() {
+        let path = entry.unwrap().path();
+        let (width, height, samples, tags) = read_frame(&path);
+
+        let mut pgm = format!("P5\n{} {}\n{}\n", width, height, tags[&50717]).into_bytes();
+        pgm.extend(samples.chunks_exact(2).flat_map(|s| [s[1], s[0]]));
+        let name = path.file_stem().unwrap();
+        std::fs::write(dir.join(name).with_extension("pgm"), pgm).unwrap();
+        std::fs::write(
+            dir.join(name).with_extension("txt"),
+            format!(
+                "Image Number : {}\nBlack Level : {}\nCamera Model Name : psmsmerge simulate\n",
+                tags[&37393], tags[&50714]
+            ),
+        )
+        .unwrap();
+    }
+
+    simulated.insert(shots, dir.clone());
+    dir
+}
+
+/// `-i` with the raws or decoded mosaics of a directory, or `--archive` for
+/// a ZIP
+fn input_args(sequence: &Path) -> Vec<OsString> {
+    if !sequence.is_dir() {
+        return vec!["--archive".into(), sequence.into()];
+    }
+
+    let mut raws = std::fs::read_dir(sequence)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("arw") || ext.eq_ignore_ascii_case("pgm")
+            })
+        })
+        .collect::<Vec<_>>();
+    raws.sort();
+
+    let mut args = vec!["-i".into()];
+    args.extend(raws.into_iter().map(Into::into));
+    args
+}
+
+/// FNV-1a of the samples, and the mean of every channel to tell how far off
+/// a mismatch is
+fn measure(path: &Path) -> (u64, [f64; 3]) {
+    let mut decoder = Decoder::new(File::open(path).unwrap())
+        .unwrap()
+        .with_limits(Limits::unlimited());
+    let DecodingResult::U16(samples) = decoder.read_image().unwrap() else {
+        panic!("{} is not a 16 bit image", path.display());
+    };
+
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in samples.iter().flat_map(|sample| sample.to_le_bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+
+    let mut means = [0.0; 3];
+    for pixel in samples.chunks_exact(3) {
+        for (mean, &sample) in means.iter_mut().zip(pixel) {
+            *mean += sample as f64;
+        }
+    }
+    let pixels = (samples.len() / 3).max(1) as f64;
+    (hash, means.map(|sum| sum / pixels))
+}
+
+fn golden(case: &str) -> Option<(u64, [f64; 3])> {
+    let text = std::fs::read_to_string(GOLDEN).ok()?;
+    let line = text
+        .lines()
+        .find(|line| line.split_whitespace().next() == Some(case))?;
+
+    let fields = line.split_whitespace().skip(1).collect::<Vec<_>>();
+    let hash = u64::from_str_radix(fields.first()?, 16).ok()?;
+    let means = fields[1..]
+        .iter()
+        .map(|v| v.parse().unwrap())
+        .collect::<Vec<_>>();
+    Some((hash, means.try_into().ok()?))
+}
+
+fn bless(case: &str, hash: u64, means: [f64; 3]) {
+    let _lock = BLESS.lock().unwrap();
+    let text = std::fs::read_to_string(GOLDEN).unwrap_or_default();
+
+    let mut lines = text
+        .lines()
+        .filter(|line| line.split_whitespace().next() != Some(case))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "{} {:016x} {:.3} {:.3} {:.3}",
+        case, hash, means[0], means[1], means[2]
+    ));
+    // comments first, then the cases by name
+    lines.sort_by_key(|line| (!line.starts_with('#'), line.clone()));
+
+    std::fs::write(GOLDEN, lines.join("\n") + "\n").unwrap();
+}
+
+/// Merges the sample sequence `name` with `args` and compares the result
+/// with the golden output of `case`
+fn check(case: &str, name: &str, args: &[&str]) {
+    let Some(sequence) = sequence(name) else {
+        eprintln!(
+            "skipping {}, neither PSMS_SAMPLES nor PSMS_SAMPLES_URL is set",
+            case
+        );
+        return;
+    };
+    compare(case, &sequence, args);
+}
+
+/// `check` for a sequence of `shots` frames shot by `simulate`
+fn check_simulated(case: &str, shots: usize, args: &[&str]) {
+    compare(case, &simulated(shots), args);
+}
+
+fn compare(case: &str, sequence: &Path, args: &[&str]) {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("reference");
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = dir.join(format!("{}.tiff", case));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_psmsmerge"))
+        .args(["-q", "merge", "--overwrite", "-o"])
+        .arg(&output)
+        .args(input_args(sequence))
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{} failed with {}", case, status);
+
+    let (hash, means) = measure(&output);
+
+    if std::env::var_os("PSMS_BLESS").is_some() {
+        bless(case, hash, means);
+        return;
+    }
+
+    let (golden_hash, golden_means) = golden(case)
+        .unwrap_or_else(|| panic!("no golden output for {}, record it with PSMS_BLESS=1", case));
+
+    assert!(
+        hash == golden_hash,
+        "{} changed: hash {:016x} instead of {:016x}, channel means {:?} instead of {:?}",
+        case,
+        hash,
+        golden_hash,
+        means,
+        golden_means
+    );
+}
+
+#[test]
+fn simulated_four_shots() {
+    check_simulated("simulated-4shots", 4, &[]);
+}
+
+#[test]
+fn simulated_four_shots_green_average() {
+    check_simulated("simulated-4shots-green-average", 4, &["--green", "average"]);
+}
+
+#[test]
+fn simulated_sixteen_shots() {
+    check_simulated("simulated-16shots", 16, &[]);
+}
+
+#[test]
+fn simulated_sixteen_shots_downscaled() {
+    check_simulated("simulated-16shots-downscale", 16, &["--downscale", "2"]);
+}
+
+#[test]
+fn simulated_sixteen_shots_mlr() {
+    check_simulated("simulated-16shots-mlr", 16, &["--reconstruct", "mlr"]);
+}
+
+#[test]
+#[ignore]
+fn four_shots() {
+    check("4shots", "4shots", &[]);
+}
+
+#[test]
+#[ignore]
+fn four_shots_green_average() {
+    check("4shots-green-average", "4shots", &["--green", "average"]);
+}
+
+#[test]
+#[ignore]
+fn four_shots_fast() {
+    check("4shots-fast", "4shots", &["--fast"]);
+}
+
+#[test]
+#[ignore]
+fn sixteen_shots() {
+    check("16shots", "16shots", &[]);
+}
+
+#[test]
+#[ignore]
+fn sixteen_shots_downscaled() {
+    check("16shots-downscale", "16shots", &["--downscale", "2"]);
+}